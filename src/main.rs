@@ -1,132 +1,24 @@
 use std::{
     env, fs,
     path::{Path, PathBuf},
-    sync::{Arc, Mutex},
 };
 
+mod modules;
+
 use clap::{Parser, Subcommand};
 
 use directories::ProjectDirs;
-use mlua::{Lua, LuaOptions, StdLib, UserData, UserDataFields, UserDataMethods};
-use regex::Regex;
-use reqwest::blocking::Client;
+use mlua::{Lua, LuaOptions, StdLib, UserData, UserDataMethods};
+use modules::{
+    fs::FsUtils,
+    http::{self, HttpModule},
+    regex,
+    stringx::StringUtils,
+    url,
+};
 
-struct FsUtils;
-struct StringUtils;
-struct RegexWrapper(Regex);
 struct ClipboardHandling;
 
-#[allow(dead_code)]
-struct HttpModule {
-    client: Arc<Mutex<Client>>,
-}
-
-impl UserData for FsUtils {
-    fn add_fields<'lua, F: UserDataFields<Self>>(fields: &mut F) {
-        fields.add_field_method_get("dirname", |_, _| {
-            Ok(Path::new(env::current_dir()?.to_str().unwrap())
-                .parent()
-                .map(|p| p.to_str().unwrap().to_string()))
-        });
-    }
-
-    fn add_methods<'lua, M: UserDataMethods<Self>>(methods: &mut M) {
-        methods.add_function("basename", |_, path: String| {
-            Ok(Path::new(&path)
-                .file_name()
-                .and_then(|s| s.to_str())
-                .map(|s| s.to_string()))
-        });
-
-        methods.add_function("readlink", |_, path: String| {
-            fs::read_link(&path)
-                .map(|p| p.to_str().map(|s| s.to_string()))
-                .map_err(|e| mlua::Error::RuntimeError(e.to_string()))
-        });
-
-        methods.add_function("dirname", |_, path: String| {
-            Ok(Path::new(&path)
-                .parent()
-                .and_then(|p| p.to_str())
-                .map(|s| s.to_string()))
-        });
-    }
-}
-
-impl UserData for StringUtils {
-    fn add_methods<'lua, M: UserDataMethods<Self>>(methods: &mut M) {
-        methods.add_function("split", |_, (s, pat): (String, String)| {
-            let parts: Vec<String> = s.split(&pat).map(|p| p.to_string()).collect();
-            Ok(parts)
-        });
-
-        methods.add_function("trim", |_, s: String| Ok(s.trim().to_string()));
-    }
-}
-
-impl UserData for RegexWrapper {
-    fn add_methods<'lua, M: UserDataMethods<Self>>(methods: &mut M) {
-        methods.add_method("is_match", |_, this, text: String| {
-            Ok(this.0.is_match(&text))
-        });
-
-        methods.add_method("captures", |lua, this, text: String| {
-            let table = lua.create_table()?;
-            if let Some(caps) = this.0.captures(&text) {
-                for (i, cap) in caps.iter().enumerate() {
-                    if let Some(cap) = cap {
-                        table.set(i + 1, cap.as_str())?;
-                    }
-                }
-            }
-            Ok(table)
-        });
-    }
-}
-
-impl UserData for HttpModule {
-    fn add_methods<'lua, M: UserDataMethods<Self>>(methods: &mut M) {
-        methods.add_method("get", |lua, _, url: String| {
-            let client = lua
-                .app_data_ref::<Arc<Mutex<Client>>>()
-                .ok_or_else(|| mlua::Error::RuntimeError("HTTP client not available".into()))?;
-
-            let response = client
-                .lock()
-                .map_err(|_| mlua::Error::RuntimeError("HTTP client lock poisoned".into()))?
-                .get(&url)
-                .send()
-                .map_err(|e| mlua::Error::RuntimeError(e.to_string()))?;
-
-            let text = response
-                .text()
-                .map_err(|e| mlua::Error::RuntimeError(e.to_string()))?;
-
-            lua.create_string(&text)
-        });
-
-        methods.add_method("post", |lua, _, (url, body): (String, String)| {
-            let client = lua
-                .app_data_ref::<Arc<Mutex<Client>>>()
-                .ok_or_else(|| mlua::Error::RuntimeError("HTTP client not available".into()))?;
-
-            let response = client
-                .lock()
-                .map_err(|_| mlua::Error::RuntimeError("HTTP client lock poisoned".into()))?
-                .post(&url)
-                .body(body)
-                .send()
-                .map_err(|e| mlua::Error::RuntimeError(e.to_string()))?;
-
-            let text = response
-                .text()
-                .map_err(|e| mlua::Error::RuntimeError(e.to_string()))?;
-
-            lua.create_string(&text)
-        });
-    }
-}
-
 impl UserData for ClipboardHandling {
     fn add_methods<'lua, M: UserDataMethods<Self>>(methods: &mut M) {
         methods.add_method("set", |_, _, text: String| {
@@ -199,6 +91,28 @@ fn find_script(program_name: &str) -> Option<PathBuf> {
     None
 }
 
+/// Creates a Lua state with every module registered as a global.
+fn new_lua() -> mlua::Result<Lua> {
+    let lua = Lua::new_with(StdLib::ALL_SAFE, LuaOptions::new().catch_rust_panics(true))?;
+
+    modules::fs::init();
+
+    lua.set_app_data(http::HttpState::new()?);
+    lua.set_app_data(regex::RegexCache::new(regex::DEFAULT_CACHE_CAPACITY));
+
+    let globals = lua.globals();
+    globals.set("fs", FsUtils)?;
+    globals.set("stringx", StringUtils)?;
+    globals.set("clipboard", ClipboardHandling)?;
+
+    globals.set("regex", regex::module(&lua)?)?;
+
+    globals.set("http", HttpModule)?;
+    globals.set("url", url::module(&lua)?)?;
+
+    Ok(lua)
+}
+
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 #[command(propagate_version = true)]
@@ -222,33 +136,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             let script_path = find_script(program_name)
                 .ok_or_else(|| format!("Script for '{}' not found", program_name))?;
 
-            let http_client = Arc::new(Mutex::new(Client::new()));
-
-            let lua = Lua::new_with(StdLib::ALL_SAFE, LuaOptions::new().catch_rust_panics(true))?;
-
-            lua.set_app_data(Arc::clone(&http_client));
-
+            let lua = new_lua()?;
             let globals = lua.globals();
-            globals.set("fs", FsUtils)?;
-            globals.set("stringx", StringUtils)?;
-            globals.set("clipboard", ClipboardHandling)?;
-
-            // Add regex module with constructor
-            let _ = globals.set(
-                "regex",
-                lua.create_function(|_, pattern: String| {
-                    Regex::new(&pattern)
-                        .map(RegexWrapper)
-                        .map_err(|e| mlua::Error::RuntimeError(e.to_string()))
-                })?,
-            );
-
-            globals.set(
-                "http",
-                HttpModule {
-                    client: Arc::clone(&http_client),
-                },
-            )?;
 
             let arg_table = lua.create_table()?;
             for (i, arg) in args.iter().enumerate() {
@@ -0,0 +1,2402 @@
+use std::{
+    collections::HashSet,
+    env, fs,
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use directories::{BaseDirs, ProjectDirs};
+use filetime::FileTime;
+use mlua::{FromLua, Function, Lua, MultiValue, Table, UserData, UserDataMethods, Value, Variadic};
+
+use super::opt;
+
+mod file;
+mod hash;
+mod lock;
+mod mmap;
+#[cfg(unix)]
+mod owner;
+mod tail;
+mod temp;
+mod watch;
+
+pub struct FsUtils;
+
+fn metadata(path: &str, no_follow: Option<bool>) -> Option<fs::Metadata> {
+    if no_follow.unwrap_or(false) {
+        fs::symlink_metadata(path).ok()
+    } else {
+        fs::metadata(path).ok()
+    }
+}
+
+pub(super) fn io_error(action: &str, path: &str, err: io::Error) -> mlua::Error {
+    mlua::Error::RuntimeError(format!("failed to {action} '{path}': {err}"))
+}
+
+/// Parses a permission mode given either as a number (`493`, `tonumber("755", 8)`)
+/// or as an octal string (`"755"`, `"0o755"`).
+fn parse_mode(value: Value) -> mlua::Result<u32> {
+    let mode = match value {
+        Value::Integer(i) => u32::try_from(i).ok(),
+        Value::Number(n) if n.fract() == 0.0 && n >= 0.0 => Some(n as u32),
+        Value::String(s) => {
+            let s = s.to_str()?;
+            let digits = s.strip_prefix("0o").unwrap_or(&s);
+            u32::from_str_radix(digits, 8).ok()
+        }
+        _ => None,
+    };
+
+    mode.filter(|m| *m <= 0o7777).ok_or_else(|| {
+        mlua::Error::RuntimeError("invalid mode: expected a number or an octal string".into())
+    })
+}
+
+fn create_dir(path: &str, recursive: bool, mode: Option<Value>) -> mlua::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(recursive);
+
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(parse_mode(mode)?);
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+
+    builder
+        .create(path)
+        .map_err(|e| io_error("create directory", path, e))
+}
+
+fn remove(path: &str) -> mlua::Result<()> {
+    let meta = fs::symlink_metadata(path).map_err(|e| io_error("remove", path, e))?;
+    if meta.is_dir() {
+        fs::remove_dir(path)
+    } else {
+        fs::remove_file(path)
+    }
+    .map_err(|e| io_error("remove", path, e))
+}
+
+fn remove_all(path: &str, missing_ok: bool) -> mlua::Result<()> {
+    let meta = match fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(e) if missing_ok && e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(io_error("remove", path, e)),
+    };
+
+    // symlinks are removed like files, so only real directories are walked
+    if !meta.is_dir() {
+        return fs::remove_file(path).map_err(|e| io_error("remove", path, e));
+    }
+
+    let canonical = fs::canonicalize(path).map_err(|e| io_error("resolve", path, e))?;
+    if canonical.parent().is_none() {
+        return Err(mlua::Error::RuntimeError(format!(
+            "refusing to remove the filesystem root '{path}'"
+        )));
+    }
+
+    fs::remove_dir_all(path).map_err(|e| io_error("remove", path, e))
+}
+
+fn copy_error(src: &Path, dst: &Path, err: impl std::fmt::Display) -> mlua::Error {
+    mlua::Error::RuntimeError(format!(
+        "failed to copy '{}' to '{}': {err}",
+        src.display(),
+        dst.display()
+    ))
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    use std::os::windows::fs::{symlink_dir, symlink_file};
+
+    // relative targets are resolved against the directory containing the link
+    let resolved = match link.parent() {
+        Some(parent) => parent.join(target),
+        None => target.to_path_buf(),
+    };
+    if resolved.is_dir() {
+        symlink_dir(target, link)
+    } else {
+        symlink_file(target, link)
+    }
+}
+
+struct CopyOptions {
+    overwrite: bool,
+    preserve_mode: bool,
+    preserve_times: bool,
+}
+
+/// Makes room for `dst`, failing if it exists and overwriting isn't allowed.
+fn prepare_destination(src: &Path, dst: &Path, opts: &CopyOptions) -> mlua::Result<()> {
+    match fs::symlink_metadata(dst) {
+        Err(_) => Ok(()),
+        Ok(_) if !opts.overwrite => Err(copy_error(src, dst, "destination already exists")),
+        Ok(meta) if meta.file_type().is_symlink() => {
+            fs::remove_file(dst).map_err(|e| copy_error(src, dst, e))
+        }
+        Ok(_) => Ok(()),
+    }
+}
+
+/// Applies the metadata of `src` that `opts` asks to preserve onto `dst`.
+fn preserve_metadata(
+    src: &Path,
+    dst: &Path,
+    meta: &fs::Metadata,
+    opts: &CopyOptions,
+) -> mlua::Result<()> {
+    if opts.preserve_mode && !meta.file_type().is_symlink() {
+        fs::set_permissions(dst, meta.permissions()).map_err(|e| copy_error(src, dst, e))?;
+    }
+
+    if opts.preserve_times {
+        let atime = FileTime::from_last_access_time(meta);
+        let mtime = FileTime::from_last_modification_time(meta);
+        if meta.file_type().is_symlink() {
+            filetime::set_symlink_file_times(dst, atime, mtime)
+        } else {
+            filetime::set_file_times(dst, atime, mtime)
+        }
+        .map_err(|e| copy_error(src, dst, e))?;
+    }
+
+    Ok(())
+}
+
+fn copy_file(src: &Path, dst: &Path, opts: &CopyOptions) -> mlua::Result<u64> {
+    prepare_destination(src, dst, opts)?;
+    let bytes = fs::copy(src, dst).map_err(|e| copy_error(src, dst, e))?;
+
+    if opts.preserve_times {
+        let meta = fs::metadata(src).map_err(|e| copy_error(src, dst, e))?;
+        preserve_metadata(src, dst, &meta, opts)?;
+    }
+
+    Ok(bytes)
+}
+
+fn copy_tree(src: &Path, dst: &Path, opts: &CopyOptions) -> mlua::Result<u64> {
+    fs::create_dir_all(dst).map_err(|e| copy_error(src, dst, e))?;
+
+    let mut bytes = 0;
+    for entry in fs::read_dir(src).map_err(|e| copy_error(src, dst, e))? {
+        let entry = entry.map_err(|e| copy_error(src, dst, e))?;
+        let from = entry.path();
+        let to: PathBuf = dst.join(entry.file_name());
+        let file_type = entry.file_type().map_err(|e| copy_error(&from, &to, e))?;
+
+        if file_type.is_dir() {
+            bytes += copy_tree(&from, &to, opts)?;
+        } else if file_type.is_symlink() {
+            let target = fs::read_link(&from).map_err(|e| copy_error(&from, &to, e))?;
+            prepare_destination(&from, &to, opts)?;
+            symlink(&target, &to).map_err(|e| copy_error(&from, &to, e))?;
+            if opts.preserve_times {
+                let meta = fs::symlink_metadata(&from).map_err(|e| copy_error(&from, &to, e))?;
+                preserve_metadata(&from, &to, &meta, opts)?;
+            }
+        } else {
+            bytes += copy_file(&from, &to, opts)?;
+        }
+    }
+
+    // directory metadata goes last, since filling the directory bumps its mtime
+    let meta = fs::metadata(src).map_err(|e| copy_error(src, dst, e))?;
+    preserve_metadata(src, dst, &meta, opts)?;
+
+    Ok(bytes)
+}
+
+fn rename(src: &Path, dst: &Path, overwrite: bool) -> mlua::Result<()> {
+    let rename_error = |e: io::Error| {
+        mlua::Error::RuntimeError(format!(
+            "failed to rename '{}' to '{}': {e}",
+            src.display(),
+            dst.display()
+        ))
+    };
+
+    if !overwrite && fs::symlink_metadata(dst).is_ok() {
+        return Err(rename_error(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "destination already exists",
+        )));
+    }
+
+    match fs::rename(src, dst) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            move_across_devices(src, dst, overwrite)
+        }
+        result => result.map_err(rename_error),
+    }
+}
+
+/// Moves `src` to `dst` on another filesystem by copying everything over, and
+/// only deleting the source once the copy has fully succeeded.
+fn move_across_devices(src: &Path, dst: &Path, overwrite: bool) -> mlua::Result<()> {
+    let rename_error = |e: io::Error| {
+        mlua::Error::RuntimeError(format!(
+            "failed to rename '{}' to '{}': {e}",
+            src.display(),
+            dst.display()
+        ))
+    };
+
+    let opts = CopyOptions {
+        overwrite,
+        preserve_mode: true,
+        preserve_times: true,
+    };
+    let meta = fs::symlink_metadata(src).map_err(rename_error)?;
+    if meta.is_dir() {
+        copy_tree(src, dst, &opts)?;
+        fs::remove_dir_all(src).map_err(rename_error)
+    } else if meta.file_type().is_symlink() {
+        let target = fs::read_link(src).map_err(rename_error)?;
+        prepare_destination(src, dst, &opts)?;
+        symlink(&target, dst).map_err(rename_error)?;
+        fs::remove_file(src).map_err(rename_error)
+    } else {
+        copy_file(src, dst, &opts)?;
+        fs::remove_file(src).map_err(rename_error)
+    }
+}
+
+fn file_kind(file_type: fs::FileType) -> &'static str {
+    if file_type.is_symlink() {
+        "symlink"
+    } else if file_type.is_dir() {
+        "dir"
+    } else if file_type.is_file() {
+        "file"
+    } else {
+        "other"
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum SortKey {
+    Name,
+    Mtime,
+    Size,
+}
+
+struct ListEntry {
+    name: String,
+    path: PathBuf,
+    kind: &'static str,
+    modified: Option<SystemTime>,
+    size: u64,
+}
+
+fn list_dir(lua: &Lua, path: &str, opts: Option<&Table>) -> mlua::Result<Table> {
+    let include_hidden = opt(opts, "include_hidden")?.unwrap_or(true);
+    let reverse = opt(opts, "reverse")?.unwrap_or(false);
+    let strict = opt(opts, "strict")?.unwrap_or(false);
+    let limit: Option<usize> = opt(opts, "limit")?;
+    let sort = match opt::<String>(opts, "sort")?.as_deref() {
+        None | Some("name") => SortKey::Name,
+        Some("mtime") => SortKey::Mtime,
+        Some("size") => SortKey::Size,
+        Some(other) => {
+            return Err(mlua::Error::RuntimeError(format!(
+                "unknown sort key '{other}' (expected name, mtime or size)"
+            )));
+        }
+    };
+    let pattern = opt::<String>(opts, "pattern")?
+        .map(|pattern| {
+            glob::Pattern::new(&pattern).map_err(|e| {
+                mlua::Error::RuntimeError(format!("invalid glob pattern '{pattern}': {e}"))
+            })
+        })
+        .transpose()?;
+
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(path).map_err(|e| io_error("list", path, e))? {
+        let entry = entry.map_err(|e| io_error("list", path, e))?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !include_hidden && name.starts_with('.') {
+            continue;
+        }
+        if pattern
+            .as_ref()
+            .is_some_and(|pattern| !pattern.matches(&name))
+        {
+            continue;
+        }
+        let file_type = entry.file_type().map_err(|e| io_error("list", path, e))?;
+
+        // metadata is only needed, and read once, when sorting by it
+        let (modified, size) = if sort == SortKey::Name {
+            (None, 0)
+        } else {
+            match entry.metadata() {
+                Ok(meta) => (meta.modified().ok(), meta.len()),
+                Err(e) if strict => {
+                    return Err(io_error("stat", &entry.path().to_string_lossy(), e));
+                }
+                Err(_) => continue,
+            }
+        };
+
+        entries.push(ListEntry {
+            name,
+            path: entry.path(),
+            kind: file_kind(file_type),
+            modified,
+            size,
+        });
+    }
+
+    entries.sort_by(|a, b| match sort {
+        SortKey::Name => a.name.cmp(&b.name),
+        SortKey::Mtime => a
+            .modified
+            .cmp(&b.modified)
+            .then_with(|| a.name.cmp(&b.name)),
+        SortKey::Size => a.size.cmp(&b.size).then_with(|| a.name.cmp(&b.name)),
+    });
+    if reverse {
+        entries.reverse();
+    }
+    if let Some(limit) = limit {
+        entries.truncate(limit);
+    }
+
+    let table = lua.create_table()?;
+    for entry in entries {
+        let item = lua.create_table()?;
+        item.set("name", entry.name)?;
+        item.set("path", entry.path.to_string_lossy())?;
+        item.set("kind", entry.kind)?;
+        table.push(item)?;
+    }
+    Ok(table)
+}
+
+struct WalkEntry {
+    path: PathBuf,
+    kind: &'static str,
+    depth: usize,
+}
+
+/// Lazy depth-first traversal backing `fs.walk`, which only keeps the pending
+/// entries of the directories along the current path in memory.
+struct Walker {
+    stack: Vec<std::vec::IntoIter<WalkEntry>>,
+    max_depth: Option<usize>,
+    follow_symlinks: bool,
+    skip: HashSet<String>,
+    visited: HashSet<PathBuf>,
+}
+
+impl Walker {
+    fn new(
+        root: &str,
+        max_depth: Option<usize>,
+        follow_symlinks: bool,
+        skip: HashSet<String>,
+    ) -> mlua::Result<Self> {
+        let mut walker = Walker {
+            stack: Vec::new(),
+            max_depth,
+            follow_symlinks,
+            skip,
+            visited: HashSet::new(),
+        };
+
+        if walker.follow_symlinks {
+            let canonical = fs::canonicalize(root).map_err(|e| io_error("walk", root, e))?;
+            walker.visited.insert(canonical);
+        }
+        let children = walker
+            .read_children(Path::new(root), 1)
+            .map_err(|e| io_error("walk", root, e))?;
+        walker.stack.push(children.into_iter());
+
+        Ok(walker)
+    }
+
+    fn read_children(&self, dir: &Path, depth: usize) -> io::Result<Vec<WalkEntry>> {
+        let mut children = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let mut file_type = entry.file_type()?;
+            if self.follow_symlinks && file_type.is_symlink() {
+                // dangling links keep being reported as symlinks
+                if let Ok(meta) = fs::metadata(entry.path()) {
+                    file_type = meta.file_type();
+                }
+            }
+            children.push(WalkEntry {
+                path: entry.path(),
+                kind: file_kind(file_type),
+                depth,
+            });
+        }
+        children.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(children)
+    }
+
+    /// Whether the walk should step into `entry`, marking it as visited.
+    fn should_descend(&mut self, entry: &WalkEntry) -> bool {
+        if entry.kind != "dir" || self.max_depth.is_some_and(|max| entry.depth >= max) {
+            return false;
+        }
+
+        if !self.follow_symlinks {
+            return true;
+        }
+        // a directory reached twice through symlinks means there's a cycle
+        match fs::canonicalize(&entry.path) {
+            Ok(canonical) => self.visited.insert(canonical),
+            Err(_) => false,
+        }
+    }
+
+    fn next_entry(&mut self) -> mlua::Result<Option<WalkEntry>> {
+        while let Some(pending) = self.stack.last_mut() {
+            let Some(entry) = pending.next() else {
+                self.stack.pop();
+                continue;
+            };
+
+            let name = entry.path.file_name().unwrap_or_default().to_string_lossy();
+            if entry.kind == "dir" && self.skip.contains(name.as_ref()) {
+                continue;
+            }
+
+            if self.should_descend(&entry) {
+                let path = entry.path.to_string_lossy();
+                let children = self
+                    .read_children(&entry.path, entry.depth + 1)
+                    .map_err(|e| io_error("walk", &path, e))?;
+                self.stack.push(children.into_iter());
+            }
+            return Ok(Some(entry));
+        }
+        Ok(None)
+    }
+}
+
+fn walk(lua: &Lua, root: &str, opts: Option<&Table>) -> mlua::Result<Function> {
+    let mut walker = Walker::new(
+        root,
+        opt(opts, "max_depth")?,
+        opt(opts, "follow_symlinks")?.unwrap_or(false),
+        opt::<Vec<String>>(opts, "skip")?
+            .unwrap_or_default()
+            .into_iter()
+            .collect(),
+    )?;
+    lua.create_function_mut(move |lua, _: MultiValue| {
+        let Some(entry) = walker.next_entry()? else {
+            return Ok(None);
+        };
+        let table = lua.create_table()?;
+        table.set("path", entry.path.to_string_lossy())?;
+        table.set("kind", entry.kind)?;
+        table.set("depth", entry.depth)?;
+        Ok(Some(table))
+    })
+}
+
+fn glob(pattern: &str, base: Option<&str>) -> mlua::Result<Vec<String>> {
+    let invalid = |e: glob::PatternError| {
+        mlua::Error::RuntimeError(format!("invalid glob pattern '{pattern}': {e}"))
+    };
+
+    glob::Pattern::new(pattern).map_err(invalid)?;
+
+    // the leading components without wildcards say where the walk starts,
+    // so "src/**/*.lua" never looks outside of "src"
+    let components: Vec<&str> = pattern.split('/').collect();
+    let literal = components
+        .iter()
+        .take_while(|c| !c.contains(['*', '?', '[']))
+        .count();
+    let prefix = match components[..literal].join("/") {
+        // only an absolute pattern like "/*" has an empty leading component
+        prefix if prefix.is_empty() && literal > 0 => "/".to_string(),
+        prefix => prefix,
+    };
+    let rest = &components[literal..];
+
+    let start = match (base, prefix.as_str()) {
+        (Some(base), prefix) => Path::new(base).join(prefix),
+        (None, "") => PathBuf::from("."),
+        (None, prefix) => PathBuf::from(prefix),
+    };
+    let show = |rel: &Path| match (base, prefix.as_str()) {
+        (None, "") => rel.to_string_lossy().into_owned(),
+        _ => start.join(rel).to_string_lossy().into_owned(),
+    };
+
+    if rest.is_empty() {
+        let found = fs::symlink_metadata(&start).is_ok();
+        return Ok(found
+            .then(|| start.to_string_lossy().into_owned())
+            .into_iter()
+            .collect());
+    }
+
+    let matcher = glob::Pattern::new(&rest.join("/")).map_err(invalid)?;
+    let options = glob::MatchOptions {
+        require_literal_separator: true,
+        ..Default::default()
+    };
+    let max_depth = (!rest.contains(&"**")).then_some(rest.len());
+
+    let mut walker = match Walker::new(&start.to_string_lossy(), max_depth, false, HashSet::new()) {
+        Ok(walker) => walker,
+        // a start directory that doesn't exist simply matches nothing
+        Err(_) => return Ok(Vec::new()),
+    };
+
+    let mut matches = Vec::new();
+    // entries that can't be read while matching are left out
+    while let Ok(Some(entry)) = walker.next_entry() {
+        let Ok(rel) = entry.path.strip_prefix(&start) else {
+            continue;
+        };
+        let rel_str = rel
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        if matcher.matches_with(&rel_str, options) {
+            matches.push(show(rel));
+        }
+    }
+    matches.sort();
+    Ok(matches)
+}
+
+/// Converts a timestamp to Unix epoch seconds, keeping the fractional part.
+fn epoch_seconds(time: SystemTime) -> f64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_secs_f64(),
+        Err(e) => -e.duration().as_secs_f64(),
+    }
+}
+
+fn stat(lua: &Lua, path: &str, follow: bool) -> mlua::Result<Table> {
+    let meta = if follow {
+        fs::metadata(path)
+    } else {
+        fs::symlink_metadata(path)
+    }
+    .map_err(|e| io_error("stat", path, e))?;
+
+    let table = lua.create_table()?;
+    table.set("size", meta.len())?;
+    table.set("is_dir", meta.is_dir())?;
+    table.set("is_file", meta.is_file())?;
+    table.set("is_symlink", meta.file_type().is_symlink())?;
+    table.set("readonly", meta.permissions().readonly())?;
+    // timestamps the platform or filesystem doesn't record are left as nil
+    table.set("modified", meta.modified().ok().map(epoch_seconds))?;
+    table.set("accessed", meta.accessed().ok().map(epoch_seconds))?;
+    table.set("created", meta.created().ok().map(epoch_seconds))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        table.set("mode", meta.permissions().mode() & 0o7777)?;
+    }
+
+    Ok(table)
+}
+
+#[cfg(unix)]
+fn chmod(path: &str, mode: u32) -> mlua::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+        .map_err(|e| io_error("change permissions of", path, e))
+}
+
+/// Only the read-only flag exists here, which follows the owner's write bit,
+/// so a mode with execute or special bits is an error rather than being
+/// silently half applied.
+#[cfg(not(unix))]
+fn chmod(path: &str, mode: u32) -> mlua::Result<()> {
+    if mode & !0o666 != 0 {
+        return Err(mlua::Error::RuntimeError(format!(
+            "failed to change permissions of '{path}' to {mode:o}: execute and special bits are not supported on this platform"
+        )));
+    }
+    let mut permissions = fs::metadata(path)
+        .map_err(|e| io_error("change permissions of", path, e))?
+        .permissions();
+    permissions.set_readonly(mode & 0o200 == 0);
+    fs::set_permissions(path, permissions).map_err(|e| io_error("change permissions of", path, e))
+}
+
+/// The umask the process started with. The umask call can only read it by
+/// replacing it, which would briefly give files that other threads create
+/// the wrong mode, so this is read once before any threads are started.
+#[cfg(all(unix, not(target_os = "linux")))]
+static STARTUP_UMASK: std::sync::LazyLock<u32> = std::sync::LazyLock::new(|| unsafe {
+    let umask = libc::umask(0);
+    libc::umask(umask);
+    umask as u32
+});
+
+/// Reads what needs reading before scripts can start threads.
+pub fn init() {
+    #[cfg(all(unix, not(target_os = "linux")))]
+    std::sync::LazyLock::force(&STARTUP_UMASK);
+}
+
+/// Linux shows the umask in `/proc`, where it can be read without changing
+/// it.
+#[cfg(target_os = "linux")]
+fn umask() -> u32 {
+    fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            let line = status.lines().find(|line| line.starts_with("Umask:"))?;
+            u32::from_str_radix(line["Umask:".len()..].trim(), 8).ok()
+        })
+        .unwrap_or(0o022)
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn umask() -> u32 {
+    *STARTUP_UMASK
+}
+
+#[cfg(unix)]
+fn make_executable(path: &str) -> mlua::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = fs::metadata(path)
+        .map_err(|e| io_error("change permissions of", path, e))?
+        .permissions()
+        .mode();
+    chmod(path, (mode | (0o111 & !umask())) & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn make_executable(path: &str) -> mlua::Result<()> {
+    Err(mlua::Error::RuntimeError(format!(
+        "failed to make '{path}' executable: not supported on this platform"
+    )))
+}
+
+/// Creates a link with `make`, replacing an existing entry at `link` through a
+/// rename when `force` is set, so the path never goes missing in between.
+fn create_link(
+    what: &str,
+    target: &str,
+    link: &str,
+    force: bool,
+    make: impl Fn(&Path, &Path) -> io::Result<()>,
+) -> mlua::Result<()> {
+    let link_error = |e: io::Error| {
+        #[cfg(windows)]
+        let e = if what == "symlink" && e.raw_os_error() == Some(1314) {
+            format!("{e} (creating symlinks requires developer mode or administrator privileges)")
+        } else {
+            e.to_string()
+        };
+        mlua::Error::RuntimeError(format!(
+            "failed to create {what} '{link}' to '{target}': {e}"
+        ))
+    };
+    let (target_path, link_path) = (Path::new(target), Path::new(link));
+
+    if !force || fs::symlink_metadata(link_path).is_err() {
+        return make(target_path, link_path).map_err(link_error);
+    }
+
+    let file_name = link_path.file_name().unwrap_or_default().to_string_lossy();
+    let staging = link_path.with_file_name(format!(".{file_name}.{}.tmp", std::process::id()));
+    make(target_path, &staging).map_err(link_error)?;
+    fs::rename(&staging, link_path).map_err(|e| {
+        let _ = fs::remove_file(&staging);
+        link_error(e)
+    })
+}
+
+/// The parent directory of a path, or `None` for a root. On Unix a path is
+/// just bytes, so ones that aren't valid UTF-8 are taken apart as they are.
+#[cfg(unix)]
+fn dirname(lua: &Lua, path: &mlua::String) -> mlua::Result<Option<mlua::String>> {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+    let path = path.as_bytes();
+    Path::new(OsStr::from_bytes(&path))
+        .parent()
+        .map(|parent| lua.create_string(parent.as_os_str().as_bytes()))
+        .transpose()
+}
+
+#[cfg(not(unix))]
+fn dirname(lua: &Lua, path: &mlua::String) -> mlua::Result<Option<mlua::String>> {
+    let path = path.to_str()?;
+    Path::new(&*path)
+        .parent()
+        .map(|parent| lua.create_string(parent.to_string_lossy().as_bytes()))
+        .transpose()
+}
+
+fn utf8_path(path: PathBuf) -> mlua::Result<String> {
+    path.into_os_string().into_string().map_err(|path| {
+        mlua::Error::RuntimeError(format!(
+            "path '{}' is not valid UTF-8",
+            Path::new(&path).display()
+        ))
+    })
+}
+
+/// Strips the `\\?\` verbatim prefix `fs::canonicalize` adds on Windows, which
+/// most other tools don't understand.
+fn strip_verbatim(path: String) -> String {
+    if let Some(rest) = path.strip_prefix(r"\\?\UNC\") {
+        format!(r"\\{rest}")
+    } else if let Some(rest) = path.strip_prefix(r"\\?\") {
+        rest.to_string()
+    } else {
+        path
+    }
+}
+
+fn file_time(epoch_seconds: f64) -> FileTime {
+    let seconds = epoch_seconds.floor();
+    let nanos = ((epoch_seconds - seconds) * 1e9) as u32;
+    FileTime::from_unix_time(seconds as i64, nanos)
+}
+
+fn touch(path: &str, time: Option<f64>, create: bool) -> mlua::Result<()> {
+    if fs::symlink_metadata(path).is_err() {
+        if !create {
+            return Err(io_error(
+                "touch",
+                path,
+                io::Error::new(io::ErrorKind::NotFound, "file does not exist"),
+            ));
+        }
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| io_error("create", path, e))?;
+    }
+
+    let time = time.map_or_else(FileTime::now, file_time);
+    filetime::set_file_times(path, time, time).map_err(|e| io_error("touch", path, e))
+}
+
+/// Returns an iterator over the lines of a file, or over fixed-size chunks of
+/// it when `chunk_size` is given. The file is closed as soon as the iterator
+/// is exhausted or garbage collected.
+fn lines(lua: &Lua, path: &str, chunk_size: Option<usize>) -> mlua::Result<Function> {
+    let file = fs::File::open(path).map_err(|e| io_error("open", path, e))?;
+    let mut reader = Some(BufReader::new(file));
+    let path = path.to_string();
+
+    lua.create_function_mut(move |lua, _: MultiValue| {
+        let Some(file) = reader.as_mut() else {
+            return Ok(None);
+        };
+
+        let mut buf = Vec::new();
+        let read = match chunk_size {
+            Some(size) => file.by_ref().take(size as u64).read_to_end(&mut buf),
+            None => file.read_until(b'\n', &mut buf),
+        }
+        .map_err(|e| io_error("read", &path, e))?;
+
+        if read == 0 {
+            reader = None;
+            return Ok(None);
+        }
+        if chunk_size.is_none() && buf.ends_with(b"\n") {
+            buf.pop();
+            if buf.ends_with(b"\r") {
+                buf.pop();
+            }
+        }
+        lua.create_string(&buf).map(Some)
+    })
+}
+
+fn read_bytes(path: &str, offset: Option<u64>, length: Option<u64>) -> mlua::Result<Vec<u8>> {
+    let mut file = fs::File::open(path).map_err(|e| io_error("open", path, e))?;
+    if let Some(offset) = offset {
+        file.seek(SeekFrom::Start(offset))
+            .map_err(|e| io_error("seek in", path, e))?;
+    }
+
+    let mut buf = Vec::new();
+    match length {
+        Some(length) => file.take(length).read_to_end(&mut buf),
+        None => file.read_to_end(&mut buf),
+    }
+    .map_err(|e| io_error("read", path, e))?;
+    Ok(buf)
+}
+
+/// Writes `contents` to a temporary file next to `path`, syncs it and renames
+/// it over `path`, so readers only ever see the old or the new contents.
+fn atomic_write(path: &str, contents: &[u8], mode: Option<Value>) -> mlua::Result<()> {
+    let target = Path::new(path);
+    let dir = match target.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    // the temporary file deletes itself if anything below fails
+    let mut file = tempfile::NamedTempFile::new_in(dir).map_err(|e| io_error("write", path, e))?;
+    file.write_all(contents)
+        .and_then(|_| file.as_file().sync_all())
+        .map_err(|e| io_error("write", path, e))?;
+
+    let permissions = match fs::metadata(target) {
+        Ok(meta) => Some(meta.permissions()),
+        Err(_) => new_file_permissions(mode)?,
+    };
+    if let Some(permissions) = permissions {
+        fs::set_permissions(file.path(), permissions).map_err(|e| io_error("write", path, e))?;
+    }
+
+    file.persist(target)
+        .map_err(|e| io_error("replace", path, e.error))?;
+
+    // make the rename itself durable, where directories can be synced
+    #[cfg(unix)]
+    if let Ok(dir) = fs::File::open(dir) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+pub(super) fn new_file_permissions(mode: Option<Value>) -> mlua::Result<Option<fs::Permissions>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    match mode {
+        Some(mode) => Ok(Some(fs::Permissions::from_mode(parse_mode(mode)?))),
+        // match what a plain write would have created, rather than the
+        // private mode temporary files start with
+        None => Ok(Some(fs::Permissions::from_mode(0o666 & !umask()))),
+    }
+}
+
+#[cfg(not(unix))]
+pub(super) fn new_file_permissions(mode: Option<Value>) -> mlua::Result<Option<fs::Permissions>> {
+    let _ = mode;
+    Ok(None)
+}
+
+/// Looks up a standard directory, either the user-wide one or the one of the
+/// given application, like the script directory lunash itself uses.
+fn standard_dir(
+    app: Option<String>,
+    base: impl Fn(&BaseDirs) -> &Path,
+    project: impl Fn(&ProjectDirs) -> &Path,
+) -> Option<String> {
+    let path = match app {
+        Some(app) => project(&ProjectDirs::from("", "", &app)?).to_path_buf(),
+        None => base(&BaseDirs::new()?).to_path_buf(),
+    };
+    Some(path.to_string_lossy().into_owned())
+}
+
+#[cfg(unix)]
+fn user_home(name: &str) -> Option<PathBuf> {
+    owner::user_home(name)
+}
+
+#[cfg(not(unix))]
+fn user_home(_name: &str) -> Option<PathBuf> {
+    None
+}
+
+/// Expands a leading `~` or `~user` to the matching home directory, leaving
+/// the path untouched when that directory is unknown.
+fn expanduser(path: &str) -> String {
+    let Some(rest) = path.strip_prefix('~') else {
+        return path.to_string();
+    };
+    let (user, rest) = match rest.find(['/', std::path::MAIN_SEPARATOR]) {
+        Some(i) => rest.split_at(i),
+        None => (rest, ""),
+    };
+
+    let home = if user.is_empty() {
+        BaseDirs::new().map(|dirs| dirs.home_dir().to_path_buf())
+    } else {
+        user_home(user)
+    };
+    match home {
+        Some(home) => format!("{}{rest}", home.to_string_lossy()),
+        None => path.to_string(),
+    }
+}
+
+fn disk_usage(lua: &Lua, path: &str) -> mlua::Result<Table> {
+    let stats = fs4::statvfs(path).map_err(|e| io_error("get disk usage of", path, e))?;
+    let table = lua.create_table()?;
+    table.set("total", stats.total_space())?;
+    table.set("free", stats.free_space())?;
+    table.set("available", stats.available_space())?;
+    Ok(table)
+}
+
+/// Sums the sizes of all files below `path`, counting hard-linked files once.
+#[derive(Default)]
+struct DirSize {
+    bytes: u64,
+    files: u64,
+    dirs: u64,
+}
+
+impl DirSize {
+    fn to_table(&self, lua: &Lua) -> mlua::Result<Table> {
+        let table = lua.create_table()?;
+        table.set("bytes", self.bytes)?;
+        table.set("files", self.files)?;
+        table.set("dirs", self.dirs)?;
+        Ok(table)
+    }
+}
+
+fn dir_size(lua: &Lua, path: &str, opts: Option<&Table>) -> mlua::Result<Table> {
+    let blocks: bool = opt(opts, "blocks")?.unwrap_or(false);
+    let on_progress: Option<Function> = opt(opts, "on_progress")?;
+    let progress_every: u64 = opt(opts, "progress_every")?.unwrap_or(1000).max(1);
+
+    #[cfg(unix)]
+    let mut seen = HashSet::new();
+    let mut size = DirSize::default();
+    let mut entries = 0;
+    let mut pending = vec![PathBuf::from(path)];
+
+    while let Some(dir) = pending.pop() {
+        let dir_str = dir.to_string_lossy();
+        for entry in fs::read_dir(&dir).map_err(|e| io_error("read", &dir_str, e))? {
+            let entry = entry.map_err(|e| io_error("read", &dir_str, e))?;
+            let meta = entry
+                .metadata()
+                .map_err(|e| io_error("stat", &entry.path().to_string_lossy(), e))?;
+
+            entries += 1;
+            if let Some(on_progress) = &on_progress
+                && entries % progress_every == 0
+            {
+                on_progress.call::<()>(size.to_table(lua)?)?;
+            }
+
+            if meta.is_dir() {
+                size.dirs += 1;
+                pending.push(entry.path());
+                continue;
+            }
+
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::MetadataExt;
+                if meta.nlink() > 1 && !seen.insert((meta.dev(), meta.ino())) {
+                    continue;
+                }
+            }
+
+            size.files += 1;
+            size.bytes += if blocks {
+                allocated_size(&meta)
+            } else {
+                meta.len()
+            };
+        }
+    }
+
+    size.to_table(lua)
+}
+
+#[cfg(unix)]
+fn allocated_size(meta: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    // st_blocks is always in 512-byte units, whatever the filesystem block size
+    meta.blocks() * 512
+}
+
+#[cfg(not(unix))]
+fn allocated_size(meta: &fs::Metadata) -> u64 {
+    meta.len()
+}
+
+/// Lexically removes `.` components and resolves `..` ones, without touching
+/// the filesystem. Leading `..` components of relative paths are kept.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized: Vec<Component> = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match normalized.last() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                // there's nothing above the root
+                Some(Component::RootDir | Component::Prefix(_)) => {}
+                _ => normalized.push(component),
+            },
+            _ => normalized.push(component),
+        }
+    }
+
+    if normalized.is_empty() {
+        PathBuf::from(".")
+    } else {
+        normalized.iter().collect()
+    }
+}
+
+/// Computes `path` relative to `base`, returning `None` when that can't be
+/// expressed, like between different Windows drives.
+fn relative(path: &Path, base: &Path) -> Option<PathBuf> {
+    let (path, base) = (normalize(path), normalize(base));
+    if path.is_absolute() != base.is_absolute() {
+        return None;
+    }
+
+    let mut path_components = path.components().peekable();
+    let mut base_components = base.components().peekable();
+    while let (Some(a), Some(b)) = (path_components.peek(), base_components.peek()) {
+        if a != b {
+            break;
+        }
+        path_components.next();
+        base_components.next();
+    }
+
+    let mut relative = PathBuf::new();
+    for component in base_components {
+        match component {
+            Component::Normal(_) => relative.push(".."),
+            Component::CurDir => {}
+            // a different drive or root, or a base climbing out of the
+            // unknown directory the path is relative to
+            _ => return None,
+        }
+    }
+    for component in path_components {
+        match component {
+            Component::Prefix(_) | Component::RootDir => return None,
+            component => relative.push(component),
+        }
+    }
+
+    if relative.as_os_str().is_empty() {
+        relative.push(".");
+    }
+    Some(relative)
+}
+
+fn modified(path: &str) -> io::Result<SystemTime> {
+    fs::metadata(path)?.modified()
+}
+
+/// Whether the data looks like text: valid UTF-8 (allowing a character cut
+/// off at the end) without any NUL bytes.
+fn looks_like_text(data: &[u8]) -> bool {
+    if data.contains(&0) {
+        return false;
+    }
+    match std::str::from_utf8(data) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    }
+}
+
+/// Guesses the type of some data from its magic numbers, returning `nil`
+/// when it's neither a known format nor text.
+fn detect_type(lua: &Lua, data: &[u8]) -> mlua::Result<Option<Table>> {
+    let is_text = !data.is_empty() && looks_like_text(data);
+    let (mime, extension) = match infer::get(data) {
+        Some(kind) => (kind.mime_type(), kind.extension()),
+        None if is_text => ("text/plain", "txt"),
+        None => return Ok(None),
+    };
+
+    let table = lua.create_table()?;
+    table.set("mime", mime)?;
+    table.set("extension", extension)?;
+    table.set("is_text", is_text)?;
+    Ok(Some(table))
+}
+
+impl UserData for FsUtils {
+    fn add_methods<'lua, M: UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_function("basename", |_, path: String| {
+            Ok(Path::new(&path)
+                .file_name()
+                .and_then(|s| s.to_str())
+                .map(|s| s.to_string()))
+        });
+
+        methods.add_function("readlink", |_, path: String| {
+            fs::read_link(&path)
+                .map(|p| p.to_str().map(|s| s.to_string()))
+                .map_err(|e| mlua::Error::RuntimeError(e.to_string()))
+        });
+
+        methods.add_function("dirname", |lua, path: mlua::String| dirname(lua, &path));
+
+        methods.add_function("join", |lua, parts: Variadic<Value>| {
+            let mut path = PathBuf::new();
+            for part in parts {
+                match part {
+                    Value::Table(parts) => {
+                        for part in parts.sequence_values::<String>() {
+                            path.push(part?);
+                        }
+                    }
+                    part => path.push(String::from_lua(part, lua)?),
+                }
+            }
+            Ok(path.to_string_lossy().into_owned())
+        });
+
+        methods.add_function("extension", |_, path: String| {
+            Ok(Path::new(&path)
+                .extension()
+                .map(|s| s.to_string_lossy().into_owned()))
+        });
+
+        methods.add_function("stem", |_, path: String| {
+            Ok(Path::new(&path)
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned()))
+        });
+
+        methods.add_function("with_extension", |_, (path, ext): (String, String)| {
+            Ok(Path::new(&path)
+                .with_extension(ext)
+                .to_string_lossy()
+                .into_owned())
+        });
+
+        methods.add_function("read_file", |_, path: String| {
+            fs::read_to_string(&path).map_err(|e| io_error("read", &path, e))
+        });
+
+        methods.add_function(
+            "write_file",
+            |_, (path, contents, create_dirs): (String, mlua::String, Option<bool>)| {
+                if create_dirs.unwrap_or(false)
+                    && let Some(parent) = Path::new(&path).parent()
+                {
+                    fs::create_dir_all(parent)
+                        .map_err(|e| io_error("create parent directories of", &path, e))?;
+                }
+                fs::write(&path, contents.as_bytes()).map_err(|e| io_error("write", &path, e))
+            },
+        );
+
+        methods.add_function(
+            "append_file",
+            |_, (path, data, opts): (String, mlua::String, Option<Table>)| {
+                let create = opt(opts.as_ref(), "create")?.unwrap_or(true);
+                let mut file = fs::OpenOptions::new()
+                    .append(true)
+                    .create(create)
+                    .open(&path)
+                    .map_err(|e| io_error("open", &path, e))?;
+
+                // a single write keeps appends from different callers from interleaving
+                let data = data.as_bytes();
+                file.write_all(&data)
+                    .map_err(|e| io_error("append to", &path, e))?;
+                Ok(data.len())
+            },
+        );
+
+        methods.add_function("exists", |_, (path, no_follow): (String, Option<bool>)| {
+            Ok(metadata(&path, no_follow).is_some())
+        });
+
+        methods.add_function("is_file", |_, (path, no_follow): (String, Option<bool>)| {
+            Ok(metadata(&path, no_follow).is_some_and(|m| m.is_file()))
+        });
+
+        methods.add_function("is_dir", |_, (path, no_follow): (String, Option<bool>)| {
+            Ok(metadata(&path, no_follow).is_some_and(|m| m.is_dir()))
+        });
+
+        methods.add_function("mkdir", |_, (path, mode): (String, Option<Value>)| {
+            create_dir(&path, false, mode)
+        });
+
+        methods.add_function("mkdir_all", |_, (path, mode): (String, Option<Value>)| {
+            create_dir(&path, true, mode)
+        });
+
+        methods.add_function("remove", |_, path: String| remove(&path));
+
+        methods.add_function("remove_all", |_, (path, opts): (String, Option<Table>)| {
+            let missing_ok = opt(opts.as_ref(), "missing_ok")?.unwrap_or(false);
+            remove_all(&path, missing_ok)
+        });
+
+        methods.add_function(
+            "copy",
+            |_, (src, dst, opts): (String, String, Option<Table>)| {
+                let recursive = opt(opts.as_ref(), "recursive")?.unwrap_or(false);
+                let preserve: Vec<String> = opt(opts.as_ref(), "preserve")?.unwrap_or_default();
+                if let Some(unknown) = preserve
+                    .iter()
+                    .find(|p| !matches!(p.as_str(), "mode" | "times"))
+                {
+                    return Err(mlua::Error::RuntimeError(format!(
+                        "unknown metadata '{unknown}' to preserve (expected mode or times)"
+                    )));
+                }
+                let opts = CopyOptions {
+                    overwrite: opt(opts.as_ref(), "overwrite")?.unwrap_or(true),
+                    preserve_mode: preserve.iter().any(|p| p == "mode"),
+                    preserve_times: preserve.iter().any(|p| p == "times"),
+                };
+                let (src, dst) = (Path::new(&src), Path::new(&dst));
+
+                let meta = fs::metadata(src).map_err(|e| copy_error(src, dst, e))?;
+                if !meta.is_dir() {
+                    copy_file(src, dst, &opts)
+                } else if recursive {
+                    copy_tree(src, dst, &opts)
+                } else {
+                    Err(copy_error(
+                        src,
+                        dst,
+                        "source is a directory (pass recursive = true to copy it)",
+                    ))
+                }
+            },
+        );
+
+        methods.add_function(
+            "rename",
+            |_, (src, dst, opts): (String, String, Option<Table>)| {
+                let overwrite = opt(opts.as_ref(), "overwrite")?.unwrap_or(true);
+                rename(Path::new(&src), Path::new(&dst), overwrite)
+            },
+        );
+
+        methods.add_function("list_dir", |lua, (path, opts): (String, Option<Table>)| {
+            list_dir(lua, &path, opts.as_ref())
+        });
+
+        methods.add_function("walk", |lua, (path, opts): (String, Option<Table>)| {
+            walk(lua, &path, opts.as_ref())
+        });
+
+        methods.add_function("glob", |_, (pattern, base): (String, Option<String>)| {
+            glob(&pattern, base.as_deref())
+        });
+
+        methods.add_function("stat", |lua, path: String| stat(lua, &path, true));
+
+        methods.add_function("lstat", |lua, path: String| stat(lua, &path, false));
+
+        methods.add_function("chmod", |_, (path, mode): (String, Value)| {
+            chmod(&path, parse_mode(mode)?)
+        });
+
+        methods.add_function("make_executable", |_, path: String| make_executable(&path));
+
+        methods.add_function("tempfile", |_, opts: Option<Table>| {
+            temp::tempfile(opts.as_ref())
+        });
+
+        methods.add_function("tempdir", |_, opts: Option<Table>| {
+            temp::tempdir(opts.as_ref())
+        });
+
+        methods.add_function(
+            "symlink",
+            |_, (target, link, opts): (String, String, Option<Table>)| {
+                let force = opt(opts.as_ref(), "force")?.unwrap_or(false);
+                create_link("symlink", &target, &link, force, symlink)
+            },
+        );
+
+        methods.add_function(
+            "hardlink",
+            |_, (target, link, opts): (String, String, Option<Table>)| {
+                let force = opt(opts.as_ref(), "force")?.unwrap_or(false);
+                create_link("hard link", &target, &link, force, |target, link| {
+                    fs::hard_link(target, link)
+                })
+            },
+        );
+
+        methods.add_function("canonicalize", |_, path: String| {
+            let canonical = fs::canonicalize(&path).map_err(|e| io_error("resolve", &path, e))?;
+            utf8_path(canonical).map(strip_verbatim)
+        });
+
+        methods.add_function("absolute", |_, path: String| {
+            let absolute =
+                std::path::absolute(&path).map_err(|e| io_error("make absolute", &path, e))?;
+            utf8_path(absolute)
+        });
+
+        methods.add_function(
+            "touch",
+            |lua, (path, time, opts): (String, Option<Value>, Option<Table>)| {
+                // the timestamp can be left out, with the options coming second
+                let (time, opts) = match time {
+                    Some(Value::Table(opts)) => (None, Some(opts)),
+                    Some(time) => (Some(f64::from_lua(time, lua)?), opts),
+                    None => (None, opts),
+                };
+                let create = opt(opts.as_ref(), "create")?.unwrap_or(true);
+                touch(&path, time, create)
+            },
+        );
+
+        methods.add_function("lines", |lua, (path, opts): (String, Option<Table>)| {
+            let chunk_size = opt(opts.as_ref(), "chunk_size")?;
+            if chunk_size == Some(0) {
+                return Err(mlua::Error::RuntimeError(
+                    "chunk_size must be greater than zero".into(),
+                ));
+            }
+            lines(lua, &path, chunk_size)
+        });
+
+        methods.add_function(
+            "read_bytes",
+            |lua, (path, offset, length): (String, Option<u64>, Option<u64>)| {
+                lua.create_string(read_bytes(&path, offset, length)?)
+            },
+        );
+
+        methods.add_function("write_bytes", |_, (path, data): (String, mlua::String)| {
+            fs::write(&path, data.as_bytes()).map_err(|e| io_error("write", &path, e))
+        });
+
+        methods.add_function(
+            "atomic_write",
+            |_, (path, contents, opts): (String, mlua::String, Option<Table>)| {
+                let mode = opt(opts.as_ref(), "mode")?;
+                atomic_write(&path, &contents.as_bytes(), mode)
+            },
+        );
+
+        methods.add_function("home_dir", |_, _: ()| {
+            Ok(BaseDirs::new().map(|dirs| dirs.home_dir().to_string_lossy().into_owned()))
+        });
+
+        methods.add_function("config_dir", |_, app: Option<String>| {
+            Ok(standard_dir(
+                app,
+                BaseDirs::config_dir,
+                ProjectDirs::config_dir,
+            ))
+        });
+
+        methods.add_function("data_dir", |_, app: Option<String>| {
+            Ok(standard_dir(app, BaseDirs::data_dir, ProjectDirs::data_dir))
+        });
+
+        methods.add_function("cache_dir", |_, app: Option<String>| {
+            Ok(standard_dir(
+                app,
+                BaseDirs::cache_dir,
+                ProjectDirs::cache_dir,
+            ))
+        });
+
+        methods.add_function("expanduser", |_, path: String| Ok(expanduser(&path)));
+
+        methods.add_function(
+            "watch",
+            |lua, (paths, callback, opts): (Value, Function, Option<Table>)| {
+                watch::watch(lua, paths, callback, opts.as_ref())
+            },
+        );
+
+        methods.add_function("disk_usage", |lua, path: String| disk_usage(lua, &path));
+
+        methods.add_function("dir_size", |lua, (path, opts): (String, Option<Table>)| {
+            dir_size(lua, &path, opts.as_ref())
+        });
+
+        methods.add_function("hash_file", |_, (path, algo): (String, String)| {
+            hash::hash_file(&path, &algo)
+        });
+
+        methods.add_function("lock_file", |_, (path, opts): (String, Option<Table>)| {
+            lock::lock_file(&path, opts.as_ref(), true)
+        });
+
+        methods.add_function(
+            "try_lock_file",
+            |_, (path, opts): (String, Option<Table>)| lock::lock_file(&path, opts.as_ref(), false),
+        );
+
+        methods.add_function(
+            "tail",
+            |lua, (path, n, opts): (String, usize, Option<Table>)| {
+                tail::tail(lua, &path, n, opts.as_ref())
+            },
+        );
+
+        methods.add_function("which", |lua, (name, opts): (String, Option<Table>)| {
+            if opt(opts.as_ref(), "all")?.unwrap_or(false) {
+                let matches = which::which_all(&name)
+                    .into_iter()
+                    .flatten()
+                    .map(|path| path.to_string_lossy().into_owned());
+                return lua.create_sequence_from(matches).map(Value::Table);
+            }
+
+            match which::which(&name) {
+                Ok(path) => lua
+                    .create_string(path.to_string_lossy().as_ref())
+                    .map(Value::String),
+                Err(_) => Ok(Value::Nil),
+            }
+        });
+
+        methods.add_function("normalize", |_, path: String| {
+            Ok(normalize(Path::new(&path)).to_string_lossy().into_owned())
+        });
+
+        methods.add_function("relative", |_, (path, base): (String, String)| {
+            Ok(relative(Path::new(&path), Path::new(&base))
+                .map(|path| path.to_string_lossy().into_owned()))
+        });
+
+        methods.add_function("open", |_, (path, mode): (String, Option<String>)| {
+            file::open(&path, mode.as_deref().unwrap_or("r"))
+        });
+
+        methods.add_function("is_newer_than", |_, (a, b): (String, String)| {
+            let a_time = modified(&a).map_err(|e| io_error("stat", &a, e))?;
+            match modified(&b) {
+                Ok(b_time) => Ok(a_time > b_time),
+                // like make, a missing output always needs rebuilding
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(true),
+                Err(e) => Err(io_error("stat", &b, e)),
+            }
+        });
+
+        methods.add_function("set_mtime", |_, (path, time): (String, f64)| {
+            filetime::set_file_mtime(&path, file_time(time))
+                .map_err(|e| io_error("set the modification time of", &path, e))
+        });
+
+        methods.add_function("trash", |lua, paths: Value| {
+            let paths: Vec<String> = match paths {
+                Value::Table(paths) => paths.sequence_values().collect::<mlua::Result<_>>()?,
+                path => vec![String::from_lua(path, lua)?],
+            };
+
+            // every path is tried, so one failure doesn't stop a bulk cleanup
+            let failures: Vec<String> = paths
+                .iter()
+                .filter_map(|path| trash::delete(path).err().map(|e| format!("'{path}': {e}")))
+                .collect();
+
+            if failures.is_empty() {
+                Ok(())
+            } else {
+                Err(mlua::Error::RuntimeError(format!(
+                    "failed to move {} of {} paths to the trash: {}",
+                    failures.len(),
+                    paths.len(),
+                    failures.join("; ")
+                )))
+            }
+        });
+
+        methods.add_function("file_type", |lua, path: String| {
+            // magic numbers sit at the start, so only the first block is read
+            let head = read_bytes(&path, None, Some(8192))?;
+            detect_type(lua, &head)
+        });
+
+        methods.add_function("detect_type", |lua, data: mlua::String| {
+            detect_type(lua, &data.as_bytes())
+        });
+
+        methods.add_function("copy_metadata", |_, (src, dst): (String, String)| {
+            let (src, dst) = (Path::new(&src), Path::new(&dst));
+            let meta = fs::metadata(src).map_err(|e| copy_error(src, dst, e))?;
+            let opts = CopyOptions {
+                overwrite: true,
+                preserve_mode: true,
+                preserve_times: true,
+            };
+            preserve_metadata(src, dst, &meta, &opts)
+        });
+
+        methods.add_function(
+            "chown",
+            |_, (path, user, group, opts): (String, Value, Value, Option<Table>)| {
+                let recursive = opt(opts.as_ref(), "recursive")?.unwrap_or(false);
+                #[cfg(unix)]
+                return owner::chown(&path, user, group, recursive);
+
+                #[cfg(not(unix))]
+                {
+                    let _ = (user, group, recursive);
+                    Err(mlua::Error::RuntimeError(format!(
+                        "failed to change the owner of '{path}': not supported on this platform"
+                    )))
+                }
+            },
+        );
+
+        methods.add_function("mmap", |_, path: String| mmap::mmap(&path));
+
+        methods.add_function("cwd", |_, _: ()| {
+            let cwd = env::current_dir().map_err(|e| {
+                mlua::Error::RuntimeError(format!("failed to get the current directory: {e}"))
+            })?;
+            utf8_path(cwd)
+        });
+
+        methods.add_function("chdir", |_, path: String| {
+            env::set_current_dir(&path).map_err(|e| io_error("change directory to", &path, e))
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mlua::Lua;
+    use tempfile::TempDir;
+
+    use super::super::testing::{error, eval, lua};
+
+    /// A Lua state whose `dir` global is the path of a new temporary
+    /// directory.
+    fn lua_in_dir() -> (Lua, TempDir) {
+        let lua = lua();
+        let dir = tempfile::tempdir().unwrap();
+        lua.globals()
+            .set("dir", dir.path().to_str().unwrap())
+            .unwrap();
+        (lua, dir)
+    }
+
+    #[test]
+    fn write_file_then_read_file() {
+        let (lua, dir) = lua_in_dir();
+        eval::<()>(&lua, r#"fs.write_file(dir .. "/a.txt", "hello\nworld")"#);
+        let read: String = eval(&lua, r#"return fs.read_file(dir .. "/a.txt")"#);
+        assert_eq!(read, "hello\nworld");
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("a.txt")).unwrap(),
+            "hello\nworld"
+        );
+    }
+
+    #[test]
+    fn read_file_of_a_missing_file_fails() {
+        let (lua, _dir) = lua_in_dir();
+        let message = error(&lua, r#"fs.read_file(dir .. "/missing.txt")"#);
+        assert!(message.contains("failed to read"), "{message}");
+        assert!(message.contains("missing.txt"), "{message}");
+        assert!(message.contains("No such file"), "{message}");
+    }
+
+    #[test]
+    fn write_file_into_a_missing_directory() {
+        let (lua, dir) = lua_in_dir();
+        let message = error(&lua, r#"fs.write_file(dir .. "/no/such/dir/a.txt", "x")"#);
+        assert!(message.contains("failed to write"), "{message}");
+        assert!(!dir.path().join("no").exists());
+
+        eval::<()>(
+            &lua,
+            r#"fs.write_file(dir .. "/no/such/dir/a.txt", "x", true)"#,
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("no/such/dir/a.txt")).unwrap(),
+            "x"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn read_and_write_without_permission_fail() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // root can read and write anything regardless of the mode
+        if unsafe { libc::geteuid() } == 0 {
+            return;
+        }
+        let (lua, dir) = lua_in_dir();
+        let path = dir.path().join("locked.txt");
+        std::fs::write(&path, "secret").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o000)).unwrap();
+
+        let message = error(&lua, r#"fs.read_file(dir .. "/locked.txt")"#);
+        assert!(message.contains("Permission denied"), "{message}");
+        let message = error(&lua, r#"fs.write_file(dir .. "/locked.txt", "x")"#);
+        assert!(message.contains("Permission denied"), "{message}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn predicates_on_a_dangling_symlink() {
+        let (lua, dir) = lua_in_dir();
+        std::os::unix::fs::symlink(dir.path().join("nowhere"), dir.path().join("link")).unwrap();
+
+        let followed: (bool, bool, bool) = eval(
+            &lua,
+            r#"local p = dir .. "/link"; return fs.exists(p), fs.is_file(p), fs.is_dir(p)"#,
+        );
+        assert_eq!(followed, (false, false, false));
+        // the link itself exists, but is neither a file nor a directory
+        let link: (bool, bool, bool) = eval(
+            &lua,
+            r#"local p = dir .. "/link"; return fs.exists(p, true), fs.is_file(p, true), fs.is_dir(p, true)"#,
+        );
+        assert_eq!(link, (true, false, false));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn predicates_follow_symlinks_by_default() {
+        let (lua, dir) = lua_in_dir();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("file"), "").unwrap();
+        std::os::unix::fs::symlink(dir.path().join("sub"), dir.path().join("to_dir")).unwrap();
+        std::os::unix::fs::symlink(dir.path().join("file"), dir.path().join("to_file")).unwrap();
+
+        let checks: (bool, bool, bool, bool) = eval(
+            &lua,
+            r#"return fs.is_dir(dir .. "/to_dir"), fs.is_file(dir .. "/to_file"),
+                fs.is_dir(dir .. "/to_dir", true), fs.is_file(dir .. "/to_file", true)"#,
+        );
+        assert_eq!(checks, (true, true, false, false));
+    }
+
+    #[test]
+    fn predicates_on_plain_paths() {
+        let (lua, dir) = lua_in_dir();
+        std::fs::write(dir.path().join("file"), "").unwrap();
+        let checks: (bool, bool, bool, bool, bool) = eval(
+            &lua,
+            r#"return fs.exists(dir), fs.is_dir(dir), fs.is_file(dir),
+                fs.is_file(dir .. "/file"), fs.exists(dir .. "/missing")"#,
+        );
+        assert_eq!(checks, (true, true, false, true, false));
+        // a path under a file can't exist, but asking isn't an error
+        let under_file: bool = eval(&lua, r#"return fs.exists(dir .. "/file/child")"#);
+        assert!(!under_file);
+    }
+
+    /// Creates `tree/a.txt` and `tree/sub/b.sh`, the latter executable and
+    /// with an old modification time.
+    fn make_tree(root: &std::path::Path) {
+        std::fs::create_dir_all(root.join("tree/sub")).unwrap();
+        std::fs::write(root.join("tree/a.txt"), "a").unwrap();
+        std::fs::write(root.join("tree/sub/b.sh"), "#!/bin/sh\n").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(
+                root.join("tree/sub/b.sh"),
+                std::fs::Permissions::from_mode(0o751),
+            )
+            .unwrap();
+        }
+        filetime::set_file_mtime(
+            root.join("tree/sub/b.sh"),
+            filetime::FileTime::from_unix_time(1_000_000_000, 0),
+        )
+        .unwrap();
+    }
+
+    fn assert_moved_tree(src: &std::path::Path, dst: &std::path::Path) {
+        assert!(!src.exists());
+        assert_eq!(std::fs::read_to_string(dst.join("a.txt")).unwrap(), "a");
+        let meta = std::fs::metadata(dst.join("sub/b.sh")).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(meta.permissions().mode() & 0o7777, 0o751);
+        }
+        assert_eq!(
+            filetime::FileTime::from_last_modification_time(&meta).unix_seconds(),
+            1_000_000_000
+        );
+    }
+
+    #[test]
+    fn rename_moves_a_directory() {
+        let (lua, dir) = lua_in_dir();
+        make_tree(dir.path());
+        eval::<()>(&lua, r#"fs.rename(dir .. "/tree", dir .. "/moved")"#);
+        assert_moved_tree(&dir.path().join("tree"), &dir.path().join("moved"));
+    }
+
+    #[test]
+    fn moving_a_directory_across_devices_copies_and_deletes_it() {
+        // the fallback used when `rename` fails with EXDEV, called directly
+        // since the tests may not have a second filesystem
+        let dir = tempfile::tempdir().unwrap();
+        make_tree(dir.path());
+        let (src, dst) = (dir.path().join("tree"), dir.path().join("moved"));
+        super::move_across_devices(&src, &dst, false).unwrap();
+        assert_moved_tree(&src, &dst);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn rename_moves_a_directory_to_another_filesystem() {
+        use std::os::unix::fs::MetadataExt;
+
+        let other = std::path::Path::new("/dev/shm");
+        let dir = tempfile::tempdir().unwrap();
+        let Ok(target) = tempfile::tempdir_in(other) else {
+            return;
+        };
+        if std::fs::metadata(dir.path()).unwrap().dev()
+            == std::fs::metadata(target.path()).unwrap().dev()
+        {
+            return;
+        }
+        make_tree(dir.path());
+        let lua = lua();
+        lua.globals()
+            .set("src", dir.path().join("tree").to_str().unwrap())
+            .unwrap();
+        lua.globals()
+            .set("dst", target.path().join("moved").to_str().unwrap())
+            .unwrap();
+        eval::<()>(&lua, "fs.rename(src, dst)");
+        assert_moved_tree(&dir.path().join("tree"), &target.path().join("moved"));
+    }
+
+    #[test]
+    fn rename_without_overwrite_keeps_the_destination() {
+        let (lua, dir) = lua_in_dir();
+        make_tree(dir.path());
+        std::fs::write(dir.path().join("taken"), "keep").unwrap();
+        let message = error(
+            &lua,
+            r#"fs.rename(dir .. "/tree", dir .. "/taken", {overwrite = false})"#,
+        );
+        assert!(message.contains("destination already exists"), "{message}");
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("taken")).unwrap(),
+            "keep"
+        );
+        assert!(dir.path().join("tree/a.txt").exists());
+    }
+
+    #[test]
+    fn stat_of_a_regular_file() {
+        let (lua, dir) = lua_in_dir();
+        std::fs::write(dir.path().join("f"), "12345").unwrap();
+        filetime::set_file_mtime(
+            dir.path().join("f"),
+            filetime::FileTime::from_unix_time(1_500_000_000, 250_000_000),
+        )
+        .unwrap();
+
+        let (size, is_file, is_dir, is_symlink, modified): (u64, bool, bool, bool, f64) = eval(
+            &lua,
+            r#"local s = fs.stat(dir .. "/f")
+            return s.size, s.is_file, s.is_dir, s.is_symlink, s.modified"#,
+        );
+        assert_eq!((size, is_file, is_dir, is_symlink), (5, true, false, false));
+        assert_eq!(modified, 1_500_000_000.25);
+        let accessed: Option<f64> = eval(&lua, r#"return fs.stat(dir .. "/f").accessed"#);
+        assert!(accessed.is_some());
+    }
+
+    #[test]
+    fn stat_of_a_directory() {
+        let (lua, _dir) = lua_in_dir();
+        let (is_file, is_dir, is_symlink): (bool, bool, bool) = eval(
+            &lua,
+            "local s = fs.stat(dir) return s.is_file, s.is_dir, s.is_symlink",
+        );
+        assert_eq!((is_file, is_dir, is_symlink), (false, true, false));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stat_follows_a_symlink_and_lstat_does_not() {
+        let (lua, dir) = lua_in_dir();
+        std::fs::write(dir.path().join("target"), "1234567890").unwrap();
+        std::os::unix::fs::symlink("target", dir.path().join("link")).unwrap();
+
+        let followed: (u64, bool, bool) = eval(
+            &lua,
+            r#"local s = fs.stat(dir .. "/link") return s.size, s.is_file, s.is_symlink"#,
+        );
+        assert_eq!(followed, (10, true, false));
+        // the link's own size is the length of the path it points to
+        let link: (u64, bool, bool) = eval(
+            &lua,
+            r#"local s = fs.lstat(dir .. "/link") return s.size, s.is_file, s.is_symlink"#,
+        );
+        assert_eq!(link, ("target".len() as u64, false, true));
+
+        std::fs::remove_file(dir.path().join("target")).unwrap();
+        let message = error(&lua, r#"fs.stat(dir .. "/link")"#);
+        assert!(message.contains("failed to stat"), "{message}");
+        let dangling: bool = eval(&lua, r#"return fs.lstat(dir .. "/link").is_symlink"#);
+        assert!(dangling);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn chmod_sets_the_mode_that_stat_reports() {
+        let (lua, dir) = lua_in_dir();
+        std::fs::write(dir.path().join("f"), "").unwrap();
+        let modes: (u32, u32, u32) = eval(
+            &lua,
+            r#"local p = dir .. "/f"
+            fs.chmod(p, tonumber("755", 8)); local a = fs.stat(p).mode
+            fs.chmod(p, "640"); local b = fs.stat(p).mode
+            fs.chmod(p, "0o4711"); local c = fs.stat(p).mode
+            return a, b, c"#,
+        );
+        assert_eq!(modes, (0o755, 0o640, 0o4711));
+    }
+
+    #[cfg(not(unix))]
+    #[test]
+    fn chmod_toggles_readonly_and_rejects_other_bits() {
+        let (lua, dir) = lua_in_dir();
+        std::fs::write(dir.path().join("f"), "").unwrap();
+        let readonly: (bool, bool) = eval(
+            &lua,
+            r#"local p = dir .. "/f"
+            fs.chmod(p, "444"); local a = fs.stat(p).readonly
+            fs.chmod(p, "644"); local b = fs.stat(p).readonly
+            return a, b"#,
+        );
+        assert_eq!(readonly, (true, false));
+        let message = error(&lua, r#"fs.chmod(dir .. "/f", "755")"#);
+        assert!(
+            message.contains("not supported on this platform"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn chmod_rejects_invalid_modes() {
+        let (lua, dir) = lua_in_dir();
+        std::fs::write(dir.path().join("f"), "").unwrap();
+        for mode in [r#""rwx""#, r#""89""#, "-1", "tonumber('17777', 8)"] {
+            let message = error(&lua, &format!(r#"fs.chmod(dir .. "/f", {mode})"#));
+            assert!(message.contains("invalid mode"), "{mode}: {message}");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn umask_is_the_one_new_files_get() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::File::create(dir.path().join("f")).unwrap();
+        let mode = std::fs::metadata(dir.path().join("f"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o666 & !super::umask());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn make_executable_adds_the_bits_the_umask_allows() {
+        let (lua, dir) = lua_in_dir();
+        std::fs::write(dir.path().join("script"), "").unwrap();
+        let mode: u32 = eval(
+            &lua,
+            r#"local p = dir .. "/script"
+            fs.chmod(p, "600"); fs.make_executable(p); return fs.stat(p).mode"#,
+        );
+        assert_eq!(mode, 0o600 | (0o111 & !super::umask()));
+        assert_ne!(mode & 0o100, 0);
+    }
+
+    #[test]
+    fn touch_creates_a_missing_file() {
+        let (lua, dir) = lua_in_dir();
+        let (size, is_file): (u64, bool) = eval(
+            &lua,
+            r#"fs.touch(dir .. "/marker") local s = fs.stat(dir .. "/marker")
+            return s.size, s.is_file"#,
+        );
+        assert_eq!((size, is_file), (0, true));
+
+        let message = error(&lua, r#"fs.touch(dir .. "/other", nil, {create = false})"#);
+        assert!(message.contains("file does not exist"), "{message}");
+        assert!(!dir.path().join("other").exists());
+    }
+
+    #[test]
+    fn touch_moves_the_mtime_of_an_existing_file() {
+        let (lua, dir) = lua_in_dir();
+        std::fs::write(dir.path().join("f"), "content").unwrap();
+        filetime::set_file_mtime(
+            dir.path().join("f"),
+            filetime::FileTime::from_unix_time(1_000_000_000, 0),
+        )
+        .unwrap();
+
+        let (before, after, size): (f64, f64, u64) = eval(
+            &lua,
+            r#"local p = dir .. "/f"
+            local before = fs.stat(p).modified
+            fs.touch(p)
+            return before, fs.stat(p).modified, fs.stat(p).size"#,
+        );
+        assert_eq!(before, 1_000_000_000.0);
+        let now = super::epoch_seconds(std::time::SystemTime::now());
+        assert!(now - after < 60.0, "{after} isn't close to {now}");
+        // touching doesn't rewrite the contents
+        assert_eq!(size, 7);
+    }
+
+    #[test]
+    fn touch_with_an_explicit_time() {
+        let (lua, _dir) = lua_in_dir();
+        let (modified, accessed): (f64, f64) = eval(
+            &lua,
+            r#"local p = dir .. "/f"
+            fs.touch(p, 1234567890.5)
+            return fs.stat(p).modified, fs.stat(p).accessed"#,
+        );
+        assert_eq!((modified, accessed), (1234567890.5, 1234567890.5));
+    }
+
+    #[test]
+    fn append_file_concatenates_and_counts_bytes() {
+        let (lua, dir) = lua_in_dir();
+        let (a, b): (usize, usize) = eval(
+            &lua,
+            r#"local p = dir .. "/log"
+            return fs.append_file(p, "first\n"), fs.append_file(p, "second\n")"#,
+        );
+        assert_eq!((a, b), (6, 7));
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("log")).unwrap(),
+            "first\nsecond\n"
+        );
+    }
+
+    #[test]
+    fn append_file_without_create_fails_on_a_missing_file() {
+        let (lua, dir) = lua_in_dir();
+        let message = error(
+            &lua,
+            r#"fs.append_file(dir .. "/log", "x", {create = false})"#,
+        );
+        assert!(message.contains("failed to open"), "{message}");
+        assert!(!dir.path().join("log").exists());
+    }
+
+    #[test]
+    fn appends_from_coroutines_keep_whole_lines() {
+        let (lua, dir) = lua_in_dir();
+        eval::<()>(
+            &lua,
+            r#"local p = dir .. "/log"
+            local function writer(name)
+                return coroutine.create(function()
+                    for i = 1, 200 do
+                        fs.append_file(p, name .. " line " .. i .. " " .. string.rep("x", 100) .. "\n")
+                        coroutine.yield()
+                    end
+                end)
+            end
+            local a, b = writer("a"), writer("b")
+            for _ = 1, 200 do
+                assert(coroutine.resume(a))
+                assert(coroutine.resume(b))
+            end"#,
+        );
+        let log = std::fs::read_to_string(dir.path().join("log")).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 400);
+        let pad = "x".repeat(100);
+        for (i, line) in lines.iter().enumerate() {
+            let name = if i % 2 == 0 { "a" } else { "b" };
+            assert_eq!(*line, format!("{name} line {} {pad}", i / 2 + 1));
+        }
+    }
+
+    #[test]
+    fn write_bytes_then_read_bytes_keeps_nul_and_invalid_utf8() {
+        let (lua, dir) = lua_in_dir();
+        let data: mlua::String = eval(
+            &lua,
+            r#"local data = "a\0b\0\0\255\254c\0"
+            fs.write_bytes(dir .. "/bin", data)
+            local read = fs.read_bytes(dir .. "/bin")
+            assert(read == data)
+            return read"#,
+        );
+        assert_eq!(&*data.as_bytes(), b"a\0b\0\0\xff\xfec\0");
+        assert_eq!(
+            std::fs::read(dir.path().join("bin")).unwrap(),
+            b"a\0b\0\0\xff\xfec\0"
+        );
+    }
+
+    #[test]
+    fn read_bytes_of_a_slice() {
+        let (lua, dir) = lua_in_dir();
+        std::fs::write(dir.path().join("bin"), b"\0\x01\x02\x03\x04\x05").unwrap();
+        let slices: (mlua::String, mlua::String, mlua::String) = eval(
+            &lua,
+            r#"local p = dir .. "/bin"
+            return fs.read_bytes(p, 2), fs.read_bytes(p, 1, 3), fs.read_bytes(p, 4, 100)"#,
+        );
+        assert_eq!(&*slices.0.as_bytes(), b"\x02\x03\x04\x05");
+        assert_eq!(&*slices.1.as_bytes(), b"\x01\x02\x03");
+        // a length past the end stops at the end
+        assert_eq!(&*slices.2.as_bytes(), b"\x04\x05");
+    }
+
+    /// The XDG base directory in `var`, or `default` under the home
+    /// directory if it's unset or relative, as the specification says.
+    #[cfg(target_os = "linux")]
+    fn xdg_dir(var: &str, default: &str) -> String {
+        match std::env::var(var) {
+            Ok(dir) if dir.starts_with('/') => dir,
+            _ => format!("{}/{default}", std::env::var("HOME").unwrap()),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn standard_dirs_follow_xdg_on_linux() {
+        let lua = lua();
+        let dirs: (String, String, String, String) = eval(
+            &lua,
+            "return fs.home_dir(), fs.config_dir(), fs.data_dir(), fs.cache_dir()",
+        );
+        assert_eq!(dirs.0, std::env::var("HOME").unwrap());
+        assert_eq!(dirs.1, xdg_dir("XDG_CONFIG_HOME", ".config"));
+        assert_eq!(dirs.2, xdg_dir("XDG_DATA_HOME", ".local/share"));
+        assert_eq!(dirs.3, xdg_dir("XDG_CACHE_HOME", ".cache"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn standard_dirs_of_an_app_on_linux() {
+        let lua = lua();
+        // the application's name is lowercased, without spaces
+        let dirs: (String, String, String) = eval(
+            &lua,
+            r#"return fs.config_dir("My App"), fs.data_dir("My App"), fs.cache_dir("My App")"#,
+        );
+        assert_eq!(dirs.0, xdg_dir("XDG_CONFIG_HOME", ".config") + "/myapp");
+        assert_eq!(dirs.1, xdg_dir("XDG_DATA_HOME", ".local/share") + "/myapp");
+        assert_eq!(dirs.2, xdg_dir("XDG_CACHE_HOME", ".cache") + "/myapp");
+    }
+
+    #[test]
+    fn hash_file_of_a_known_fixture() {
+        let (lua, dir) = lua_in_dir();
+        std::fs::write(
+            dir.path().join("fox"),
+            "The quick brown fox jumps over the lazy dog",
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("empty"), "").unwrap();
+
+        let hash = |algo: &str, file: &str| -> String {
+            eval(
+                &lua,
+                &format!(r#"return fs.hash_file(dir .. "/{file}", "{algo}")"#),
+            )
+        };
+        assert_eq!(
+            hash("sha256", "fox"),
+            "d7a8fbb307d7809469ca9abcb0082e4f8d5651e46d3cdb762d02d0bf37c9e592"
+        );
+        assert_eq!(
+            hash("sha1", "fox"),
+            "2fd4e1c67a2d28fced849ee1bb76e7391b93eb12"
+        );
+        assert_eq!(
+            hash("sha512", "fox"),
+            "07e547d9586f6a73f73fbac0435ed76951218fb7d0c8d788a309d785436bbb642e93a252a954f23912547d1e8a3b5ed6e1bfd7097821233fa0538f3db854fee6"
+        );
+        assert_eq!(hash("md5", "fox"), "9e107d9d372bb6826bd81d3542a419d6");
+        assert_eq!(
+            hash("blake3", "empty"),
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+        );
+    }
+
+    #[test]
+    fn hash_file_streams_files_larger_than_its_buffer() {
+        let (lua, dir) = lua_in_dir();
+        // a few times the 64 KiB buffer, not a multiple of it
+        let data: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+        std::fs::write(dir.path().join("big"), &data).unwrap();
+
+        let (sha256, blake3): (String, String) = eval(
+            &lua,
+            r#"local p = dir .. "/big"
+            return fs.hash_file(p, "sha256"), fs.hash_file(p, "blake3")"#,
+        );
+        assert_eq!(
+            sha256,
+            "e24bc62381f1224fbbb74688663f8f9743b9680b193edd666835e97b06e730eb"
+        );
+        assert_eq!(blake3, blake3::hash(&data).to_hex().to_string());
+    }
+
+    #[test]
+    fn hash_file_with_an_unknown_algorithm_lists_the_supported_ones() {
+        let (lua, dir) = lua_in_dir();
+        std::fs::write(dir.path().join("f"), "").unwrap();
+        let message = error(&lua, r#"fs.hash_file(dir .. "/f", "crc32")"#);
+        assert!(
+            message.contains(
+                "unknown hash algorithm 'crc32' (supported: sha256, sha1, sha512, md5, blake3)"
+            ),
+            "{message}"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn normalize_lexically() {
+        let lua = lua();
+        for (path, expected) in [
+            ("a/./b/../c", "a/c"),
+            ("a/b/", "a/b"),
+            ("a/b/../", "a"),
+            ("//a//b", "/a/b"),
+            ("/../x", "/x"),
+            ("../a/../../b", "../../b"),
+            ("a/..", "."),
+            ("./", "."),
+            ("does/not/../exist", "does/exist"),
+        ] {
+            let normalized: String = eval(&lua, &format!("return fs.normalize({path:?})"));
+            assert_eq!(normalized, expected, "fs.normalize({path:?})");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn relative_paths() {
+        let lua = lua();
+        for (path, base, expected) in [
+            ("/a/b/c", "/a", Some("b/c")),
+            ("/a/b/", "/a/b", Some(".")),
+            ("/a/b", "/a/b/", Some(".")),
+            ("/a/x", "/a/b/c/", Some("../../x")),
+            ("/a", "/a/b/..", Some(".")),
+            ("/a/b/../c", "/a/b", Some("../c")),
+            ("../x", "y", Some("../../x")),
+            ("x/y", "x/z", Some("../y")),
+            // no relative path leads from a relative to an absolute path, or
+            // out of the unknown directory above `..`
+            ("a/b", "/a", None),
+            ("/a/b", "a", None),
+            ("a", "..", None),
+        ] {
+            let relative: Option<String> =
+                eval(&lua, &format!("return fs.relative({path:?}, {base:?})"));
+            assert_eq!(
+                relative.as_deref(),
+                expected,
+                "fs.relative({path:?}, {base:?})"
+            );
+        }
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn windows_paths_with_mixed_separators() {
+        let lua = lua();
+        let normalized: String = eval(&lua, r#"return fs.normalize([[C:\a/b\..\c/]])"#);
+        assert_eq!(normalized, r"C:\a\c");
+        let relative: (Option<String>, Option<String>) = eval(
+            &lua,
+            r#"return fs.relative("C:/a/b/c", [[C:\a]]), fs.relative([[C:\a\b]], [[D:\a]])"#,
+        );
+        assert_eq!(relative, (Some(r"b\c".to_string()), None));
+    }
+
+    #[test]
+    fn is_newer_than_compares_sub_second_mtimes() {
+        let (lua, _dir) = lua_in_dir();
+        let results: (bool, bool, bool, f64) = eval(
+            &lua,
+            r#"local src, out = dir .. "/src", dir .. "/out"
+            fs.write_file(src, ""); fs.write_file(out, "")
+            fs.set_mtime(out, 1700000000.25)
+            fs.set_mtime(src, 1700000000.5)
+            local newer, older = fs.is_newer_than(src, out), fs.is_newer_than(out, src)
+            fs.set_mtime(src, 1700000000.25)
+            return newer, older, fs.is_newer_than(src, out), fs.stat(src).modified"#,
+        );
+        // an equal mtime isn't newer, so nothing gets rebuilt needlessly
+        assert_eq!(results, (true, false, false, 1700000000.25));
+    }
+
+    #[test]
+    fn is_newer_than_a_missing_file() {
+        let (lua, _dir) = lua_in_dir();
+        let newer: bool = eval(
+            &lua,
+            r#"fs.write_file(dir .. "/src", "")
+            return fs.is_newer_than(dir .. "/src", dir .. "/missing")"#,
+        );
+        assert!(newer);
+
+        let message = error(
+            &lua,
+            r#"fs.is_newer_than(dir .. "/missing", dir .. "/src")"#,
+        );
+        assert!(message.contains("failed to stat"), "{message}");
+        assert!(message.contains("/missing'"), "{message}");
+    }
+
+    #[test]
+    fn set_mtime_of_a_missing_file_fails() {
+        let (lua, _dir) = lua_in_dir();
+        let message = error(&lua, r#"fs.set_mtime(dir .. "/missing", 0)"#);
+        assert!(
+            message.contains("failed to set the modification time of"),
+            "{message}"
+        );
+        let exists: bool = eval(&lua, r#"return fs.exists(dir .. "/missing")"#);
+        assert!(!exists);
+    }
+
+    /// Writes an executable script with an old mtime.
+    #[cfg(unix)]
+    fn make_executable_fixture(path: &std::path::Path) {
+        use std::os::unix::fs::PermissionsExt;
+
+        std::fs::write(path, "#!/bin/sh\necho hi\n").unwrap();
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o750)).unwrap();
+        filetime::set_file_mtime(path, filetime::FileTime::from_unix_time(1_000_000_000, 0))
+            .unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn copy_preserving_mode_and_times() {
+        let (lua, dir) = lua_in_dir();
+        make_executable_fixture(&dir.path().join("tool"));
+        let (mode, modified, kept_mtime): (u32, f64, bool) = eval(
+            &lua,
+            r#"fs.copy(dir .. "/tool", dir .. "/plain")
+            fs.copy(dir .. "/tool", dir .. "/copy", {preserve = {"mode", "times"}})
+            local s = fs.stat(dir .. "/copy")
+            return s.mode, s.modified, fs.stat(dir .. "/plain").modified == s.modified"#,
+        );
+        assert_eq!(
+            (mode, modified, kept_mtime),
+            (0o750, 1_000_000_000.0, false)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn copy_metadata_between_existing_files() {
+        let (lua, dir) = lua_in_dir();
+        make_executable_fixture(&dir.path().join("tool"));
+        let (mode, modified, contents): (u32, f64, String) = eval(
+            &lua,
+            r#"local dst = dir .. "/dst"
+            fs.write_file(dst, "other")
+            fs.chmod(dst, "600")
+            fs.copy_metadata(dir .. "/tool", dst)
+            return fs.stat(dst).mode, fs.stat(dst).modified, fs.read_file(dst)"#,
+        );
+        assert_eq!((mode, modified), (0o750, 1_000_000_000.0));
+        assert_eq!(contents, "other");
+    }
+
+    #[test]
+    fn copy_rejects_unknown_metadata() {
+        let (lua, _dir) = lua_in_dir();
+        let message = error(
+            &lua,
+            r#"fs.write_file(dir .. "/f", "")
+            fs.copy(dir .. "/f", dir .. "/g", {preserve = {"owner"}})"#,
+        );
+        assert!(
+            message.contains("unknown metadata 'owner' to preserve"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn dirname_is_a_function() {
+        let lua = lua();
+        let parents: (String, String, Option<String>, String) = eval(
+            &lua,
+            r#"assert(type(fs.dirname) == "function")
+            return fs.dirname("/a/b/c.txt"), fs.dirname("a/b/"), fs.dirname("/"), fs.dirname("a")"#,
+        );
+        assert_eq!(
+            parents,
+            ("/a/b".to_string(), "a".to_string(), None, String::new())
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dirname_of_a_non_utf8_path() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let (lua, dir) = lua_in_dir();
+        let name = OsStr::from_bytes(b"caf\xe9");
+        std::fs::create_dir(dir.path().join(name)).unwrap();
+
+        let parent: mlua::String = eval(&lua, r#"return fs.dirname(dir .. "/caf\xe9/f.txt")"#);
+        let expected = dir.path().join(name);
+        assert_eq!(&*parent.as_bytes(), expected.as_os_str().as_bytes());
+    }
+
+    /// `fs.cwd` can't be tested by changing directories, as the tests share
+    /// the process, but it's `utf8_path` that reports the bad path.
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_paths_are_an_error_rather_than_a_panic() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let path = std::path::Path::new(OsStr::from_bytes(b"/tmp/caf\xe9")).to_path_buf();
+        let message = super::utf8_path(path).unwrap_err().to_string();
+        assert!(message.contains("is not valid UTF-8"), "{message}");
+        assert!(message.contains("/tmp/caf"), "{message}");
+    }
+
+    #[test]
+    fn cwd_is_the_current_directory() {
+        let lua = lua();
+        let cwd: String = eval(&lua, "return fs.cwd()");
+        assert_eq!(std::path::Path::new(&cwd), std::env::current_dir().unwrap());
+    }
+}
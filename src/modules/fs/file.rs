@@ -0,0 +1,186 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+};
+
+use mlua::{Lua, UserData, UserDataMethods};
+
+use super::io_error;
+
+/// Writes are buffered up to this many bytes before they hit the file.
+const WRITE_BUFFER_SIZE: usize = 64 * 1024;
+
+struct OpenFile {
+    reader: BufReader<File>,
+    pending: Vec<u8>,
+}
+
+impl OpenFile {
+    /// Writes out buffered data, which has to happen before anything that
+    /// reads or moves the file position.
+    fn flush_writes(&mut self) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            self.reader.get_mut().write_all(&self.pending)?;
+            self.pending.clear();
+        }
+        Ok(())
+    }
+
+    /// Drops read-ahead data, moving the file back to the logical position.
+    fn discard_reads(&mut self) -> io::Result<()> {
+        if !self.reader.buffer().is_empty() {
+            let pos = self.reader.stream_position()?;
+            self.reader.seek(SeekFrom::Start(pos))?;
+        }
+        Ok(())
+    }
+}
+
+/// A file opened with `fs.open`, closed by `close()` or garbage collection.
+pub struct FileHandle {
+    file: Option<OpenFile>,
+    path: String,
+}
+
+impl FileHandle {
+    fn open_file(&mut self) -> mlua::Result<&mut OpenFile> {
+        self.file.as_mut().ok_or_else(|| {
+            mlua::Error::RuntimeError(format!("file '{}' already closed", self.path))
+        })
+    }
+
+    fn io<T>(
+        &mut self,
+        action: &str,
+        f: impl FnOnce(&mut OpenFile) -> io::Result<T>,
+    ) -> mlua::Result<T> {
+        let path = self.path.clone();
+        f(self.open_file()?).map_err(|e| io_error(action, &path, e))
+    }
+}
+
+impl Drop for FileHandle {
+    fn drop(&mut self) {
+        if let Some(file) = self.file.as_mut() {
+            let _ = file.flush_writes();
+        }
+    }
+}
+
+pub fn open(path: &str, mode: &str) -> mlua::Result<FileHandle> {
+    let mut options = OpenOptions::new();
+    match mode.replace('b', "").as_str() {
+        "r" => options.read(true),
+        "w" => options.write(true).create(true).truncate(true),
+        "a" => options.append(true).create(true),
+        "r+" => options.read(true).write(true),
+        "w+" => options.read(true).write(true).create(true).truncate(true),
+        "a+" => options.read(true).append(true).create(true),
+        _ => {
+            return Err(mlua::Error::RuntimeError(format!(
+                "invalid file mode '{mode}' (expected r, w, a, r+, w+ or a+)"
+            )));
+        }
+    };
+
+    let file = options.open(path).map_err(|e| io_error("open", path, e))?;
+    Ok(FileHandle {
+        file: Some(OpenFile {
+            reader: BufReader::new(file),
+            pending: Vec::new(),
+        }),
+        path: path.to_string(),
+    })
+}
+
+impl UserData for FileHandle {
+    fn add_methods<'lua, M: UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method_mut("read", |lua: &Lua, this, n: Option<u64>| {
+            let buf = this.io("read", |file| {
+                file.flush_writes()?;
+                let mut buf = Vec::new();
+                match n {
+                    Some(n) => file.reader.by_ref().take(n).read_to_end(&mut buf)?,
+                    None => file.reader.read_to_end(&mut buf)?,
+                };
+                Ok(buf)
+            })?;
+
+            // like Lua's own file:read, the end of the file gives nil
+            if buf.is_empty() && n != Some(0) {
+                return Ok(None);
+            }
+            lua.create_string(&buf).map(Some)
+        });
+
+        methods.add_method_mut("read_line", |lua: &Lua, this, _: ()| {
+            let line = this.io("read", |file| {
+                file.flush_writes()?;
+                let mut line = Vec::new();
+                if file.reader.read_until(b'\n', &mut line)? == 0 {
+                    return Ok(None);
+                }
+                if line.ends_with(b"\n") {
+                    line.pop();
+                    if line.ends_with(b"\r") {
+                        line.pop();
+                    }
+                }
+                Ok(Some(line))
+            })?;
+            line.map(|line| lua.create_string(&line)).transpose()
+        });
+
+        methods.add_method_mut("write", |_, this, data: mlua::String| {
+            this.io("write", |file| {
+                file.discard_reads()?;
+                file.pending.extend_from_slice(&data.as_bytes());
+                if file.pending.len() >= WRITE_BUFFER_SIZE {
+                    file.flush_writes()?;
+                }
+                Ok(())
+            })
+        });
+
+        methods.add_method_mut(
+            "seek",
+            |_, this, (whence, offset): (Option<String>, Option<i64>)| {
+                let offset = offset.unwrap_or(0);
+                let from = match whence.as_deref().unwrap_or("cur") {
+                    "set" => SeekFrom::Start(offset.max(0) as u64),
+                    "cur" => SeekFrom::Current(offset),
+                    "end" => SeekFrom::End(offset),
+                    whence => {
+                        return Err(mlua::Error::RuntimeError(format!(
+                            "invalid seek origin '{whence}' (expected set, cur or end)"
+                        )));
+                    }
+                };
+                this.io("seek in", |file| {
+                    file.flush_writes()?;
+                    file.reader.seek(from)
+                })
+            },
+        );
+
+        methods.add_method_mut("flush", |_, this, _: ()| {
+            this.io("flush", |file| {
+                file.flush_writes()?;
+                file.reader.get_mut().flush()
+            })
+        });
+
+        methods.add_method_mut("sync", |_, this, _: ()| {
+            this.io("sync", |file| {
+                file.flush_writes()?;
+                file.reader.get_ref().sync_all()
+            })
+        });
+
+        methods.add_method_mut("close", |_, this, _: ()| {
+            this.io("close", |file| file.flush_writes())?;
+            this.file = None;
+            Ok(())
+        });
+    }
+}
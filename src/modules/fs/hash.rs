@@ -0,0 +1,62 @@
+use std::{
+    fmt::Write,
+    fs::File,
+    io::{self, Read},
+};
+
+use sha2::Digest;
+
+use super::io_error;
+
+const SUPPORTED: &str = "sha256, sha1, sha512, md5, blake3";
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+/// Feeds the reader to `update` in fixed-size chunks, so files of any size
+/// are hashed in constant memory.
+fn stream(mut reader: impl Read, mut update: impl FnMut(&[u8])) -> io::Result<()> {
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(read) => update(&buf[..read]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn digest<D: Digest>(reader: impl Read) -> io::Result<String> {
+    let mut hasher = D::new();
+    stream(reader, |chunk| hasher.update(chunk))?;
+    Ok(hex(&hasher.finalize()))
+}
+
+pub fn hash_file(path: &str, algo: &str) -> mlua::Result<String> {
+    let hash = match algo {
+        "sha256" => digest::<sha2::Sha256>,
+        "sha1" => digest::<sha1::Sha1>,
+        "sha512" => digest::<sha2::Sha512>,
+        "md5" => digest::<md5::Md5>,
+        "blake3" => |reader| {
+            let mut hasher = blake3::Hasher::new();
+            stream(reader, |chunk| {
+                hasher.update(chunk);
+            })?;
+            Ok(hasher.finalize().to_hex().to_string())
+        },
+        _ => {
+            return Err(mlua::Error::RuntimeError(format!(
+                "unknown hash algorithm '{algo}' (supported: {SUPPORTED})"
+            )));
+        }
+    };
+
+    let file = File::open(path).map_err(|e| io_error("open", path, e))?;
+    hash(file).map_err(|e| io_error("hash", path, e))
+}
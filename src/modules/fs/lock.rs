@@ -0,0 +1,88 @@
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use mlua::{Table, UserData, UserDataMethods};
+
+use super::io_error;
+use crate::modules::opt;
+
+/// An advisory lock on a file, released by `unlock()`, by garbage collection
+/// or when the process exits.
+pub struct FileLock {
+    file: Option<File>,
+    path: String,
+}
+
+impl UserData for FileLock {
+    fn add_methods<'lua, M: UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method_mut("unlock", |_, this, _: ()| {
+            if let Some(file) = this.file.take() {
+                file.unlock()
+                    .map_err(|e| io_error("unlock", &this.path, e))?;
+            }
+            Ok(())
+        });
+    }
+}
+
+fn try_lock(file: &File, shared: bool) -> Result<(), TryLockError> {
+    if shared {
+        file.try_lock_shared()
+    } else {
+        file.try_lock()
+    }
+}
+
+fn contended(path: &str) -> mlua::Error {
+    mlua::Error::RuntimeError(format!(
+        "failed to lock '{path}': lock held by another process"
+    ))
+}
+
+/// Locks the file at `path`, creating it if needed. Without `wait`, a
+/// contended lock returns `None` right away.
+pub fn lock_file(path: &str, opts: Option<&Table>, wait: bool) -> mlua::Result<Option<FileLock>> {
+    let shared = opt(opts, "shared")?.unwrap_or(false);
+    let timeout = opt::<u64>(opts, "timeout_ms")?.map(Duration::from_millis);
+
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|e| io_error("open", path, e))?;
+
+    let locked = |file: File| {
+        Ok(Some(FileLock {
+            file: Some(file),
+            path: path.to_string(),
+        }))
+    };
+
+    if wait && timeout.is_none() {
+        if shared {
+            file.lock_shared()
+        } else {
+            file.lock()
+        }
+        .map_err(|e| io_error("lock", path, e))?;
+        return locked(file);
+    }
+
+    let deadline = Instant::now() + timeout.unwrap_or_default();
+    loop {
+        match try_lock(&file, shared) {
+            Ok(()) => return locked(file),
+            Err(TryLockError::Error(e)) => return Err(io_error("lock", path, e)),
+            Err(TryLockError::WouldBlock) if !wait => return Ok(None),
+            Err(TryLockError::WouldBlock) if Instant::now() >= deadline => {
+                return Err(contended(path));
+            }
+            Err(TryLockError::WouldBlock) => thread::sleep(Duration::from_millis(10)),
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use std::fs::File;
+
+use memchr::memmem;
+use memmap2::Mmap;
+use mlua::{Lua, UserData, UserDataMethods};
+
+use super::io_error;
+
+/// A read-only mapping created by `fs.mmap`, unmapped when collected.
+pub struct MappedFile {
+    map: Mmap,
+    path: String,
+}
+
+impl MappedFile {
+    fn check_range(&self, offset: u64, len: u64) -> mlua::Result<usize> {
+        let file_len = self.map.len() as u64;
+        match offset.checked_add(len) {
+            Some(end) if end <= file_len => Ok(offset as usize),
+            _ => Err(mlua::Error::RuntimeError(format!(
+                "{len} bytes at offset {offset} are out of bounds for '{}' ({file_len} bytes long)",
+                self.path
+            ))),
+        }
+    }
+}
+
+pub fn mmap(path: &str) -> mlua::Result<MappedFile> {
+    let file = File::open(path).map_err(|e| io_error("open", path, e))?;
+    // SAFETY: the mapping is read-only; as with any mmap, another process
+    // truncating the file while it is mapped is outside our control
+    let map = unsafe { Mmap::map(&file) }.map_err(|e| io_error("map", path, e))?;
+    Ok(MappedFile {
+        map,
+        path: path.to_string(),
+    })
+}
+
+impl UserData for MappedFile {
+    fn add_methods<'lua, M: UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("len", |_, this, _: ()| Ok(this.map.len()));
+
+        methods.add_method("slice", |lua: &Lua, this, (offset, len): (u64, u64)| {
+            let start = this.check_range(offset, len)?;
+            lua.create_string(&this.map[start..start + len as usize])
+        });
+
+        methods.add_method(
+            "find",
+            |_, this, (needle, from): (mlua::String, Option<u64>)| {
+                let from = from.unwrap_or(0);
+                let start = this.check_range(from, 0)?;
+                Ok(memmem::find(&this.map[start..], &needle.as_bytes()).map(|pos| start + pos))
+            },
+        );
+    }
+}
@@ -0,0 +1,111 @@
+use std::{
+    ffi::{CStr, CString, OsStr},
+    fs,
+    os::unix::{ffi::OsStrExt, fs::lchown},
+    path::{Path, PathBuf},
+};
+
+use mlua::Value;
+
+use super::io_error;
+
+/// Runs one of the reentrant `get*nam_r` lookups, growing the buffer until
+/// the entry fits, and hands the found entry to `f`.
+fn lookup<E, T>(
+    name: &str,
+    get: unsafe extern "C" fn(
+        *const libc::c_char,
+        *mut E,
+        *mut libc::c_char,
+        libc::size_t,
+        *mut *mut E,
+    ) -> libc::c_int,
+    f: impl FnOnce(&E) -> T,
+) -> Option<T> {
+    let name = CString::new(name).ok()?;
+    let mut buf: Vec<libc::c_char> = vec![0; 1024];
+    loop {
+        let mut entry: E = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+        let rc = unsafe {
+            get(
+                name.as_ptr(),
+                &mut entry,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        };
+
+        match rc {
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            0 if !result.is_null() => return Some(f(&entry)),
+            _ => return None,
+        }
+    }
+}
+
+pub fn user_home(name: &str) -> Option<PathBuf> {
+    lookup(name, libc::getpwnam_r, |passwd| {
+        let dir = unsafe { CStr::from_ptr(passwd.pw_dir) };
+        PathBuf::from(OsStr::from_bytes(dir.to_bytes()))
+    })
+}
+
+/// Resolves a numeric id, or a user or group name with `resolve`. `nil`
+/// leaves the id unchanged.
+fn resolve_id(
+    value: Value,
+    what: &str,
+    resolve: impl Fn(&str) -> Option<u32>,
+) -> mlua::Result<Option<u32>> {
+    let unknown = |name: &str| mlua::Error::RuntimeError(format!("unknown {what} '{name}'"));
+    match value {
+        Value::Nil => Ok(None),
+        Value::Integer(id) => u32::try_from(id)
+            .map(Some)
+            .map_err(|_| unknown(&id.to_string())),
+        Value::Number(id) if id.fract() == 0.0 && id >= 0.0 => Ok(Some(id as u32)),
+        Value::String(name) => {
+            let name = name.to_str()?;
+            match name.parse() {
+                Ok(id) => Ok(Some(id)),
+                Err(_) => resolve(&name).map(Some).ok_or_else(|| unknown(&name)),
+            }
+        }
+        other => Err(mlua::Error::RuntimeError(format!(
+            "invalid {what}: expected a number or a name, got {}",
+            other.type_name()
+        ))),
+    }
+}
+
+fn chown_tree(path: &Path, uid: Option<u32>, gid: Option<u32>) -> mlua::Result<()> {
+    let path_str = path.to_string_lossy();
+    // lchown changes symlinks themselves, and the walk never follows them
+    lchown(path, uid, gid).map_err(|e| io_error("change the owner of", &path_str, e))?;
+
+    let meta = fs::symlink_metadata(path).map_err(|e| io_error("stat", &path_str, e))?;
+    if meta.is_dir() {
+        for entry in fs::read_dir(path).map_err(|e| io_error("read", &path_str, e))? {
+            let entry = entry.map_err(|e| io_error("read", &path_str, e))?;
+            chown_tree(&entry.path(), uid, gid)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn chown(path: &str, user: Value, group: Value, recursive: bool) -> mlua::Result<()> {
+    let uid = resolve_id(user, "user", |name| {
+        lookup(name, libc::getpwnam_r, |passwd| passwd.pw_uid)
+    })?;
+    let gid = resolve_id(group, "group", |name| {
+        lookup(name, libc::getgrnam_r, |group| group.gr_gid)
+    })?;
+
+    if recursive {
+        chown_tree(Path::new(path), uid, gid)
+    } else {
+        lchown(path, uid, gid).map_err(|e| io_error("change the owner of", path, e))
+    }
+}
@@ -0,0 +1,132 @@
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    thread,
+    time::{Duration, Instant},
+};
+
+use mlua::{Function, Lua, Table, Value};
+
+use super::io_error;
+use crate::modules::opt;
+
+const BLOCK_SIZE: u64 = 8 * 1024;
+
+fn strip_cr(mut line: Vec<u8>) -> Vec<u8> {
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    line
+}
+
+/// Reads blocks backwards from the end of the file until it has seen enough
+/// newlines for `n` lines, so only the tail of the file is ever read.
+fn last_lines(file: &mut File, len: u64, n: usize) -> io::Result<Vec<Vec<u8>>> {
+    if n == 0 {
+        return Ok(Vec::new());
+    }
+
+    let mut blocks = Vec::new();
+    let mut pos = len;
+    let mut newlines = 0;
+    while pos > 0 && newlines <= n {
+        let size = BLOCK_SIZE.min(pos);
+        pos -= size;
+        file.seek(SeekFrom::Start(pos))?;
+        let mut block = vec![0; size as usize];
+        file.read_exact(&mut block)?;
+        newlines += block.iter().filter(|b| **b == b'\n').count();
+        blocks.push(block);
+    }
+
+    let mut tail: Vec<u8> = blocks.into_iter().rev().flatten().collect();
+    if tail.ends_with(b"\n") {
+        tail.pop();
+    }
+    if tail.is_empty() && len == 0 {
+        return Ok(Vec::new());
+    }
+
+    let lines: Vec<&[u8]> = tail.split(|b| *b == b'\n').collect();
+    let skip = lines.len().saturating_sub(n);
+    Ok(lines[skip..]
+        .iter()
+        .map(|line| strip_cr(line.to_vec()))
+        .collect())
+}
+
+/// Polls the file for appended data and hands every complete line to
+/// `on_line`, until it returns `false` or `timeout` elapses.
+fn follow(
+    lua: &Lua,
+    path: &str,
+    mut file: File,
+    mut offset: u64,
+    on_line: Function,
+    poll: Duration,
+    timeout: Option<Duration>,
+) -> mlua::Result<()> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut partial = Vec::new();
+
+    loop {
+        let len = file
+            .metadata()
+            .map_err(|e| io_error("stat", path, e))?
+            .len();
+        if len < offset {
+            // the file was truncated, so start over from its beginning
+            offset = 0;
+            partial.clear();
+        }
+
+        if len > offset {
+            file.seek(SeekFrom::Start(offset))
+                .map_err(|e| io_error("seek in", path, e))?;
+            let read = file
+                .by_ref()
+                .take(len - offset)
+                .read_to_end(&mut partial)
+                .map_err(|e| io_error("read", path, e))?;
+            offset += read as u64;
+
+            while let Some(end) = partial.iter().position(|b| *b == b'\n') {
+                let mut line: Vec<u8> = partial.drain(..=end).collect();
+                line.pop();
+                let keep_going: Value = on_line.call(lua.create_string(strip_cr(line))?)?;
+                if matches!(keep_going, Value::Boolean(false)) {
+                    return Ok(());
+                }
+            }
+        }
+
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Ok(());
+        }
+        thread::sleep(poll);
+    }
+}
+
+pub fn tail(lua: &Lua, path: &str, n: usize, opts: Option<&Table>) -> mlua::Result<Table> {
+    let mut file = File::open(path).map_err(|e| io_error("open", path, e))?;
+    let len = file
+        .metadata()
+        .map_err(|e| io_error("stat", path, e))?
+        .len();
+
+    let table = lua.create_table()?;
+    for line in last_lines(&mut file, len, n).map_err(|e| io_error("read", path, e))? {
+        table.push(lua.create_string(line)?)?;
+    }
+
+    if opt(opts, "follow")?.unwrap_or(false) {
+        let on_line: Function = opt(opts, "on_line")?.ok_or_else(|| {
+            mlua::Error::RuntimeError("follow = true requires an on_line callback".into())
+        })?;
+        let poll = Duration::from_millis(opt(opts, "poll_ms")?.unwrap_or(250));
+        let timeout = opt::<u64>(opts, "timeout_ms")?.map(Duration::from_millis);
+        follow(lua, path, file, len, on_line, poll, timeout)?;
+    }
+
+    Ok(table)
+}
@@ -0,0 +1,84 @@
+use std::{fs, io, path::Path};
+
+use mlua::{Lua, Table, UserData, UserDataFields, UserDataMethods};
+use tempfile::{TempDir, TempPath};
+
+use super::io_error;
+use crate::modules::opt;
+
+enum TempEntry {
+    File(TempPath),
+    Dir(TempDir),
+}
+
+/// A temporary file or directory that is removed once the handle is garbage
+/// collected or the Lua state shuts down, unless `keep()` was called.
+pub struct TempHandle(TempEntry);
+
+impl TempHandle {
+    fn path(&self) -> &Path {
+        match &self.0 {
+            TempEntry::File(path) => path,
+            TempEntry::Dir(dir) => dir.path(),
+        }
+    }
+}
+
+fn create<T>(
+    opts: Option<&Table>,
+    what: &str,
+    make: impl FnOnce(&tempfile::Builder) -> io::Result<T>,
+) -> mlua::Result<T> {
+    let prefix: Option<String> = opt(opts, "prefix")?;
+    let suffix: Option<String> = opt(opts, "suffix")?;
+
+    let mut builder = tempfile::Builder::new();
+    if let Some(prefix) = &prefix {
+        builder.prefix(prefix);
+    }
+    if let Some(suffix) = &suffix {
+        builder.suffix(suffix);
+    }
+
+    make(&builder)
+        .map_err(|e| mlua::Error::RuntimeError(format!("failed to create temporary {what}: {e}")))
+}
+
+pub fn tempfile(opts: Option<&Table>) -> mlua::Result<TempHandle> {
+    let file = create(opts, "file", |builder| builder.tempfile())?;
+    Ok(TempHandle(TempEntry::File(file.into_temp_path())))
+}
+
+pub fn tempdir(opts: Option<&Table>) -> mlua::Result<TempHandle> {
+    let dir = create(opts, "directory", |builder| builder.tempdir())?;
+    Ok(TempHandle(TempEntry::Dir(dir)))
+}
+
+impl UserData for TempHandle {
+    fn add_fields<'lua, F: UserDataFields<Self>>(fields: &mut F) {
+        fields.add_field_method_get("path", |_, this| {
+            Ok(this.path().to_string_lossy().into_owned())
+        });
+    }
+
+    fn add_methods<'lua, M: UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("write", |_, this, contents: mlua::String| {
+            let path = this.path().to_string_lossy();
+            fs::write(this.path(), contents.as_bytes()).map_err(|e| io_error("write", &path, e))
+        });
+
+        methods.add_method("read", |lua: &Lua, this, _: ()| {
+            let path = this.path().to_string_lossy();
+            let contents = fs::read(this.path()).map_err(|e| io_error("read", &path, e))?;
+            lua.create_string(&contents)
+        });
+
+        methods.add_method_mut("keep", |_, this, _: ()| {
+            match &mut this.0 {
+                TempEntry::File(path) => path.disable_cleanup(true),
+                TempEntry::Dir(dir) => dir.disable_cleanup(true),
+            }
+            Ok(())
+        });
+    }
+}
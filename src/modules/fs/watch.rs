@@ -0,0 +1,145 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        Once,
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
+    time::{Duration, Instant},
+};
+
+use mlua::{Function, Lua, Table, Value};
+use notify::{EventKind, RecursiveMode, Watcher, event::ModifyKind};
+
+use crate::modules::opt;
+
+static WATCHING: AtomicBool = AtomicBool::new(false);
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static HANDLER: Once = Once::new();
+
+/// Ctrl-C stops a running watch with an error instead of killing the process,
+/// so the script unwinds normally. Outside of a watch it exits as usual.
+fn install_interrupt_handler() {
+    HANDLER.call_once(|| {
+        let _ = ctrlc::set_handler(|| {
+            if WATCHING.load(Ordering::SeqCst) {
+                INTERRUPTED.store(true, Ordering::SeqCst);
+            } else {
+                std::process::exit(130);
+            }
+        });
+    });
+}
+
+/// Resets the watching flag however the watch loop ends.
+struct WatchGuard;
+
+impl Drop for WatchGuard {
+    fn drop(&mut self) {
+        WATCHING.store(false, Ordering::SeqCst);
+    }
+}
+
+fn event_kind(kind: &EventKind) -> Option<&'static str> {
+    match kind {
+        EventKind::Create(_) => Some("create"),
+        EventKind::Modify(ModifyKind::Name(_)) => Some("rename"),
+        EventKind::Modify(_) => Some("modify"),
+        EventKind::Remove(_) => Some("remove"),
+        _ => None,
+    }
+}
+
+fn watch_error(e: notify::Error) -> mlua::Error {
+    mlua::Error::RuntimeError(format!("failed to watch: {e}"))
+}
+
+/// Calls the callback with an event, returning whether watching should go on.
+fn dispatch(lua: &Lua, callback: &Function, path: &Path, kind: &str) -> mlua::Result<bool> {
+    let event = lua.create_table()?;
+    event.set("path", path.to_string_lossy())?;
+    event.set("kind", kind)?;
+    let keep_going: Value = callback.call(event)?;
+    Ok(!matches!(keep_going, Value::Boolean(false)))
+}
+
+pub fn watch(
+    lua: &Lua,
+    paths: Value,
+    callback: Function,
+    opts: Option<&Table>,
+) -> mlua::Result<()> {
+    let paths: Vec<String> = match paths {
+        Value::Table(paths) => paths.sequence_values().collect::<mlua::Result<_>>()?,
+        path => vec![lua.unpack(path)?],
+    };
+    let recursive = opt(opts, "recursive")?.unwrap_or(false);
+    let debounce = Duration::from_millis(opt(opts, "debounce_ms")?.unwrap_or(0));
+    let deadline =
+        opt::<u64>(opts, "timeout_ms")?.map(|ms| Instant::now() + Duration::from_millis(ms));
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx).map_err(watch_error)?;
+    let mode = if recursive {
+        RecursiveMode::Recursive
+    } else {
+        RecursiveMode::NonRecursive
+    };
+    for path in &paths {
+        watcher
+            .watch(path.as_ref(), mode)
+            .map_err(|e| mlua::Error::RuntimeError(format!("failed to watch '{path}': {e}")))?;
+    }
+
+    install_interrupt_handler();
+    INTERRUPTED.store(false, Ordering::SeqCst);
+    WATCHING.store(true, Ordering::SeqCst);
+    let _guard = WatchGuard;
+
+    // with debouncing, events are held back until none came in for a while,
+    // keeping only the latest kind for each path
+    let mut pending: Vec<(PathBuf, &'static str)> = Vec::new();
+    let mut last_event = Instant::now();
+
+    loop {
+        if INTERRUPTED.swap(false, Ordering::SeqCst) {
+            return Err(mlua::Error::RuntimeError("fs.watch interrupted".into()));
+        }
+
+        match rx.recv_timeout(Duration::from_millis(50)) {
+            Ok(Ok(event)) => {
+                let Some(kind) = event_kind(&event.kind) else {
+                    continue;
+                };
+                for path in event.paths {
+                    if debounce.is_zero() {
+                        if !dispatch(lua, &callback, &path, kind)? {
+                            return Ok(());
+                        }
+                        continue;
+                    }
+                    match pending.iter_mut().find(|(pending, _)| *pending == path) {
+                        Some(entry) => entry.1 = kind,
+                        None => pending.push((path, kind)),
+                    }
+                    last_event = Instant::now();
+                }
+            }
+            Ok(Err(e)) => return Err(watch_error(e)),
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+
+        if !pending.is_empty() && last_event.elapsed() >= debounce {
+            for (path, kind) in pending.drain(..) {
+                if !dispatch(lua, &callback, &path, kind)? {
+                    return Ok(());
+                }
+            }
+        }
+
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,1933 @@
+use std::{
+    error::Error,
+    fs,
+    io::{self, Read, Write},
+    path::Path,
+    sync::{
+        Arc, Mutex, MutexGuard,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use mlua::{
+    AppDataRef, AppDataRefMut, Function, IntoLua, Lua, Table, UserData, UserDataMethods, Value,
+};
+use reqwest::{
+    Method, StatusCode, Url,
+    blocking::{
+        Client, Request, RequestBuilder, Response,
+        multipart::{Form, Part},
+    },
+    header::{
+        ACCEPT, AUTHORIZATION, CONTENT_TYPE, COOKIE, HeaderMap, HeaderName, HeaderValue, LOCATION,
+        PROXY_AUTHORIZATION,
+    },
+    redirect::Policy,
+};
+
+use encoding_rs::Encoding;
+use tempfile::NamedTempFile;
+use url::form_urlencoded;
+
+use super::{
+    fs::{io_error, new_file_permissions},
+    opt,
+};
+
+mod cookies;
+mod json;
+mod proxy;
+mod retry;
+mod tls;
+
+use cookies::CookieJar;
+use proxy::{ProxyConfig, ProxyMode};
+use retry::RetryPolicy;
+use tls::TlsConfig;
+
+pub struct HttpModule;
+
+/// The request timeout clients get until `http:set_defaults` changes it.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many redirects are followed by default, the same as reqwest's limit.
+const DEFAULT_MAX_REDIRECTS: usize = 10;
+
+/// How many requests `http:batch` sends at once by default.
+const DEFAULT_CONCURRENCY: usize = 8;
+
+/// The options the shared client is built with, which `http:set_defaults`
+/// changes and the options of a request can override.
+#[derive(Clone, PartialEq)]
+struct ClientConfig {
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    max_redirects: usize,
+    cookies: bool,
+    proxy: ProxyMode,
+    user_agent: Option<HeaderValue>,
+    tls: TlsConfig,
+}
+
+impl Default for ClientConfig {
+    fn default() -> ClientConfig {
+        ClientConfig {
+            timeout: Some(DEFAULT_TIMEOUT),
+            connect_timeout: None,
+            max_redirects: DEFAULT_MAX_REDIRECTS,
+            cookies: false,
+            proxy: ProxyMode::System,
+            user_agent: None,
+            tls: TlsConfig::default(),
+        }
+    }
+}
+
+impl ClientConfig {
+    /// Applies the options that requests and `http:set_defaults` share.
+    fn apply(&mut self, opts: Option<&Table>) -> mlua::Result<()> {
+        if let Some(timeout) = timeout_ms(opts, "connect_timeout_ms")? {
+            self.connect_timeout = timeout;
+        }
+        match opt::<Value>(opts, "redirect")? {
+            None | Some(Value::Nil) => {}
+            Some(Value::Boolean(follow)) => {
+                self.max_redirects = if follow { DEFAULT_MAX_REDIRECTS } else { 0 };
+            }
+            Some(Value::Integer(max)) if max >= 0 => self.max_redirects = max as usize,
+            Some(other) => {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "invalid redirect option: expected a boolean or a number of redirects, got {}",
+                    other.to_string()?
+                )));
+            }
+        }
+        if let Some(tls) = opt::<Table>(opts, "tls")? {
+            self.tls.apply(&tls)?;
+        }
+        Ok(())
+    }
+
+    fn build(&self, jar: &Arc<CookieJar>) -> mlua::Result<Client> {
+        let mut builder = Client::builder().timeout(self.timeout);
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if self.cookies {
+            builder = builder.cookie_provider(Arc::clone(jar));
+        }
+        if let Some(user_agent) = &self.user_agent {
+            builder = builder.user_agent(user_agent.clone());
+        }
+        builder = self.tls.configure(builder);
+        match &self.proxy {
+            ProxyMode::System => {}
+            ProxyMode::Direct => builder = builder.no_proxy(),
+            ProxyMode::Custom(proxies) => builder = builder.proxy(proxies.to_proxy()),
+        }
+
+        let max = self.max_redirects;
+        builder = builder.redirect(if max == 0 {
+            Policy::none()
+        } else {
+            Policy::custom(move |attempt| {
+                if attempt.previous().len() > max {
+                    // the first URL is the one originally requested
+                    let followed: Vec<String> = attempt.previous()[1..]
+                        .iter()
+                        .map(|url| format!("'{url}'"))
+                        .collect();
+                    let message = format!(
+                        "stopped after following {max} redirects, to {}, instead of another one to '{}'",
+                        followed.join(", "),
+                        attempt.url()
+                    );
+                    attempt.error(message)
+                } else {
+                    attempt.follow()
+                }
+            })
+        });
+        builder
+            .build()
+            .map_err(|e| mlua::Error::RuntimeError(format!("failed to build the HTTP client: {e}")))
+    }
+}
+
+/// Credentials for the `Authorization` header, which reqwest marks as
+/// sensitive, and which nothing here should ever print.
+#[derive(Clone)]
+enum Auth {
+    Basic { user: String, pass: Option<String> },
+    Bearer(String),
+}
+
+impl Auth {
+    /// Reads an `auth` option, where `false` stands for no credentials.
+    fn from_opts(opts: Option<&Table>) -> mlua::Result<Option<Option<Auth>>> {
+        let auth = match opt::<Value>(opts, "auth")? {
+            None | Some(Value::Nil) => return Ok(None),
+            Some(Value::Boolean(false)) => return Ok(Some(None)),
+            Some(Value::Table(auth)) => auth,
+            Some(other) => {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "invalid auth option: expected a table or false, got {}",
+                    other.type_name()
+                )));
+            }
+        };
+
+        let kind: Option<String> = auth.get("type")?;
+        let auth = match kind.as_deref() {
+            Some("basic") => Auth::Basic {
+                user: auth
+                    .get::<Option<String>>("user")?
+                    .ok_or_else(|| mlua::Error::RuntimeError("basic auth needs a user".into()))?,
+                pass: auth.get("pass")?,
+            },
+            Some("bearer") => Auth::Bearer(
+                auth.get::<Option<String>>("token")?
+                    .ok_or_else(|| mlua::Error::RuntimeError("bearer auth needs a token".into()))?,
+            ),
+            Some(other) => {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "unknown auth type '{other}': expected \"basic\" or \"bearer\""
+                )));
+            }
+            None => {
+                return Err(mlua::Error::RuntimeError(
+                    "auth needs a type, either \"basic\" or \"bearer\"".into(),
+                ));
+            }
+        };
+        Ok(Some(Some(auth)))
+    }
+
+    fn apply(&self, request: RequestBuilder) -> RequestBuilder {
+        match self {
+            Auth::Basic { user, pass } => request.basic_auth(user, pass.as_ref()),
+            Auth::Bearer(token) => request.bearer_auth(token),
+        }
+    }
+
+    /// Describes the credentials for `http:get_defaults`, leaving out the
+    /// password or token.
+    fn describe(&self, lua: &Lua) -> mlua::Result<Table> {
+        let table = lua.create_table()?;
+        match self {
+            Auth::Basic { user, .. } => {
+                table.set("type", "basic")?;
+                table.set("user", user.as_str())?;
+            }
+            Auth::Bearer(_) => table.set("type", "bearer")?,
+        }
+        Ok(table)
+    }
+}
+
+/// The shared client and the configuration it was built with, kept in the
+/// Lua state's app data, along with the defaults applied to each request.
+pub struct HttpState {
+    config: ClientConfig,
+    client: Client,
+    jar: Arc<CookieJar>,
+    auth: Option<Auth>,
+    headers: HeaderMap,
+}
+
+impl HttpState {
+    pub fn new() -> mlua::Result<HttpState> {
+        let config = ClientConfig::default();
+        let jar = Arc::default();
+        let client = config.build(&jar)?;
+        Ok(HttpState {
+            config,
+            client,
+            jar,
+            auth: None,
+            headers: HeaderMap::new(),
+        })
+    }
+
+    /// Switches to a client built with `config`, unless it's the current
+    /// one, so that the client's connections are only dropped when needed.
+    fn reconfigure(&mut self, config: ClientConfig) -> mlua::Result<()> {
+        if config != self.config {
+            self.client = config.build(&self.jar)?;
+            self.config = config;
+        }
+        Ok(())
+    }
+}
+
+fn state(lua: &Lua) -> mlua::Result<AppDataRef<'_, HttpState>> {
+    lua.app_data_ref::<HttpState>()
+        .ok_or_else(|| mlua::Error::RuntimeError("HTTP client not available".into()))
+}
+
+fn state_mut(lua: &Lua) -> mlua::Result<AppDataRefMut<'_, HttpState>> {
+    lua.app_data_mut::<HttpState>()
+        .ok_or_else(|| mlua::Error::RuntimeError("HTTP client not available".into()))
+}
+
+/// Reads a timeout in milliseconds, where 0 means no timeout.
+fn timeout_ms(opts: Option<&Table>, key: &str) -> mlua::Result<Option<Option<Duration>>> {
+    Ok(opt::<u64>(opts, key)?.map(|ms| (ms > 0).then(|| Duration::from_millis(ms))))
+}
+
+/// Turns a failed request into an error whose message starts with what
+/// kind of failure it was, so that scripts can tell a timeout from a
+/// refused connection, followed by the underlying causes.
+fn request_error(e: reqwest::Error) -> mlua::Error {
+    mlua::Error::RuntimeError(format!("{}: {}", error_kind(&e), with_causes(&e)))
+}
+
+fn error_kind(e: &reqwest::Error) -> &'static str {
+    if e.is_timeout() {
+        "timeout"
+    } else if e.is_connect() {
+        "connect"
+    } else if e.is_redirect() {
+        "redirect"
+    } else if e.is_body() || e.is_decode() {
+        "body"
+    } else {
+        "request"
+    }
+}
+
+/// Formats an error followed by the errors that caused it.
+fn with_causes(e: &reqwest::Error) -> String {
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(cause) = source {
+        message.push_str(&format!(": {cause}"));
+        source = cause.source();
+    }
+    message
+}
+
+/// Turns a failed request into an error like `request_error`, except that
+/// failing to connect to a proxy set with `http:set_proxy` says so, rather
+/// than looking like the server is down.
+fn send_error(config: &ClientConfig, e: reqwest::Error) -> mlua::Error {
+    if let (ProxyMode::Custom(proxies), Some(url)) = (&config.proxy, e.url())
+        && e.is_connect()
+        && let Some(proxy) = proxies.for_url(url)
+    {
+        return mlua::Error::RuntimeError(format!(
+            "proxy: failed to connect through the proxy '{}': {}",
+            proxy::display(proxy),
+            with_causes(&e)
+        ));
+    }
+    request_error(e)
+}
+
+/// Returns the client to send a request with, which is the shared one
+/// unless the request's options change how the client has to be built.
+fn client_for(lua: &Lua, opts: Option<&Table>) -> mlua::Result<(Client, ClientConfig)> {
+    let state = state(lua)?;
+    let mut config = state.config.clone();
+    // a request can only set a timeout of its own, so turning the timeout
+    // off needs a client without one
+    if let Some(None) = timeout_ms(opts, "timeout_ms")? {
+        config.timeout = None;
+    }
+    config.apply(opts)?;
+    match opt::<Value>(opts, "proxy")? {
+        None | Some(Value::Nil) | Some(Value::Boolean(true)) => {}
+        Some(Value::Boolean(false)) => config.proxy = ProxyMode::Direct,
+        Some(other) => {
+            return Err(mlua::Error::RuntimeError(format!(
+                "invalid proxy option: expected false, got {}",
+                other.type_name()
+            )));
+        }
+    }
+
+    if config == state.config {
+        Ok((state.client.clone(), config))
+    } else {
+        Ok((config.build(&state.jar)?, config))
+    }
+}
+
+/// Turns a header value from Lua into a `HeaderValue`, accepting numbers as
+/// well as strings so that a `Content-Length` doesn't need a `tostring`.
+fn header_value(name: &HeaderName, value: Value) -> mlua::Result<HeaderValue> {
+    let bytes = match value {
+        Value::String(s) => s.as_bytes().to_vec(),
+        Value::Integer(n) => n.to_string().into_bytes(),
+        Value::Number(n) => n.to_string().into_bytes(),
+        other => {
+            return Err(mlua::Error::RuntimeError(format!(
+                "invalid value for header '{name}': expected a string or a number, got {}",
+                other.type_name()
+            )));
+        }
+    };
+    HeaderValue::from_bytes(&bytes).map_err(|_| {
+        mlua::Error::RuntimeError(format!(
+            "invalid value for header '{name}': it contains a newline or another control character"
+        ))
+    })
+}
+
+/// Builds the headers of a `{name = value}` table, where an array of values
+/// sends the header once for each of them.
+fn header_map(headers: Table) -> mlua::Result<HeaderMap> {
+    let mut map = HeaderMap::new();
+    for pair in headers.pairs::<mlua::String, Value>() {
+        let (name, value) = pair?;
+        let name = HeaderName::from_bytes(&name.as_bytes()).map_err(|_| {
+            mlua::Error::RuntimeError(format!("invalid header name '{}'", name.to_string_lossy()))
+        })?;
+        match value {
+            Value::Table(values) => {
+                for value in values.sequence_values::<Value>() {
+                    map.append(&name, credential_value(&name, header_value(&name, value?)?));
+                }
+            }
+            value => {
+                map.append(&name, credential_value(&name, header_value(&name, value)?));
+            }
+        }
+    }
+    Ok(map)
+}
+
+/// Marks the values of headers that carry credentials as sensitive, the way
+/// reqwest does for its own auth helpers.
+fn credential_value(name: &HeaderName, mut value: HeaderValue) -> HeaderValue {
+    if name == AUTHORIZATION || name == PROXY_AUTHORIZATION || name == COOKIE {
+        value.set_sensitive(true);
+    }
+    value
+}
+
+/// Builds a table of the response headers that can be indexed with a name in
+/// any case, joining repeated headers with `", "`.
+fn headers_table(lua: &Lua, headers: &HeaderMap) -> mlua::Result<Table> {
+    let table = lua.create_table()?;
+    for name in headers.keys() {
+        let values: Vec<&[u8]> = headers.get_all(name).iter().map(|v| v.as_bytes()).collect();
+        table.set(name.as_str(), lua.create_string(values.join(&b", "[..]))?)?;
+    }
+    case_insensitive(lua, table)
+}
+
+/// Builds a table like `headers_table`, but with an array of the values of
+/// each header, for headers such as `Set-Cookie` that can't be joined
+/// without losing where one value ends, since their dates contain commas.
+fn headers_all_table(lua: &Lua, headers: &HeaderMap) -> mlua::Result<Table> {
+    let table = lua.create_table()?;
+    for name in headers.keys() {
+        let values = headers
+            .get_all(name)
+            .iter()
+            .map(|value| lua.create_string(value.as_bytes()));
+        table.set(
+            name.as_str(),
+            lua.create_sequence_from(values.collect::<mlua::Result<Vec<_>>>()?)?,
+        )?;
+    }
+    case_insensitive(lua, table)
+}
+
+/// Lets a table whose keys are lowercase header names be indexed with a
+/// name in any case.
+fn case_insensitive(lua: &Lua, table: Table) -> mlua::Result<Table> {
+    let meta = lua.create_table()?;
+    meta.set(
+        "__index",
+        lua.create_function(|_, (table, key): (Table, mlua::String)| {
+            table.raw_get::<Value>(key.to_str()?.to_ascii_lowercase())
+        })?,
+    )?;
+    table.set_metatable(Some(meta));
+    Ok(table)
+}
+
+/// Turns a scalar from a query or form table into its text.
+fn param_value(key: &str, value: Value) -> mlua::Result<String> {
+    match value {
+        Value::String(s) => Ok(s.to_str()?.to_string()),
+        Value::Integer(n) => Ok(n.to_string()),
+        Value::Number(n) => Ok(n.to_string()),
+        Value::Boolean(b) => Ok(b.to_string()),
+        other => Err(mlua::Error::RuntimeError(format!(
+            "invalid value for parameter '{key}': expected a string, number or boolean, got {}",
+            other.type_name()
+        ))),
+    }
+}
+
+/// Flattens a `{key = value}` table into pairs sorted by key, so the same
+/// table always gives the same string, where an array of values repeats its
+/// key once for each of them in order.
+fn param_pairs(params: &Table) -> mlua::Result<Vec<(String, String)>> {
+    let mut pairs = Vec::new();
+    for pair in params.pairs::<Value, Value>() {
+        let (key, value) = pair?;
+        let key = match key {
+            Value::String(s) => s.to_str()?.to_string(),
+            Value::Integer(n) => n.to_string(),
+            other => {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "invalid parameter name: expected a string, got {}",
+                    other.type_name()
+                )));
+            }
+        };
+        match value {
+            Value::Table(values) => {
+                for value in values.sequence_values::<Value>() {
+                    pairs.push((key.clone(), param_value(&key, value?)?));
+                }
+            }
+            value => pairs.push((key.clone(), param_value(&key, value)?)),
+        }
+    }
+    // the sort is stable, so repeated keys keep the order of their array
+    pairs.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(pairs)
+}
+
+pub(super) fn encode_query(params: &Table) -> mlua::Result<String> {
+    Ok(form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(param_pairs(params)?)
+        .finish())
+}
+
+/// Decodes a query string into a table, where a key that appears more than
+/// once gets an array of its values.
+pub(super) fn decode_query(lua: &Lua, query: &str) -> mlua::Result<Table> {
+    let query = query.strip_prefix('?').unwrap_or(query);
+    let table = lua.create_table()?;
+    for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+        match table.raw_get::<Value>(key.as_ref())? {
+            Value::Nil => table.raw_set(key.as_ref(), value.as_ref())?,
+            Value::Table(values) => values.raw_push(value.as_ref())?,
+            first => table.raw_set(
+                key.as_ref(),
+                lua.create_sequence_from([
+                    first,
+                    Value::String(lua.create_string(value.as_ref())?),
+                ])?,
+            )?,
+        }
+    }
+    Ok(table)
+}
+
+/// A request with everything its options say applied, which can be sent
+/// without the Lua state, such as from the threads of `http:batch`.
+struct Prepared {
+    client: Client,
+    config: ClientConfig,
+    request: Request,
+    retry: Option<RetryPolicy>,
+    error_on_status: bool,
+}
+
+/// Builds a request with the shared client and applies the options common
+/// to every method.
+fn prepare(
+    lua: &Lua,
+    build: impl FnOnce(&Client) -> RequestBuilder,
+    opts: Option<&Table>,
+) -> mlua::Result<Prepared> {
+    let (client, config) = client_for(lua, opts)?;
+    let mut request = build(&client);
+    let (default_auth, default_headers) = {
+        let state = state(lua)?;
+        (state.auth.clone(), state.headers.clone())
+    };
+    let auth = match Auth::from_opts(opts)? {
+        Some(auth) => auth,
+        None => default_auth,
+    };
+    if let Some(auth) = auth {
+        request = auth.apply(request);
+    }
+    // these replace any headers of the same name set before, such as the
+    // `Content-Type` of a JSON body
+    if let Some(headers) = opt::<Table>(opts, "headers")? {
+        request = request.headers(header_map(headers)?);
+    }
+    if let Some(query) = opt::<Table>(opts, "query")? {
+        request = request.query(&param_pairs(&query)?);
+    }
+    if let Some(Some(timeout)) = timeout_ms(opts, "timeout_ms")? {
+        request = request.timeout(timeout);
+    }
+
+    let (client, request) = request.build_split();
+    let mut request = request.map_err(request_error)?;
+    // the default headers only fill in the ones the request doesn't have by
+    // now, so they don't replace a header that its method or auth set
+    let headers = request.headers_mut();
+    for name in default_headers.keys() {
+        if !headers.contains_key(name) {
+            for value in default_headers.get_all(name) {
+                headers.append(name.clone(), value.clone());
+            }
+        }
+    }
+    // retrying a POST could for example create something twice
+    let retry = RetryPolicy::from_opts(opts)?
+        .filter(|retry| retry.non_idempotent || request.method().is_idempotent());
+
+    Ok(Prepared {
+        client,
+        config,
+        request,
+        retry,
+        error_on_status: opt(opts, "error_on_status")?.unwrap_or(false),
+    })
+}
+
+impl Prepared {
+    /// Sends the request, retrying as `opts.retry` says, and returns the
+    /// response and how many attempts it took.
+    fn execute(self) -> mlua::Result<(Response, u32)> {
+        let Prepared {
+            client,
+            config,
+            mut request,
+            retry,
+            error_on_status,
+        } = self;
+
+        let mut attempts = 1;
+        loop {
+            // a body streamed from a file can't be sent again, so such
+            // requests only get one attempt
+            let next = match &retry {
+                Some(retry) if attempts <= retry.max => request.try_clone(),
+                _ => None,
+            };
+            let result = client.execute(request);
+
+            let delay = match (&retry, &next, &result) {
+                (Some(retry), Some(_), Ok(response)) if retry.retries_status(response.status()) => {
+                    Some(
+                        retry
+                            .retry_after(response)
+                            .unwrap_or_else(|| retry.delay(attempts)),
+                    )
+                }
+                (Some(retry), Some(_), Err(e)) if retry.retries_kind(error_kind(e)) => {
+                    Some(retry.delay(attempts))
+                }
+                _ => None,
+            };
+            match (delay, next) {
+                (Some(delay), Some(next)) => {
+                    thread::sleep(delay);
+                    request = next;
+                    attempts += 1;
+                }
+                _ => {
+                    return match result {
+                        Ok(response) if error_on_status && !response.status().is_success() => {
+                            Err(status_error(&response))
+                        }
+                        Ok(response) => Ok((response, attempts)),
+                        Err(e) => Err(match send_error(&config, e) {
+                            mlua::Error::RuntimeError(message) if attempts > 1 => {
+                                mlua::Error::RuntimeError(format!(
+                                    "{message} (after {attempts} attempts)"
+                                ))
+                            }
+                            e => e,
+                        }),
+                    };
+                }
+            }
+        }
+    }
+
+    /// Sends the request and reads the whole response.
+    fn receive(self) -> mlua::Result<Received> {
+        let (response, attempts) = self.execute()?;
+        let mut received = Received::new(&response, attempts);
+        received.body = Some(response.bytes().map_err(request_error)?.to_vec());
+        Ok(received)
+    }
+
+    /// Sends the request and passes its body to `on_chunk` as it arrives
+    /// instead of keeping it, until the callback returns `false`. Returns the
+    /// response without a body and whether the callback stopped it.
+    fn stream(self, lua: &Lua, on_chunk: Function) -> mlua::Result<(Received, bool)> {
+        let (mut response, attempts) = self.execute()?;
+        let received = Received::new(&response, attempts);
+
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = read_chunk(&mut response, &mut buf)?;
+            if n == 0 {
+                return Ok((received, false));
+            }
+            let chunk = lua.create_string(&buf[..n])?;
+            if let Value::Boolean(false) = on_chunk.call::<Value>(chunk)? {
+                return Ok((received, true));
+            }
+        }
+    }
+}
+
+/// A response, with its body unless it was streamed.
+struct Received {
+    status: StatusCode,
+    url: Url,
+    headers: HeaderMap,
+    body: Option<Vec<u8>>,
+    attempts: u32,
+}
+
+impl Received {
+    fn new(response: &Response, attempts: u32) -> Received {
+        Received {
+            status: response.status(),
+            url: response.url().clone(),
+            headers: response.headers().clone(),
+            body: None,
+            attempts,
+        }
+    }
+
+    fn into_table(self, lua: &Lua) -> mlua::Result<Table> {
+        // where a redirect that wasn't followed points to, as an absolute URL
+        let location = self
+            .status
+            .is_redirection()
+            .then(|| self.headers.get(LOCATION)?.to_str().ok())
+            .flatten()
+            .and_then(|location| self.url.join(location).ok())
+            .map(String::from);
+
+        let table = lua.create_table()?;
+        table.set("status", self.status.as_u16())?;
+        table.set("ok", self.status.is_success())?;
+        table.set("headers", headers_table(lua, &self.headers)?)?;
+        table.set("headers_all", headers_all_table(lua, &self.headers)?)?;
+        table.set(
+            "body",
+            self.body.map(|body| lua.create_string(body)).transpose()?,
+        )?;
+        table.set("text", lua.create_function(response_text)?)?;
+        table.set("url", self.url.as_str())?;
+        table.set("location", location)?;
+        table.set("attempts", self.attempts)?;
+        Ok(table)
+    }
+}
+
+/// Returns the `charset` parameter of a `Content-Type` header.
+fn charset(content_type: &str) -> Option<&str> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("charset")
+            .then(|| value.trim().trim_matches('"'))
+    })
+}
+
+/// `response:text([encoding])`, which decodes the body with the given
+/// encoding or else the charset of its `Content-Type`, or UTF-8 without one.
+fn response_text(
+    lua: &Lua,
+    (response, encoding): (Table, Option<String>),
+) -> mlua::Result<Option<mlua::String>> {
+    let Some(body) = response.get::<Option<mlua::String>>("body")? else {
+        return Ok(None);
+    };
+    let label = match encoding {
+        Some(encoding) => encoding,
+        None => {
+            let headers: Table = response.get("headers")?;
+            let content_type: Option<String> = headers.get("content-type")?;
+            content_type
+                .as_deref()
+                .and_then(charset)
+                .unwrap_or("utf-8")
+                .to_string()
+        }
+    };
+    let encoding = Encoding::for_label(label.as_bytes())
+        .ok_or_else(|| mlua::Error::RuntimeError(format!("unknown encoding '{label}'")))?;
+    // a byte order mark overrides the encoding, as it does in browsers,
+    // and invalid sequences become U+FFFD
+    let body = body.as_bytes();
+    let (text, _, _) = encoding.decode(&body);
+    lua.create_string(&*text).map(Some)
+}
+
+fn status_error(response: &Response) -> mlua::Error {
+    mlua::Error::RuntimeError(format!(
+        "HTTP {} from '{}'",
+        response.status(),
+        response.url()
+    ))
+}
+
+/// Turns an error from reading a response body back into the request error
+/// it wraps, so that it gets the same kind of message.
+fn read_error(e: io::Error) -> mlua::Error {
+    match e
+        .into_inner()
+        .map(|inner| inner.downcast::<reqwest::Error>())
+    {
+        Some(Ok(e)) => request_error(*e),
+        Some(Err(inner)) => mlua::Error::RuntimeError(format!("body: {inner}")),
+        None => mlua::Error::RuntimeError("body: failed to read the response".into()),
+    }
+}
+
+/// Reads the next part of a response body into `buf`, returning how much
+/// was read, which is 0 at the end of the body.
+fn read_chunk(response: &mut Response, buf: &mut [u8]) -> mlua::Result<usize> {
+    loop {
+        match response.read(buf) {
+            Ok(n) => return Ok(n),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(read_error(e)),
+        }
+    }
+}
+
+/// Sends a request and returns the response as a table, or only its body
+/// with `opts.simple`.
+fn send(
+    lua: &Lua,
+    build: impl FnOnce(&Client) -> RequestBuilder,
+    opts: Option<Table>,
+) -> mlua::Result<Value> {
+    let prepared = prepare(lua, build, opts.as_ref())?;
+    let (response, aborted) = match opt::<Function>(opts.as_ref(), "on_chunk")? {
+        Some(on_chunk) => prepared.stream(lua, on_chunk)?,
+        None => (prepared.receive()?, false),
+    };
+    if opt::<bool>(opts.as_ref(), "simple")?.unwrap_or(false) {
+        return response.body.into_lua(lua);
+    }
+    let table = response.into_table(lua)?;
+    if aborted {
+        table.set("aborted", true)?;
+    }
+    Ok(Value::Table(table))
+}
+
+fn parse_method(method: &str) -> mlua::Result<Method> {
+    Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+        .map_err(|_| mlua::Error::RuntimeError(format!("invalid HTTP method '{method}'")))
+}
+
+fn error_message(e: mlua::Error) -> String {
+    match e {
+        mlua::Error::RuntimeError(message) => message,
+        e => e.to_string(),
+    }
+}
+
+/// Locks a mutex of `http:batch`, whose contents stay usable even if a
+/// thread panicked while holding it.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Prepares a request of `http:batch`, whose table has its `method`, `url`
+/// and `body` along with the options of a single request.
+fn batch_entry(lua: &Lua, request: &Table) -> mlua::Result<Prepared> {
+    let method = match request.get::<Option<String>>("method")? {
+        Some(method) => parse_method(&method)?,
+        None => Method::GET,
+    };
+    let url: String = request
+        .get::<Option<String>>("url")?
+        .ok_or_else(|| mlua::Error::RuntimeError("the request has no url".into()))?;
+    let body: Option<mlua::String> = request.get("body")?;
+    prepare(
+        lua,
+        |client| {
+            let request = client.request(method, &url);
+            match body {
+                Some(body) => request.body(body.as_bytes().to_vec()),
+                None => request,
+            }
+        },
+        Some(request),
+    )
+}
+
+/// Sends the requests from up to `opts.concurrency` threads at once, and
+/// returns a table for each of them in the same order, with its response or
+/// why it failed.
+fn batch(lua: &Lua, requests: Table, opts: Option<&Table>) -> mlua::Result<Table> {
+    let concurrency = opt::<usize>(opts, "concurrency")?.unwrap_or(DEFAULT_CONCURRENCY);
+    if concurrency == 0 {
+        return Err(mlua::Error::RuntimeError(
+            "invalid concurrency: at least one request has to be sent at a time".into(),
+        ));
+    }
+    let stop_on_error = opt::<bool>(opts, "stop_on_error")?.unwrap_or(false);
+
+    // everything that needs the Lua state happens here, so that the threads
+    // below only send the requests, whose results are left empty when they
+    // are skipped. Errors are kept as their messages, as `mlua::Error` can't
+    // be sent between threads.
+    let mut pending = Vec::new();
+    let mut results = Vec::new();
+    for request in requests.sequence_values::<Table>() {
+        let (prepared, result) = match batch_entry(lua, &request?) {
+            Ok(prepared) => (Some(prepared), None),
+            Err(e) => (None, Some(Err(error_message(e)))),
+        };
+        pending.push(Mutex::new(prepared));
+        results.push(Mutex::new(result));
+    }
+    let failed = AtomicBool::new(false);
+
+    let next = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        for _ in 0..concurrency.min(pending.len()) {
+            scope.spawn(|| {
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(slot) = pending.get(i) else {
+                        break;
+                    };
+                    // an invalid request fails when its turn comes, so that
+                    // the ones before it are still sent
+                    let Some(prepared) = lock(slot).take() else {
+                        if stop_on_error {
+                            failed.store(true, Ordering::Relaxed);
+                        }
+                        continue;
+                    };
+                    if failed.load(Ordering::Relaxed) {
+                        continue;
+                    }
+                    let result = prepared.receive().map_err(error_message);
+                    if stop_on_error && result.is_err() {
+                        failed.store(true, Ordering::Relaxed);
+                    }
+                    *lock(&results[i]) = Some(result);
+                }
+            });
+        }
+    });
+
+    let table = lua.create_table_with_capacity(results.len(), 0)?;
+    for result in results {
+        let result = match result.into_inner().unwrap_or_else(|e| e.into_inner()) {
+            Some(Ok(received)) => received.into_table(lua)?,
+            Some(Err(message)) => {
+                let result = lua.create_table()?;
+                result.set("ok", false)?;
+                result.set("error", message)?;
+                result
+            }
+            None => {
+                let result = lua.create_table()?;
+                result.set("ok", false)?;
+                result.set("error", "skipped after an earlier request failed")?;
+                result.set("skipped", true)?;
+                result
+            }
+        };
+        table.raw_push(result)?;
+    }
+    Ok(table)
+}
+
+/// Sends a request and decodes its JSON response, returning the decoded
+/// value, which is `nil` for an empty body, and the response table.
+fn send_json(
+    lua: &Lua,
+    build: impl FnOnce(&Client) -> RequestBuilder,
+    opts: Option<Table>,
+) -> mlua::Result<(Value, Table)> {
+    let build = |client: &Client| build(client).header(ACCEPT, "application/json");
+    let response = prepare(lua, build, opts.as_ref())?
+        .receive()?
+        .into_table(lua)?;
+
+    let body: mlua::String = response.get("body")?;
+    let body = body.as_bytes();
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok((Value::Nil, response));
+    }
+    match serde_json::from_slice(&body) {
+        Ok(value) => Ok((json::to_lua(lua, value)?, response)),
+        Err(e) => {
+            // enough of the body to tell an HTML error page from broken JSON
+            let start = String::from_utf8_lossy(&body[..body.len().min(200)]);
+            Err(mlua::Error::RuntimeError(format!(
+                "failed to decode the JSON response from '{}' (HTTP {}): {e}, the body starts with: {start}",
+                response.get::<String>("url")?,
+                response.get::<u16>("status")?,
+            )))
+        }
+    }
+}
+
+/// Builds a multipart form from an array of parts, each either a text field
+/// with a `value` or a file streamed from its `path`.
+fn multipart_form(parts: Table) -> mlua::Result<Form> {
+    let mut form = Form::new();
+    for (i, part) in parts.sequence_values::<Table>().enumerate() {
+        let part = part?;
+        let part_error = |name: Option<&str>, message: String| {
+            let name = name.map(|name| format!(" ('{name}')")).unwrap_or_default();
+            mlua::Error::RuntimeError(format!("multipart part {}{name}: {message}", i + 1))
+        };
+
+        let name: String = part
+            .get::<Option<String>>("name")?
+            .ok_or_else(|| part_error(None, "has no name".into()))?;
+        let path: Option<String> = part.get("path")?;
+        let value: Option<mlua::String> = part.get("value")?;
+
+        let mut field = match (path, value) {
+            (Some(path), None) => Part::file(&path)
+                .map_err(|e| part_error(Some(&name), format!("failed to open '{path}': {e}")))?,
+            (None, Some(value)) => Part::bytes(value.as_bytes().to_vec()),
+            (Some(_), Some(_)) => {
+                return Err(part_error(
+                    Some(&name),
+                    "has both a path and a value".into(),
+                ));
+            }
+            (None, None) => return Err(part_error(Some(&name), "needs a path or a value".into())),
+        };
+        if let Some(filename) = part.get::<Option<String>>("filename")? {
+            field = field.file_name(filename);
+        }
+        if let Some(mime) = part.get::<Option<String>>("mime")? {
+            field = field
+                .mime_str(&mime)
+                .map_err(|_| part_error(Some(&name), format!("invalid MIME type '{mime}'")))?;
+        }
+        form = form.part(name, field);
+    }
+    Ok(form)
+}
+
+/// Streams the body of a GET request into a temporary file next to `dest`
+/// and renames it to `dest` once it's complete, so a failed download never
+/// leaves part of a file behind. Returns the size and the status.
+fn download(lua: &Lua, url: &str, dest: &str, opts: Option<&Table>) -> mlua::Result<(u64, u16)> {
+    let overwrite = opt::<bool>(opts, "overwrite")?.unwrap_or(false);
+    let on_progress: Option<Function> = opt(opts, "on_progress")?;
+
+    let target = Path::new(dest);
+    if !overwrite && target.exists() {
+        return Err(io_error(
+            "download to",
+            dest,
+            io::Error::from(io::ErrorKind::AlreadyExists),
+        ));
+    }
+
+    let (mut response, _) = prepare(lua, |client| client.get(url), opts)?.execute()?;
+    if !response.status().is_success() {
+        return Err(status_error(&response));
+    }
+    let total = response.content_length();
+
+    let dir = match target.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    // the temporary file deletes itself if anything below fails
+    let mut file = NamedTempFile::new_in(dir).map_err(|e| io_error("write", dest, e))?;
+
+    let mut buf = vec![0; 64 * 1024];
+    let mut downloaded = 0;
+    loop {
+        let n = read_chunk(&mut response, &mut buf)?;
+        if n == 0 {
+            break;
+        }
+        file.write_all(&buf[..n])
+            .map_err(|e| io_error("write", dest, e))?;
+        downloaded += n as u64;
+        if let Some(on_progress) = &on_progress {
+            on_progress.call::<()>((downloaded, total))?;
+        }
+    }
+    file.as_file()
+        .sync_all()
+        .map_err(|e| io_error("write", dest, e))?;
+    if let Some(permissions) = new_file_permissions(None)? {
+        fs::set_permissions(file.path(), permissions).map_err(|e| io_error("write", dest, e))?;
+    }
+
+    let persisted = if overwrite {
+        file.persist(target)
+    } else {
+        file.persist_noclobber(target)
+    };
+    persisted.map_err(|e| io_error("download to", dest, e.error))?;
+
+    Ok((downloaded, response.status().as_u16()))
+}
+
+impl UserData for HttpModule {
+    fn add_methods<'lua, M: UserDataMethods<Self>>(methods: &mut M) {
+        for (name, method) in [
+            ("get", Method::GET),
+            ("head", Method::HEAD),
+            ("delete", Method::DELETE),
+        ] {
+            methods.add_method(name, move |lua, _, (url, opts): (String, Option<Table>)| {
+                send(lua, |client| client.request(method.clone(), &url), opts)
+            });
+        }
+
+        for (name, method) in [
+            ("post", Method::POST),
+            ("put", Method::PUT),
+            ("patch", Method::PATCH),
+        ] {
+            methods.add_method(
+                name,
+                move |lua, _, (url, body, opts): (String, mlua::String, Option<Table>)| {
+                    let body = body.as_bytes().to_vec();
+                    send(
+                        lua,
+                        |client| client.request(method.clone(), &url).body(body),
+                        opts,
+                    )
+                },
+            );
+        }
+
+        methods.add_method(
+            "request",
+            |lua, _, (method, url, opts): (String, String, Option<Table>)| {
+                let method = parse_method(&method)?;
+                let body = opt::<mlua::String>(opts.as_ref(), "body")?;
+                send(
+                    lua,
+                    |client| {
+                        let request = client.request(method, &url);
+                        match body {
+                            Some(body) => request.body(body.as_bytes().to_vec()),
+                            None => request,
+                        }
+                    },
+                    opts,
+                )
+            },
+        );
+
+        methods.add_method(
+            "get_json",
+            |lua, _, (url, opts): (String, Option<Table>)| {
+                send_json(lua, |client| client.get(&url), opts)
+            },
+        );
+
+        methods.add_method(
+            "post_json",
+            |lua, _, (url, value, opts): (String, Value, Option<Table>)| {
+                let body = json::encode(value)?;
+                send_json(
+                    lua,
+                    |client| {
+                        client
+                            .post(&url)
+                            .header(CONTENT_TYPE, "application/json")
+                            .body(body)
+                    },
+                    opts,
+                )
+            },
+        );
+
+        methods.add_method(
+            "post_form",
+            |lua, _, (url, fields, opts): (String, Table, Option<Table>)| {
+                let fields = param_pairs(&fields)?;
+                send(lua, |client| client.post(&url).form(&fields), opts)
+            },
+        );
+
+        methods.add_method(
+            "post_multipart",
+            |lua, _, (url, parts, opts): (String, Table, Option<Table>)| {
+                let form = multipart_form(parts)?;
+                send(lua, |client| client.post(&url).multipart(form), opts)
+            },
+        );
+
+        methods.add_method(
+            "download",
+            |lua, _, (url, dest, opts): (String, String, Option<Table>)| {
+                download(lua, &url, &dest, opts.as_ref())
+            },
+        );
+
+        methods.add_method(
+            "batch",
+            |lua, _, (requests, opts): (Table, Option<Table>)| batch(lua, requests, opts.as_ref()),
+        );
+
+        methods.add_method("encode_query", |_, _, params: Table| encode_query(&params));
+
+        methods.add_method("decode_query", |lua, _, query: String| {
+            decode_query(lua, &query)
+        });
+
+        methods.add_method("set_defaults", |lua, _, opts: Table| {
+            let auth = Auth::from_opts(Some(&opts))?;
+            let mut state = state_mut(lua)?;
+            let mut config = state.config.clone();
+            if let Some(timeout) = timeout_ms(Some(&opts), "timeout_ms")? {
+                config.timeout = timeout;
+            }
+            config.apply(Some(&opts))?;
+            match opts.get::<Value>("user_agent")? {
+                Value::Nil => {}
+                Value::Boolean(false) => config.user_agent = None,
+                Value::String(user_agent) => {
+                    config.user_agent =
+                        Some(HeaderValue::from_bytes(&user_agent.as_bytes()).map_err(|_| {
+                            mlua::Error::RuntimeError(
+                                "invalid user_agent: it contains a newline or another control character"
+                                    .into(),
+                            )
+                        })?);
+                }
+                other => {
+                    return Err(mlua::Error::RuntimeError(format!(
+                        "invalid user_agent: expected a string or false, got {}",
+                        other.type_name()
+                    )));
+                }
+            }
+            let headers = match opts.get::<Value>("headers")? {
+                Value::Nil => None,
+                Value::Boolean(false) => Some(HeaderMap::new()),
+                Value::Table(headers) => Some(header_map(headers)?),
+                other => {
+                    return Err(mlua::Error::RuntimeError(format!(
+                        "invalid headers: expected a table or false, got {}",
+                        other.type_name()
+                    )));
+                }
+            };
+
+            // only a change to how the client is built replaces it, so the
+            // other defaults keep its open connections
+            state.reconfigure(config)?;
+            if let Some(auth) = auth {
+                state.auth = auth;
+            }
+            if let Some(headers) = headers {
+                state.headers = headers;
+            }
+            Ok(())
+        });
+
+        methods.add_method("get_defaults", |lua, _, ()| {
+            let state = state(lua)?;
+            let config = &state.config;
+            let defaults = lua.create_table()?;
+            defaults.set(
+                "timeout_ms",
+                config
+                    .timeout
+                    .map_or(0, |timeout| timeout.as_millis() as u64),
+            )?;
+            defaults.set(
+                "connect_timeout_ms",
+                config
+                    .connect_timeout
+                    .map(|timeout| timeout.as_millis() as u64),
+            )?;
+            match config.max_redirects {
+                0 => defaults.set("redirect", false)?,
+                max => defaults.set("redirect", max)?,
+            }
+            defaults.set(
+                "user_agent",
+                config
+                    .user_agent
+                    .as_ref()
+                    .map(|user_agent| lua.create_string(user_agent.as_bytes()))
+                    .transpose()?,
+            )?;
+            defaults.set("tls", config.tls.describe(lua)?)?;
+            defaults.set(
+                "auth",
+                state
+                    .auth
+                    .as_ref()
+                    .map(|auth| auth.describe(lua))
+                    .transpose()?,
+            )?;
+
+            // credentials set as headers are left out like those in `auth`
+            let mut headers = state.headers.clone();
+            for value in headers.values_mut() {
+                if value.is_sensitive() {
+                    *value = HeaderValue::from_static("***");
+                }
+            }
+            defaults.set("headers", headers_table(lua, &headers)?)?;
+            Ok(defaults)
+        });
+
+        methods.add_method("enable_cookies", |lua, _, enabled: Option<bool>| {
+            let mut state = state_mut(lua)?;
+            let config = ClientConfig {
+                cookies: enabled.unwrap_or(true),
+                ..state.config.clone()
+            };
+            state.reconfigure(config)
+        });
+
+        methods.add_method("cookies", |lua, _, url: String| {
+            let jar = Arc::clone(&state(lua)?.jar);
+            jar.list(lua, &url)
+        });
+
+        methods.add_method(
+            "set_cookie",
+            |lua, _, (url, name, value, attrs): (String, String, String, Option<Table>)| {
+                let jar = Arc::clone(&state(lua)?.jar);
+                jar.set(&url, name, value, attrs.as_ref())
+            },
+        );
+
+        methods.add_method("clear_cookies", |lua, _, ()| {
+            state(lua)?.jar.clear();
+            Ok(())
+        });
+
+        methods.add_method("save_cookies", |lua, _, path: String| {
+            state(lua)?.jar.save(&path)
+        });
+
+        methods.add_method("load_cookies", |lua, _, path: String| {
+            state(lua)?.jar.load(&path)
+        });
+
+        methods.add_method("set_proxy", |lua, _, proxies: Value| {
+            let proxy = match proxies {
+                Value::Nil => ProxyMode::System,
+                Value::Boolean(false) => ProxyMode::Direct,
+                Value::Table(proxies) => ProxyMode::Custom(ProxyConfig::from_table(&proxies)?),
+                other => {
+                    return Err(mlua::Error::RuntimeError(format!(
+                        "invalid proxies: expected a table, false or nil, got {}",
+                        other.type_name()
+                    )));
+                }
+            };
+            let mut state = state_mut(lua)?;
+            let config = ClientConfig {
+                proxy,
+                ..state.config.clone()
+            };
+            state.reconfigure(config)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        sync::{
+            Arc, Mutex,
+            atomic::{AtomicUsize, Ordering},
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use mlua::Lua;
+
+    use super::super::testing::{error, eval, lua};
+
+    /// A request as the test server read it off the connection.
+    struct RawRequest {
+        /// The request line, as in `GET /path HTTP/1.1`.
+        line: String,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    }
+
+    impl RawRequest {
+        /// The values the request has for a header, in the order they came.
+        fn header(&self, name: &str) -> Vec<&str> {
+            self.headers
+                .iter()
+                .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+                .collect()
+        }
+
+        /// The path and query the request was sent to.
+        fn target(&self) -> &str {
+            self.line.split(' ').nth(1).unwrap_or_default()
+        }
+    }
+
+    fn read_request(reader: &mut impl BufRead) -> Option<RawRequest> {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let mut headers = Vec::new();
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).ok()?;
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            let (name, value) = header.split_once(':')?;
+            headers.push((name.to_string(), value.trim().to_string()));
+        }
+        let mut request = RawRequest {
+            line: line.trim_end().to_string(),
+            headers,
+            body: Vec::new(),
+        };
+        let length = request
+            .header("content-length")
+            .first()
+            .map_or(0, |length| length.parse().unwrap());
+        request.body = vec![0; length];
+        reader.read_exact(&mut request.body).ok()?;
+        Some(request)
+    }
+
+    /// A complete HTTP/1.1 response that closes the connection after it.
+    fn response(status: &str, headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+        let mut head = format!(
+            "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            body.len()
+        );
+        for (name, value) in headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        head.push_str("\r\n");
+        [head.into_bytes(), body.to_vec()].concat()
+    }
+
+    /// Starts a server on a free local port that answers every request with
+    /// what `respond` returns for it, each connection on a thread of its own
+    /// so that concurrent requests are answered concurrently. Returns a Lua
+    /// state whose `server` global is the server's URL, and the requests the
+    /// server got.
+    fn serve(
+        respond: impl Fn(&RawRequest) -> Vec<u8> + Send + Sync + 'static,
+    ) -> (Lua, Arc<Mutex<Vec<RawRequest>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = Arc::clone(&requests);
+        let respond = Arc::new(respond);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { break };
+                let (respond, received) = (Arc::clone(&respond), Arc::clone(&received));
+                thread::spawn(move || {
+                    let Some(request) = read_request(&mut BufReader::new(&stream)) else {
+                        return;
+                    };
+                    // recorded before answering, so that the requests are
+                    // all there by the time the client has its response
+                    let answer = respond(&request);
+                    received.lock().unwrap().push(request);
+                    let _ = stream.write_all(&answer);
+                });
+            }
+        });
+
+        let lua = lua();
+        lua.globals().set("server", url).unwrap();
+        (lua, requests)
+    }
+
+    #[test]
+    fn custom_headers_arrive_as_sent() {
+        let (lua, requests) = serve(|_| response("200 OK", &[], b"ok"));
+        let (status, body): (u16, String) = eval(
+            &lua,
+            r#"local r = http:get(server .. "/headers", {headers = {
+                ["X-Custom"] = "one", accept = "application/json",
+                ["x-multi"] = {"a", "b"}, ["User-Agent"] = "lunash-test/1.0",
+                ["X-Number"] = 42,
+            }})
+            return r.status, r.body"#,
+        );
+        assert_eq!((status, body.as_str()), (200, "ok"));
+
+        let requests = requests.lock().unwrap();
+        let request = &requests[0];
+        assert_eq!(request.line, "GET /headers HTTP/1.1");
+        assert_eq!(request.header("x-custom"), ["one"]);
+        assert_eq!(request.header("Accept"), ["application/json"]);
+        assert_eq!(request.header("X-Multi"), ["a", "b"]);
+        assert_eq!(request.header("user-agent"), ["lunash-test/1.0"]);
+        assert_eq!(request.header("x-number"), ["42"]);
+    }
+
+    #[test]
+    fn post_sends_its_body_and_headers() {
+        let (lua, requests) = serve(|request| response("201 Created", &[], &request.body));
+        let (status, echoed): (u16, mlua::String) = eval(
+            &lua,
+            r#"local r = http:post(server .. "/items", "raw\0body", {headers = {["Content-Type"] = "text/plain"}})
+            return r.status, r.body"#,
+        );
+        assert_eq!(status, 201);
+        assert_eq!(&*echoed.as_bytes(), b"raw\0body");
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].line, "POST /items HTTP/1.1");
+        assert_eq!(requests[0].header("content-type"), ["text/plain"]);
+    }
+
+    #[test]
+    fn invalid_headers_are_errors_naming_them() {
+        let lua = lua();
+        for (headers, expected) in [
+            (
+                r#"{["bad header"] = "x"}"#,
+                "invalid header name 'bad header'",
+            ),
+            (
+                r#"{["X-Ok"] = "line\nbreak"}"#,
+                "invalid value for header 'x-ok'",
+            ),
+            (
+                r#"{["X-Ok"] = true}"#,
+                "invalid value for header 'x-ok': expected a string or a number, got boolean",
+            ),
+        ] {
+            let message = error(
+                &lua,
+                &format!(r#"http:get("http://127.0.0.1:9/", {{headers = {headers}}})"#),
+            );
+            assert!(message.contains(expected), "{headers}: {message}");
+        }
+    }
+
+    #[test]
+    fn slow_servers_time_out_on_time() {
+        let (lua, _) = serve(|_| {
+            thread::sleep(Duration::from_secs(1));
+            response("200 OK", &[], b"late")
+        });
+        let started = Instant::now();
+        let message = error(&lua, r#"http:get(server, {timeout_ms = 200})"#);
+        assert!(started.elapsed() < Duration::from_millis(900));
+        assert!(message.starts_with("runtime error: timeout: "), "{message}");
+
+        // the same through the defaults, which a request can still turn off
+        let message = error(
+            &lua,
+            r#"http:set_defaults({timeout_ms = 200}); http:get(server)"#,
+        );
+        assert!(message.starts_with("runtime error: timeout: "), "{message}");
+        let body: String = eval(&lua, r#"return http:get(server, {timeout_ms = 0}).body"#);
+        assert_eq!(body, "late");
+    }
+
+    #[test]
+    fn refused_connections_are_told_apart_from_timeouts() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let message = error(&lua(), &format!("http:get('http://127.0.0.1:{port}/')"));
+        assert!(message.starts_with("runtime error: connect: "), "{message}");
+    }
+
+    #[test]
+    fn query_params_are_sorted_and_encoded() {
+        let (lua, requests) = serve(|_| response("200 OK", &[], b""));
+        eval::<()>(
+            &lua,
+            r#"http:get(server .. "/search?x=1", {query = {
+                q = "a b&c=d", lang = "café", tag = {"z", "a"}, page = 2,
+            }})"#,
+        );
+        assert_eq!(
+            requests.lock().unwrap()[0].target(),
+            "/search?x=1&lang=caf%C3%A9&page=2&q=a+b%26c%3Dd&tag=z&tag=a"
+        );
+    }
+
+    #[test]
+    fn encode_query_and_decode_query_round_trip() {
+        let lua = lua();
+        let encoded: String = eval(
+            &lua,
+            r#"return http:encode_query({b = "x y", a = {"1", "2"}, ["k&="] = "ü"})"#,
+        );
+        assert_eq!(encoded, "a=1&a=2&b=x+y&k%26%3D=%C3%BC");
+
+        let decoded: String = eval(
+            &lua,
+            r#"local q = http:decode_query("?a=1&b=x+y&a=2&k%26%3D=%C3%BC&empty=")
+            return table.concat({q.a[1], q.a[2], q.b, q["k&="], "[" .. q.empty .. "]"}, ",")"#,
+        );
+        assert_eq!(decoded, "1,2,x y,ü,[]");
+
+        let message = error(&lua, r#"http:encode_query({a = {}, [true] = "x"})"#);
+        assert!(
+            message.contains("invalid parameter name: expected a string, got boolean"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn cookies_go_back_once_enabled() {
+        let (lua, requests) = serve(|request| {
+            if request.target() == "/login" {
+                response(
+                    "200 OK",
+                    &[("Set-Cookie", "session=abc; Path=/; HttpOnly")],
+                    b"",
+                )
+            } else {
+                response("200 OK", &[], b"")
+            }
+        });
+        eval::<()>(
+            &lua,
+            r#"http:get(server .. "/login"); http:get(server .. "/profile")"#,
+        );
+        let described: String = eval(
+            &lua,
+            r#"http:enable_cookies()
+            http:get(server .. "/login"); http:get(server .. "/profile")
+            local cookies = http:cookies(server .. "/profile")
+            local c = cookies[1]
+            return table.concat({#cookies, c.name, c.value, c.path, tostring(c.http_only),
+                tostring(c.secure), tostring(c.expires)}, ",")"#,
+        );
+        assert_eq!(described, "1,session,abc,/,true,false,nil");
+
+        let count: usize = eval(
+            &lua,
+            r#"http:clear_cookies(); http:get(server .. "/profile")
+            return #http:cookies(server)"#,
+        );
+        assert_eq!(count, 0);
+
+        let requests = requests.lock().unwrap();
+        let cookies: Vec<_> = requests.iter().map(|r| r.header("cookie")).collect();
+        assert_eq!(
+            cookies,
+            [vec![], vec![], vec![], vec!["session=abc"], vec![]]
+        );
+    }
+
+    #[test]
+    fn post_form_round_trips_its_fields() {
+        let (lua, requests) = serve(|request| response("200 OK", &[], &request.body));
+        let decoded: String = eval(
+            &lua,
+            r#"local r = http:post_form(server .. "/form", {note = "a&b=c d", name = "José", tag = {"x", "y"}})
+            local q = http:decode_query(r.body)
+            return table.concat({q.note, q.name, q.tag[1], q.tag[2]}, "|")"#,
+        );
+        assert_eq!(decoded, "a&b=c d|José|x|y");
+        eval::<()>(
+            &lua,
+            r#"http:post_form(server .. "/form", {a = "1"},
+                {headers = {["Content-Type"] = "application/x-www-form-urlencoded; charset=utf-8"}})"#,
+        );
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(
+            requests[0].body,
+            b"name=Jos%C3%A9&note=a%26b%3Dc+d&tag=x&tag=y"
+        );
+        assert_eq!(
+            requests[0].header("content-type"),
+            ["application/x-www-form-urlencoded"]
+        );
+        assert_eq!(
+            requests[1].header("content-type"),
+            ["application/x-www-form-urlencoded; charset=utf-8"]
+        );
+    }
+
+    #[test]
+    fn retries_resend_until_the_status_is_not_retried() {
+        let sent = AtomicUsize::new(0);
+        let (lua, requests) = serve(move |_| match sent.fetch_add(1, Ordering::SeqCst) {
+            0 => response("503 Service Unavailable", &[], b""),
+            1 => response("418 I'm a teapot", &[], b""),
+            _ => response("200 OK", &[], b"done"),
+        });
+        let (status, attempts): (u16, u32) = eval(
+            &lua,
+            r#"local r = http:get(server, {retry = {on = {503, 418}, backoff_ms = 1, jitter = false}})
+            return r.status, r.attempts"#,
+        );
+        assert_eq!((status, attempts), (200, 3));
+        assert_eq!(requests.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn retry_statuses_out_of_range_are_errors() {
+        let lua = lua();
+        for status in ["42", "600", "65939", "-1"] {
+            let message = error(
+                &lua,
+                &format!("http:get('http://127.0.0.1:9/', {{retry = {{on = {{{status}}}}}}})"),
+            );
+            assert!(
+                message.contains(&format!(
+                    "invalid retry status {status}: expected a status code from 100 to 599"
+                )),
+                "{message}"
+            );
+        }
+    }
+
+    #[test]
+    fn default_headers_only_fill_in_missing_headers() {
+        let (lua, requests) =
+            serve(|_| response("200 OK", &[("Content-Type", "application/json")], b"{}"));
+        eval::<()>(
+            &lua,
+            r#"http:set_defaults({headers = {
+                ["Content-Type"] = "text/plain", accept = "text/html",
+                ["X-Default"] = "default", ["X-Multi"] = {"a", "b"},
+                authorization = "Bearer default",
+            }})
+            http:post_json(server, {a = 1})
+            http:get_json(server)
+            http:get(server, {headers = {["x-default"] = "mine", Accept = "*/*"}})
+            http:get(server, {auth = {type = "bearer", token = "mine"}})
+            http:post(server, "text")"#,
+        );
+
+        let requests = requests.lock().unwrap();
+        let header = |i: usize, name| requests[i].header(name);
+        assert_eq!(header(0, "content-type"), ["application/json"]);
+        assert_eq!(header(0, "accept"), ["application/json"]);
+        assert_eq!(header(0, "x-multi"), ["a", "b"]);
+        assert_eq!(header(1, "accept"), ["application/json"]);
+        assert_eq!(header(1, "x-default"), ["default"]);
+        assert_eq!(header(2, "x-default"), ["mine"]);
+        assert_eq!(header(2, "accept"), ["*/*"]);
+        assert_eq!(header(2, "authorization"), ["Bearer default"]);
+        assert_eq!(header(3, "authorization"), ["Bearer mine"]);
+        assert_eq!(header(4, "content-type"), ["text/plain"]);
+        assert_eq!(header(4, "accept"), ["text/html"]);
+    }
+
+    #[test]
+    fn bodies_stay_bytes_until_decoded() {
+        let (lua, _) = serve(|request| match request.target() {
+            "/latin1" => response(
+                "200 OK",
+                &[("Content-Type", "text/plain; charset=\"ISO-8859-1\"")],
+                b"caf\xe9 \x80",
+            ),
+            "/binary" => response(
+                "200 OK",
+                &[("Content-Type", "application/octet-stream")],
+                &(0..=255).collect::<Vec<u8>>(),
+            ),
+            _ => response("200 OK", &[], b"bad \xff utf-8"),
+        });
+
+        let (raw, text, cp1252): (mlua::String, String, String) = eval(
+            &lua,
+            r#"local r = http:get(server .. "/latin1")
+            return r.body, r:text(), r:text("windows-1252")"#,
+        );
+        assert_eq!(&*raw.as_bytes(), b"caf\xe9 \x80");
+        // ISO-8859-1 is an alias of windows-1252, as in browsers
+        assert_eq!(text, "café €");
+        assert_eq!(cp1252, "café €");
+
+        let binary: mlua::String = eval(&lua, r#"return http:get(server .. "/binary").body"#);
+        assert_eq!(&*binary.as_bytes(), &(0..=255).collect::<Vec<u8>>()[..]);
+
+        let text: String = eval(&lua, r#"return http:get(server .. "/plain"):text()"#);
+        assert_eq!(text, "bad \u{fffd} utf-8");
+
+        let message = error(&lua, r#"http:get(server .. "/plain"):text("klingon")"#);
+        assert!(message.contains("unknown encoding 'klingon'"), "{message}");
+    }
+
+    #[test]
+    fn repeated_headers_are_kept_apart_in_headers_all() {
+        let (lua, _) = serve(|_| {
+            response(
+                "200 OK",
+                &[
+                    ("Set-Cookie", "a=1; Expires=Wed, 21 Oct 2037 07:28:00 GMT"),
+                    ("Set-Cookie", "b=2"),
+                    ("X-Single", "one"),
+                ],
+                b"",
+            )
+        });
+        let (joined, all, single, missing): (String, Vec<String>, Vec<String>, bool) = eval(
+            &lua,
+            r#"local r = http:get(server)
+            return r.headers["set-cookie"], r.headers_all["Set-Cookie"], r.headers_all["x-single"],
+                r.headers_all["x-missing"] == nil"#,
+        );
+        assert_eq!(joined, "a=1; Expires=Wed, 21 Oct 2037 07:28:00 GMT, b=2");
+        assert_eq!(all, ["a=1; Expires=Wed, 21 Oct 2037 07:28:00 GMT", "b=2"]);
+        assert_eq!(single, ["one"]);
+        assert!(missing);
+    }
+
+    #[test]
+    fn json_requests_encode_and_decode_their_bodies() {
+        let page = format!(
+            "<!DOCTYPE html><html><body>{}</body></html>",
+            "x".repeat(300)
+        );
+        let (lua, requests) = serve(move |request| match request.target() {
+            "/echo" => response(
+                "200 OK",
+                &[("Content-Type", "application/json")],
+                &request.body,
+            ),
+            "/empty" => response("204 No Content", &[], b""),
+            _ => response(
+                "502 Bad Gateway",
+                &[("Content-Type", "text/html")],
+                page.as_bytes(),
+            ),
+        });
+
+        let (echoed, empty): (String, bool) = eval(
+            &lua,
+            r#"local value, r = http:post_json(server .. "/echo", {name = "é", tags = {"a", "b"}, none = {}})
+            assert(r.status == 200)
+            local nothing = http:get_json(server .. "/empty")
+            return value.name .. "," .. table.concat(value.tags, "+") .. "," .. type(value.none),
+                nothing == nil"#,
+        );
+        assert_eq!(echoed, "é,a+b,table");
+        assert!(empty);
+
+        let message = error(&lua, r#"http:get_json(server .. "/broken")"#);
+        assert!(
+            message.contains(&format!(
+                "failed to decode the JSON response from '{}/broken' (HTTP 502): expected value at line 1 column 1, the body starts with: <!DOCTYPE html><html><body>xxx",
+                lua.globals().get::<String>("server").unwrap()
+            )),
+            "{message}"
+        );
+        // only the first 200 bytes of the page are shown
+        assert!(!message.contains(&"x".repeat(200)), "{message}");
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].line, "POST /echo HTTP/1.1");
+        assert_eq!(requests[0].header("content-type"), ["application/json"]);
+        assert_eq!(requests[0].header("accept"), ["application/json"]);
+        assert_eq!(
+            requests[0].body,
+            r#"{"name":"é","none":{},"tags":["a","b"]}"#.as_bytes()
+        );
+        assert_eq!(requests[1].line, "GET /empty HTTP/1.1");
+        assert_eq!(requests[1].header("accept"), ["application/json"]);
+        assert!(requests[1].header("content-type").is_empty());
+    }
+
+    #[test]
+    fn batch_results_keep_the_order_of_the_requests() {
+        let (lua, requests) = serve(|request| {
+            if request.target() == "/slow" {
+                thread::sleep(Duration::from_millis(300));
+            }
+            response("200 OK", &[], request.target().as_bytes())
+        });
+        let results: Vec<String> = eval(
+            &lua,
+            r#"local results = http:batch({
+                {url = server .. "/slow"},
+                {url = server .. "/invalid", headers = {["bad header"] = "x"}},
+                {url = server .. "/fast", method = "POST", body = "x"},
+            })
+            local described = {}
+            for i, r in ipairs(results) do
+                described[i] = r.ok and r.body or "error: " .. r.error
+            end
+            return described"#,
+        );
+        assert_eq!(results[0], "/slow");
+        assert!(
+            results[1].starts_with("error: ")
+                && results[1].contains("invalid header name 'bad header'"),
+            "{}",
+            results[1]
+        );
+        assert_eq!(results[2], "/fast");
+
+        // the fast request didn't wait for the slow one
+        let requests = requests.lock().unwrap();
+        let order: Vec<_> = requests.iter().map(RawRequest::target).collect();
+        assert_eq!(order, ["/fast", "/slow"]);
+    }
+
+    #[test]
+    fn batch_stop_on_error_skips_the_requests_not_yet_started() {
+        let (lua, requests) = serve(|request| response("200 OK", &[], request.target().as_bytes()));
+        let refused = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        lua.globals()
+            .set("refused", format!("http://127.0.0.1:{refused}/"))
+            .unwrap();
+        let described: Vec<String> = eval(
+            &lua,
+            r#"local function describe(results)
+                local described = {}
+                for i, r in ipairs(results) do
+                    described[i] = r.skipped and "skipped" or r.ok and r.body
+                        or r.error:match("^[^:]*")
+                end
+                return table.concat(described, ",")
+            end
+            local opts = {concurrency = 1, stop_on_error = true}
+            return {
+                describe(http:batch({{url = server .. "/a"}, {url = refused}, {url = server .. "/b"}}, opts)),
+                describe(http:batch({{url = server .. "/c"}, {url = server, retry = 1}, {url = server .. "/d"}}, opts)),
+                describe(http:batch({{url = refused}, {url = server .. "/e"}}, {concurrency = 1})),
+            }"#,
+        );
+        assert_eq!(
+            described,
+            [
+                "/a,connect,skipped",
+                "/c,invalid retry option,skipped",
+                "connect,/e"
+            ]
+        );
+        let order: Vec<_> = requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|r| r.target().to_string())
+            .collect();
+        assert_eq!(order, ["/a", "/c", "/e"]);
+    }
+}
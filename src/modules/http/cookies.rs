@@ -0,0 +1,214 @@
+use std::{fs, sync::RwLock};
+
+use cookie::{Cookie as RawCookie, time::OffsetDateTime};
+use cookie_store::{Cookie, CookieDomain, CookieExpiration, CookieStore};
+use mlua::{Lua, Table};
+use reqwest::header::HeaderValue;
+use serde_json::{Value as Json, json};
+use url::Url;
+
+use super::super::{fs::io_error, opt};
+
+/// The cookies of every request that has them enabled, shared with the
+/// functions that let scripts look at and change them, which reqwest's own
+/// `Jar` doesn't allow.
+#[derive(Default)]
+pub struct CookieJar(RwLock<CookieStore>);
+
+impl reqwest::cookie::CookieStore for CookieJar {
+    fn set_cookies(&self, cookie_headers: &mut dyn Iterator<Item = &HeaderValue>, url: &Url) {
+        let cookies = cookie_headers.filter_map(|value| {
+            let value = value.to_str().ok()?;
+            RawCookie::parse(value).ok().map(RawCookie::into_owned)
+        });
+        self.0
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .store_response_cookies(cookies, url);
+    }
+
+    fn cookies(&self, url: &Url) -> Option<HeaderValue> {
+        let header = self
+            .0
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_request_values(url)
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect::<Vec<_>>()
+            .join("; ");
+        if header.is_empty() {
+            return None;
+        }
+        let mut header = HeaderValue::from_str(&header).ok()?;
+        header.set_sensitive(true);
+        Some(header)
+    }
+}
+
+fn parse_url(url: &str) -> mlua::Result<Url> {
+    Url::parse(url).map_err(|e| mlua::Error::RuntimeError(format!("invalid URL '{url}': {e}")))
+}
+
+/// Describes a stored cookie with the fields both `http:cookies` and the
+/// saved file use.
+fn describe(cookie: &Cookie) -> Json {
+    let (domain, host_only) = match &cookie.domain {
+        CookieDomain::HostOnly(host) => (Some(host.as_str()), true),
+        CookieDomain::Suffix(domain) => (Some(domain.as_str()), false),
+        CookieDomain::NotPresent | CookieDomain::Empty => (None, true),
+    };
+    let expires = match cookie.expires {
+        CookieExpiration::AtUtc(time) => Some(time.unix_timestamp()),
+        CookieExpiration::SessionEnd => None,
+    };
+    json!({
+        "name": cookie.name(),
+        "value": cookie.value(),
+        "domain": domain,
+        "host_only": host_only,
+        "path": String::from(&cookie.path),
+        "secure": cookie.secure().unwrap_or(false),
+        "http_only": cookie.http_only().unwrap_or(false),
+        "expires": expires,
+    })
+}
+
+impl CookieJar {
+    fn store(&self) -> std::sync::RwLockWriteGuard<'_, CookieStore> {
+        self.0.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Returns the cookies that would be sent with a request to the URL.
+    pub fn list(&self, lua: &Lua, url: &str) -> mlua::Result<Table> {
+        let url = parse_url(url)?;
+        let store = self.store();
+        let cookies = lua.create_table()?;
+        for cookie in store.matches(&url) {
+            let cookie = describe(cookie);
+            let table = lua.create_table()?;
+            for key in [
+                "name",
+                "value",
+                "domain",
+                "path",
+                "secure",
+                "http_only",
+                "expires",
+            ] {
+                match &cookie[key] {
+                    Json::String(s) => table.set(key, s.as_str())?,
+                    Json::Bool(b) => table.set(key, *b)?,
+                    Json::Number(n) => table.set(key, n.as_i64())?,
+                    _ => {}
+                }
+            }
+            cookies.raw_push(table)?;
+        }
+        Ok(cookies)
+    }
+
+    /// Stores a cookie as if the URL had sent it in a `Set-Cookie` header.
+    pub fn set(
+        &self,
+        url: &str,
+        name: String,
+        value: String,
+        attrs: Option<&Table>,
+    ) -> mlua::Result<()> {
+        let url = parse_url(url)?;
+        let mut cookie = RawCookie::build((name.clone(), value));
+        if let Some(path) = opt::<String>(attrs, "path")? {
+            cookie = cookie.path(path);
+        }
+        if let Some(domain) = opt::<String>(attrs, "domain")? {
+            cookie = cookie.domain(domain);
+        }
+        if let Some(secure) = opt::<bool>(attrs, "secure")? {
+            cookie = cookie.secure(secure);
+        }
+        if let Some(http_only) = opt::<bool>(attrs, "http_only")? {
+            cookie = cookie.http_only(http_only);
+        }
+        if let Some(max_age) = opt::<i64>(attrs, "max_age")? {
+            cookie = cookie.max_age(cookie::time::Duration::seconds(max_age));
+        }
+        if let Some(expires) = opt::<i64>(attrs, "expires")? {
+            let expires = OffsetDateTime::from_unix_timestamp(expires).map_err(|e| {
+                mlua::Error::RuntimeError(format!("invalid expiry time for cookie '{name}': {e}"))
+            })?;
+            cookie = cookie.expires(expires);
+        }
+
+        self.store()
+            .insert_raw(&cookie.build(), &url)
+            .map(|_| ())
+            .map_err(|e| {
+                mlua::Error::RuntimeError(format!("failed to set cookie '{name}' for '{url}': {e}"))
+            })
+    }
+
+    pub fn clear(&self) {
+        self.store().clear();
+    }
+
+    /// Writes every cookie that hasn't expired, including the ones that only
+    /// last for the session, to a JSON file.
+    pub fn save(&self, path: &str) -> mlua::Result<()> {
+        let cookies: Vec<Json> = self.store().iter_unexpired().map(describe).collect();
+        let json = serde_json::to_string_pretty(&cookies)
+            .map_err(|e| mlua::Error::RuntimeError(format!("failed to save cookies: {e}")))?;
+        fs::write(path, json).map_err(|e| io_error("write", path, e))
+    }
+
+    /// Adds the cookies from a file written by `save`, skipping ones that
+    /// have expired since, and returns how many were loaded.
+    pub fn load(&self, path: &str) -> mlua::Result<usize> {
+        let invalid = |message: String| {
+            mlua::Error::RuntimeError(format!("failed to load cookies from '{path}': {message}"))
+        };
+
+        let json = fs::read_to_string(path).map_err(|e| io_error("read", path, e))?;
+        let cookies: Vec<Json> = serde_json::from_str(&json).map_err(|e| invalid(e.to_string()))?;
+
+        let mut store = self.store();
+        let mut loaded = 0;
+        for (i, cookie) in cookies.iter().enumerate() {
+            let field = |key: &str| {
+                cookie[key]
+                    .as_str()
+                    .ok_or_else(|| invalid(format!("cookie {} has no {key}", i + 1)))
+            };
+            let (name, value, domain, cookie_path) = (
+                field("name")?,
+                field("value")?,
+                field("domain")?,
+                field("path")?,
+            );
+
+            let mut raw = RawCookie::build((name.to_string(), value.to_string()))
+                .path(cookie_path.to_string())
+                .secure(cookie["secure"].as_bool().unwrap_or(false))
+                .http_only(cookie["http_only"].as_bool().unwrap_or(false));
+            // a cookie without a Domain attribute only goes back to its host
+            if !cookie["host_only"].as_bool().unwrap_or(true) {
+                raw = raw.domain(domain.to_string());
+            }
+            if let Some(expires) = cookie["expires"].as_i64() {
+                match OffsetDateTime::from_unix_timestamp(expires) {
+                    Ok(expires) if expires > OffsetDateTime::now_utc() => {
+                        raw = raw.expires(expires)
+                    }
+                    _ => continue,
+                }
+            }
+
+            // https, so that secure cookies are accepted too
+            let url = parse_url(&format!("https://{domain}{cookie_path}"))?;
+            store
+                .insert_raw(&raw.build(), &url)
+                .map_err(|e| invalid(format!("cookie '{name}' was rejected: {e}")))?;
+            loaded += 1;
+        }
+        Ok(loaded)
+    }
+}
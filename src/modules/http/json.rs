@@ -0,0 +1,228 @@
+use std::ffi::c_void;
+
+use mlua::{Lua, Table, Value};
+use serde_json::{Map, Number, Value as Json};
+
+/// How deep tables may nest.
+const MAX_DEPTH: usize = 128;
+
+fn encode_error(path: &str, message: String) -> mlua::Error {
+    mlua::Error::RuntimeError(format!("failed to encode {path} as JSON: {message}"))
+}
+
+/// Returns the length of the table if its keys are exactly `1..=n`, so
+/// that it can be encoded as an array.
+fn array_len(table: &Table) -> mlua::Result<Option<usize>> {
+    let len = table.raw_len();
+    let mut count = 0;
+    for pair in table.pairs::<Value, Value>() {
+        match pair?.0 {
+            Value::Integer(i) if i >= 1 && i as usize <= len => count += 1,
+            _ => return Ok(None),
+        }
+    }
+    Ok((count == len && len > 0).then_some(len))
+}
+
+/// Encodes `value`, found at `path` inside the tables in `parents`.
+fn encode_at(value: Value, path: &str, parents: &mut Vec<*const c_void>) -> mlua::Result<Json> {
+    match value {
+        Value::Nil => Ok(Json::Null),
+        Value::Boolean(b) => Ok(Json::Bool(b)),
+        Value::Integer(n) => Ok(Json::Number(n.into())),
+        Value::Number(n) => Number::from_f64(n)
+            .map(Json::Number)
+            .ok_or_else(|| encode_error(path, format!("{n} isn't a valid JSON number"))),
+        Value::String(s) => match s.to_str() {
+            Ok(s) => Ok(Json::String(s.to_string())),
+            Err(_) => Err(encode_error(path, "the string isn't valid UTF-8".into())),
+        },
+        Value::Table(table) => {
+            if parents.contains(&table.to_pointer()) {
+                return Err(encode_error(path, "the table contains itself".into()));
+            }
+            if parents.len() >= MAX_DEPTH {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "failed to encode JSON: tables are nested more than {MAX_DEPTH} deep"
+                )));
+            }
+            parents.push(table.to_pointer());
+            let json = encode_table(&table, path, parents);
+            parents.pop();
+            json
+        }
+        other => Err(encode_error(
+            path,
+            format!("a {} can't be encoded", other.type_name()),
+        )),
+    }
+}
+
+fn encode_table(table: &Table, path: &str, parents: &mut Vec<*const c_void>) -> mlua::Result<Json> {
+    if let Some(len) = array_len(table)? {
+        let mut items = Vec::with_capacity(len);
+        for i in 1..=len {
+            items.push(encode_at(
+                table.raw_get(i)?,
+                &format!("{path}[{i}]"),
+                parents,
+            )?);
+        }
+        return Ok(Json::Array(items));
+    }
+
+    // an empty table is an object, as that's what APIs usually expect
+    let mut object = Map::new();
+    for pair in table.pairs::<Value, Value>() {
+        let (key, value) = pair?;
+        let key = match key {
+            Value::String(s) => s.to_str()?.to_string(),
+            Value::Integer(n) => n.to_string(),
+            other => {
+                return Err(encode_error(
+                    path,
+                    format!("a {} can't be an object key", other.type_name()),
+                ));
+            }
+        };
+        let value = encode_at(value, &format!("{path}.{key}"), parents)?;
+        object.insert(key, value);
+    }
+    Ok(Json::Object(object))
+}
+
+/// Encodes a Lua value as JSON text, with tables whose keys are `1..=n`
+/// becoming arrays and other tables becoming objects.
+pub fn encode(value: Value) -> mlua::Result<String> {
+    let json = encode_at(value, "value", &mut Vec::new())?;
+    serde_json::to_string(&json)
+        .map_err(|e| mlua::Error::RuntimeError(format!("failed to encode JSON: {e}")))
+}
+
+/// Converts decoded JSON to Lua, where `null` becomes `nil`.
+pub fn to_lua(lua: &Lua, json: Json) -> mlua::Result<Value> {
+    Ok(match json {
+        Json::Null => Value::Nil,
+        Json::Bool(b) => Value::Boolean(b),
+        Json::Number(n) => match n.as_i64() {
+            Some(n) => Value::Integer(n),
+            None => Value::Number(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        Json::String(s) => Value::String(lua.create_string(&s)?),
+        Json::Array(items) => {
+            let table = lua.create_table_with_capacity(items.len(), 0)?;
+            for (i, item) in items.into_iter().enumerate() {
+                table.raw_set(i + 1, to_lua(lua, item)?)?;
+            }
+            Value::Table(table)
+        }
+        Json::Object(object) => {
+            let table = lua.create_table_with_capacity(0, object.len())?;
+            for (key, value) in object {
+                table.raw_set(key, to_lua(lua, value)?)?;
+            }
+            Value::Table(table)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use mlua::{Lua, Table, Value};
+
+    use super::super::super::testing::{eval, lua};
+    use super::{MAX_DEPTH, encode, to_lua};
+
+    /// Encodes the value a chunk returns.
+    fn encoded(lua: &Lua, code: &str) -> mlua::Result<String> {
+        encode(eval::<Value>(lua, code))
+    }
+
+    #[test]
+    fn sequences_are_arrays_and_other_tables_objects() {
+        let lua = lua();
+        for (code, expected) in [
+            ("return {1, 2, 3}", "[1,2,3]"),
+            ("return {}", "{}"),
+            (
+                r#"return {b = 1, a = {true, false}}"#,
+                r#"{"a":[true,false],"b":1}"#,
+            ),
+            (r#"return {"x", n = 1}"#, r#"{"1":"x","n":1}"#),
+            // a hole or a key past the end leaves the table an object
+            ("return {[1] = 1, [3] = 3}", r#"{"1":1,"3":3}"#),
+            ("return {[2] = 2}", r#"{"2":2}"#),
+            ("return {1.5, -0, 2^53}", "[1.5,0,9007199254740992]"),
+            (
+                r#"return {"quote\" and \n", "é"}"#,
+                r#"["quote\" and \n","é"]"#,
+            ),
+            ("return nil", "null"),
+        ] {
+            assert_eq!(encoded(&lua, code).unwrap(), expected, "{code}");
+        }
+    }
+
+    #[test]
+    fn values_json_lacks_are_errors_naming_their_path() {
+        let lua = lua();
+        for (code, expected) in [
+            (
+                "local t = {a = {}} t.a.self = t return t",
+                "failed to encode value.a.self as JSON: the table contains itself",
+            ),
+            (
+                "return {list = {1, print}}",
+                "failed to encode value.list[2] as JSON: a function can't be encoded",
+            ),
+            (
+                "return {[true] = 1}",
+                "failed to encode value as JSON: a boolean can't be an object key",
+            ),
+            (
+                "return {n = 0/0}",
+                "failed to encode value.n as JSON: NaN isn't a valid JSON number",
+            ),
+            (
+                r#"return {s = "\xff"}"#,
+                "failed to encode value.s as JSON: the string isn't valid UTF-8",
+            ),
+        ] {
+            let message = encoded(&lua, code).unwrap_err().to_string();
+            assert!(message.contains(expected), "{code}: {message}");
+        }
+
+        // a table may appear twice as long as it doesn't contain itself
+        let shared = encoded(&lua, "local t = {1} return {t, t}").unwrap();
+        assert_eq!(shared, "[[1],[1]]");
+
+        let deep = format!(
+            "local t = {{}} local top = t for _ = 1, {MAX_DEPTH} do t[1] = {{}} t = t[1] end return top"
+        );
+        let message = encoded(&lua, &deep).unwrap_err().to_string();
+        assert!(
+            message.contains(&format!("tables are nested more than {MAX_DEPTH} deep")),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn decoded_null_becomes_nil() {
+        let lua = lua();
+        let json = serde_json::from_str(
+            r#"{"a": null, "list": [1, null, 3], "n": 1.5, "big": 18446744073709551615}"#,
+        )
+        .unwrap();
+        let Value::Table(table) = to_lua(&lua, json).unwrap() else {
+            panic!("an object should become a table");
+        };
+        assert!(table.get::<Value>("a").unwrap().is_nil());
+        let list: Table = table.get("list").unwrap();
+        assert_eq!(list.get::<i64>(1).unwrap(), 1);
+        assert!(list.get::<Value>(2).unwrap().is_nil());
+        assert_eq!(list.get::<i64>(3).unwrap(), 3);
+        assert_eq!(table.get::<f64>("n").unwrap(), 1.5);
+        // too big for an integer, so it's a float
+        assert_eq!(table.get::<f64>("big").unwrap(), 18446744073709551615.0);
+    }
+}
@@ -0,0 +1,114 @@
+use std::net::IpAddr;
+
+use mlua::{Table, Value};
+use reqwest::Proxy;
+use url::Url;
+
+/// The proxies set with `http:set_proxy`, and the hosts that bypass them.
+#[derive(Clone, PartialEq)]
+pub struct ProxyConfig {
+    http: Option<Url>,
+    https: Option<Url>,
+    no_proxy: Vec<String>,
+}
+
+fn proxy_url(scheme: &str, url: String) -> mlua::Result<Url> {
+    let invalid = |reason: &str| {
+        mlua::Error::RuntimeError(format!("invalid {scheme} proxy URL '{url}': {reason}"))
+    };
+    let parsed = Url::parse(&url).map_err(|e| invalid(&e.to_string()))?;
+    match parsed.scheme() {
+        "http" | "https" | "socks5" | "socks5h" => Ok(parsed),
+        _ => Err(invalid("the scheme must be http, https, socks5 or socks5h")),
+    }
+}
+
+impl ProxyConfig {
+    pub fn from_table(table: &Table) -> mlua::Result<ProxyConfig> {
+        let http = table.get::<Option<String>>("http")?;
+        let https = table.get::<Option<String>>("https")?;
+
+        let no_proxy = match table.get::<Value>("no_proxy")? {
+            Value::Nil => Vec::new(),
+            Value::String(hosts) => hosts
+                .to_str()?
+                .split(',')
+                .map(|host| host.trim().to_string())
+                .filter(|host| !host.is_empty())
+                .collect(),
+            Value::Table(hosts) => hosts
+                .sequence_values::<String>()
+                .collect::<mlua::Result<_>>()?,
+            other => {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "invalid no_proxy option: expected an array of hosts, got {}",
+                    other.type_name()
+                )));
+            }
+        };
+
+        Ok(ProxyConfig {
+            http: http.map(|url| proxy_url("http", url)).transpose()?,
+            https: https.map(|url| proxy_url("https", url)).transpose()?,
+            no_proxy: no_proxy
+                .into_iter()
+                .map(|host| host.to_ascii_lowercase())
+                .collect(),
+        })
+    }
+
+    /// Returns whether `host` is one of the `no_proxy` entries, which match
+    /// a host and its subdomains, or `*` for every host.
+    fn bypasses(&self, host: &str) -> bool {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        self.no_proxy.iter().any(|entry| {
+            let entry = entry.trim_start_matches('.');
+            if entry == "*" || entry.eq_ignore_ascii_case(host) {
+                return true;
+            }
+            // an IP address only matches itself, not anything ending in it
+            if host.parse::<IpAddr>().is_ok() {
+                return false;
+            }
+            host.len() > entry.len()
+                && host[host.len() - entry.len()..].eq_ignore_ascii_case(entry)
+                && host.as_bytes()[host.len() - entry.len() - 1] == b'.'
+        })
+    }
+
+    /// Returns the proxy a request to `url` goes through, if any.
+    pub fn for_url(&self, url: &Url) -> Option<&Url> {
+        if url.host_str().is_some_and(|host| self.bypasses(host)) {
+            return None;
+        }
+        match url.scheme() {
+            "http" => self.http.as_ref(),
+            "https" => self.https.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn to_proxy(&self) -> Proxy {
+        let config = self.clone();
+        Proxy::custom(move |url| config.for_url(url).cloned())
+    }
+}
+
+/// Formats a proxy URL for an error message, without its password.
+pub fn display(proxy: &Url) -> String {
+    let mut proxy = proxy.clone();
+    if proxy.password().is_some() {
+        let _ = proxy.set_password(Some("***"));
+    }
+    proxy.to_string()
+}
+
+/// Where the shared client sends requests: through the proxies from the
+/// environment variables reqwest reads, straight to the server, or through
+/// the ones set with `http:set_proxy`.
+#[derive(Clone, PartialEq)]
+pub enum ProxyMode {
+    System,
+    Direct,
+    Custom(ProxyConfig),
+}
@@ -0,0 +1,131 @@
+use std::time::{Duration, SystemTime};
+
+use mlua::{Table, Value};
+use reqwest::{StatusCode, blocking::Response, header::RETRY_AFTER};
+
+use super::super::opt;
+
+/// The kinds of failure from `request_error` that can be retried.
+const KINDS: [&str; 4] = ["timeout", "connect", "body", "request"];
+
+/// What `opts.retry` retries unless its `on` says otherwise.
+const DEFAULT_KINDS: [&str; 2] = ["timeout", "connect"];
+const DEFAULT_STATUSES: [u16; 4] = [429, 502, 503, 504];
+
+/// When and how often to send a request again, read from `opts.retry`.
+pub struct RetryPolicy {
+    pub max: u32,
+    backoff: Duration,
+    factor: f64,
+    jitter: bool,
+    max_delay: Duration,
+    kinds: Vec<String>,
+    statuses: Vec<u16>,
+    pub non_idempotent: bool,
+}
+
+impl RetryPolicy {
+    /// Reads `opts.retry`, which is either a table or `true` for the
+    /// defaults.
+    pub fn from_opts(opts: Option<&Table>) -> mlua::Result<Option<RetryPolicy>> {
+        let retry = match opt::<Value>(opts, "retry")? {
+            None | Some(Value::Nil) | Some(Value::Boolean(false)) => return Ok(None),
+            Some(Value::Boolean(true)) => None,
+            Some(Value::Table(retry)) => Some(retry),
+            Some(other) => {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "invalid retry option: expected a table or a boolean, got {}",
+                    other.type_name()
+                )));
+            }
+        };
+        let retry = retry.as_ref();
+
+        let mut kinds = Vec::new();
+        let mut statuses = Vec::new();
+        match opt::<Table>(retry, "on")? {
+            Some(on) => {
+                for condition in on.sequence_values::<Value>() {
+                    match condition? {
+                        Value::Integer(status) => match u16::try_from(status) {
+                            Ok(status @ 100..=599) => statuses.push(status),
+                            _ => {
+                                return Err(mlua::Error::RuntimeError(format!(
+                                    "invalid retry status {status}: expected a status code from 100 to 599"
+                                )));
+                            }
+                        },
+                        Value::String(kind) if KINDS.contains(&&*kind.to_str()?) => {
+                            kinds.push(kind.to_str()?.to_string());
+                        }
+                        other => {
+                            return Err(mlua::Error::RuntimeError(format!(
+                                "invalid retry condition {}: expected a status code or one of {}",
+                                other.to_string()?,
+                                KINDS.join(", ")
+                            )));
+                        }
+                    }
+                }
+            }
+            None => {
+                kinds = DEFAULT_KINDS.iter().map(|kind| kind.to_string()).collect();
+                statuses = DEFAULT_STATUSES.to_vec();
+            }
+        }
+
+        Ok(Some(RetryPolicy {
+            max: opt(retry, "max")?.unwrap_or(3),
+            backoff: Duration::from_millis(opt(retry, "backoff_ms")?.unwrap_or(500)),
+            factor: opt(retry, "factor")?.unwrap_or(2.0),
+            jitter: opt(retry, "jitter")?.unwrap_or(true),
+            max_delay: Duration::from_millis(opt(retry, "max_delay_ms")?.unwrap_or(60_000)),
+            kinds,
+            statuses,
+            non_idempotent: opt(retry, "non_idempotent")?.unwrap_or(false),
+        }))
+    }
+
+    pub fn retries_kind(&self, kind: &str) -> bool {
+        self.kinds.iter().any(|k| k == kind)
+    }
+
+    pub fn retries_status(&self, status: StatusCode) -> bool {
+        self.statuses.contains(&status.as_u16())
+    }
+
+    /// Returns how long to wait before the given retry, counting from 1,
+    /// where jitter picks a time between half of it and all of it so that
+    /// many scripts failing together don't all retry at once.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let delay = self.backoff.as_secs_f64() * self.factor.powi(retry as i32 - 1);
+        let delay = if self.jitter {
+            delay * (0.5 + rand::random::<f64>() / 2.0)
+        } else {
+            delay
+        };
+        Duration::try_from_secs_f64(delay)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+    }
+
+    /// Returns how long the server asked to wait with a `Retry-After` header
+    /// on a 429 or 503 response, given in seconds or as a date.
+    pub fn retry_after(&self, response: &Response) -> Option<Duration> {
+        if !matches!(
+            response.status(),
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+        ) {
+            return None;
+        }
+        let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+        let delay = match value.parse::<u64>() {
+            Ok(seconds) => Duration::from_secs(seconds),
+            Err(_) => httpdate::parse_http_date(value)
+                .ok()?
+                .duration_since(SystemTime::now())
+                .unwrap_or_default(),
+        };
+        Some(delay.min(self.max_delay))
+    }
+}
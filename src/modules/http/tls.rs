@@ -0,0 +1,132 @@
+use std::fs;
+
+use mlua::{Lua, Table, Value};
+use reqwest::{Certificate, blocking::ClientBuilder};
+
+use super::{super::fs::io_error, with_causes};
+
+/// The options a `tls` table can have. The ones that turn off checks are
+/// named after reqwest's `danger_` methods, so that they stand out.
+const OPTIONS: [&str; 3] = [
+    "ca_file",
+    "danger_accept_invalid_certs",
+    "danger_accept_invalid_hostnames",
+];
+
+/// The certificates of a `ca_file`, kept with the contents of the file so
+/// that two configurations using the same file compare equal.
+#[derive(Clone)]
+struct CaBundle {
+    path: String,
+    pem: Vec<u8>,
+    certs: Vec<Certificate>,
+}
+
+impl PartialEq for CaBundle {
+    fn eq(&self, other: &CaBundle) -> bool {
+        self.path == other.path && self.pem == other.pem
+    }
+}
+
+impl CaBundle {
+    /// Reads the certificates right away rather than when the client is
+    /// built, so that a bad file is reported where it's configured.
+    fn load(path: String) -> mlua::Result<CaBundle> {
+        let pem = fs::read(&path).map_err(|e| io_error("read", &path, e))?;
+        let invalid = |reason: String| {
+            mlua::Error::RuntimeError(format!("invalid tls.ca_file '{path}': {reason}"))
+        };
+        let certs = Certificate::from_pem_bundle(&pem).map_err(|e| invalid(with_causes(&e)))?;
+        if certs.is_empty() {
+            return Err(invalid("it has no PEM certificates".into()));
+        }
+        Ok(CaBundle { path, pem, certs })
+    }
+}
+
+/// The TLS options of `http:set_defaults` and of a request.
+#[derive(Clone, Default, PartialEq)]
+pub struct TlsConfig {
+    ca: Option<CaBundle>,
+    danger_accept_invalid_certs: bool,
+    danger_accept_invalid_hostnames: bool,
+}
+
+/// Reads one of the options that turn off checks, which has to be an actual
+/// boolean so that something like `1` or `"no"` can't turn it on.
+fn danger_option(tls: &Table, key: &str) -> mlua::Result<Option<bool>> {
+    match tls.get::<Value>(key)? {
+        Value::Nil => Ok(None),
+        Value::Boolean(enabled) => Ok(Some(enabled)),
+        other => Err(mlua::Error::RuntimeError(format!(
+            "invalid tls.{key}: expected true or false, got {}",
+            other.type_name()
+        ))),
+    }
+}
+
+impl TlsConfig {
+    /// Applies the options of a `tls` table, leaving the ones it doesn't
+    /// have unchanged.
+    pub fn apply(&mut self, tls: &Table) -> mlua::Result<()> {
+        for pair in tls.pairs::<Value, Value>() {
+            let key = pair?.0;
+            let known = match &key {
+                Value::String(key) => OPTIONS.contains(&&*key.to_str()?),
+                _ => false,
+            };
+            if !known {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "unknown tls option '{}': expected {}",
+                    key.to_string()?,
+                    OPTIONS.join(", ")
+                )));
+            }
+        }
+
+        match tls.get::<Value>("ca_file")? {
+            Value::Nil => {}
+            Value::Boolean(false) => self.ca = None,
+            Value::String(path) => self.ca = Some(CaBundle::load(path.to_str()?.to_string())?),
+            other => {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "invalid tls.ca_file: expected a path or false, got {}",
+                    other.type_name()
+                )));
+            }
+        }
+        if let Some(enabled) = danger_option(tls, "danger_accept_invalid_certs")? {
+            self.danger_accept_invalid_certs = enabled;
+        }
+        if let Some(enabled) = danger_option(tls, "danger_accept_invalid_hostnames")? {
+            self.danger_accept_invalid_hostnames = enabled;
+        }
+        Ok(())
+    }
+
+    pub fn configure(&self, mut builder: ClientBuilder) -> ClientBuilder {
+        if let Some(ca) = &self.ca {
+            for cert in &ca.certs {
+                builder = builder.add_root_certificate(cert.clone());
+            }
+        }
+        builder
+            .danger_accept_invalid_certs(self.danger_accept_invalid_certs)
+            .danger_accept_invalid_hostnames(self.danger_accept_invalid_hostnames)
+    }
+
+    /// Describes the options for `http:get_defaults`.
+    pub fn describe(&self, lua: &Lua) -> mlua::Result<Table> {
+        let table = lua.create_table()?;
+        table.set("ca_file", self.ca.as_ref().map(|ca| ca.path.as_str()))?;
+        table.set(
+            "danger_accept_invalid_certs",
+            self.danger_accept_invalid_certs,
+        )?;
+        table.set(
+            "danger_accept_invalid_hostnames",
+            self.danger_accept_invalid_hostnames,
+        )?;
+        Ok(table)
+    }
+}
@@ -0,0 +1,41 @@
+use mlua::{FromLua, Table};
+
+pub mod fs;
+pub mod http;
+pub mod regex;
+pub mod stringx;
+pub mod url;
+
+/// Reads an optional field from an options table that may itself be absent.
+pub fn opt<T: FromLua>(opts: Option<&Table>, key: &str) -> mlua::Result<Option<T>> {
+    match opts {
+        Some(opts) => opts.get(key),
+        None => Ok(None),
+    }
+}
+
+/// Helpers for the tests of each module, which run Lua code in a state like
+/// the one scripts run in.
+#[cfg(test)]
+pub mod testing {
+    use mlua::{FromLuaMulti, Lua};
+
+    pub fn lua() -> Lua {
+        crate::new_lua().expect("failed to create the Lua state")
+    }
+
+    /// Evaluates a chunk, panicking with the Lua error if it fails.
+    pub fn eval<T: FromLuaMulti>(lua: &Lua, code: &str) -> T {
+        lua.load(code)
+            .eval()
+            .unwrap_or_else(|e| panic!("Lua code failed: {e}\n{code}"))
+    }
+
+    /// Runs a chunk that should fail and returns the message of its error.
+    pub fn error(lua: &Lua, code: &str) -> String {
+        match lua.load(code).exec() {
+            Ok(()) => panic!("Lua code didn't fail:\n{code}"),
+            Err(e) => e.to_string(),
+        }
+    }
+}
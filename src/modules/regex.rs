@@ -0,0 +1,1098 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufRead, BufReader},
+    rc::Rc,
+};
+
+use ::regex::{Regex, RegexBuilder, RegexSet};
+use lru::LruCache;
+use mlua::{Function, IntoLuaMulti, Lua, Table, UserData, UserDataMethods, Value};
+
+use super::{fs::io_error, opt};
+
+mod bytes;
+
+pub struct RegexWrapper(Rc<Regex>);
+
+/// The number of compiled patterns kept by default.
+pub const DEFAULT_CACHE_CAPACITY: usize = 128;
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Flags {
+    case_insensitive: bool,
+    multi_line: bool,
+    dot_matches_new_line: bool,
+    ignore_whitespace: bool,
+    unicode: bool,
+    size_limit: Option<usize>,
+}
+
+impl Default for Flags {
+    fn default() -> Flags {
+        Flags {
+            case_insensitive: false,
+            multi_line: false,
+            dot_matches_new_line: false,
+            ignore_whitespace: false,
+            unicode: true,
+            size_limit: None,
+        }
+    }
+}
+
+/// Applies `Flags` to a `RegexBuilder` for either strings or bytes and
+/// builds it, since the two builders share their methods but not a trait.
+macro_rules! configure {
+    ($builder:expr, $flags:expr) => {{
+        let mut builder = $builder;
+        let flags = $flags;
+        builder
+            .case_insensitive(flags.case_insensitive)
+            .multi_line(flags.multi_line)
+            .dot_matches_new_line(flags.dot_matches_new_line)
+            .ignore_whitespace(flags.ignore_whitespace)
+            .unicode(flags.unicode);
+        if let Some(limit) = flags.size_limit {
+            builder.size_limit(limit);
+        }
+        builder
+            .build()
+            .map_err(|e| mlua::Error::RuntimeError(e.to_string()))
+    }};
+}
+
+impl Flags {
+    /// Reads flags given either as a string like `"imsx"` or as an options
+    /// table.
+    fn parse(flags: Option<Value>) -> mlua::Result<Flags> {
+        let mut parsed = Flags::default();
+        match flags {
+            None | Some(Value::Nil) => {}
+            Some(Value::String(flags)) => {
+                for flag in flags.to_str()?.chars() {
+                    match flag {
+                        'i' => parsed.case_insensitive = true,
+                        'm' => parsed.multi_line = true,
+                        's' => parsed.dot_matches_new_line = true,
+                        'x' => parsed.ignore_whitespace = true,
+                        'a' => parsed.unicode = false,
+                        _ => {
+                            return Err(mlua::Error::RuntimeError(format!(
+                                "invalid regex flag '{flag}' (expected i, m, s, x or a)"
+                            )));
+                        }
+                    }
+                }
+            }
+            Some(Value::Table(opts)) => {
+                let opts = Some(&opts);
+                let flag = |key| opt(opts, key).map(|value| value.unwrap_or(false));
+                parsed.case_insensitive = flag("case_insensitive")?;
+                parsed.multi_line = flag("multi_line")?;
+                parsed.dot_matches_new_line = flag("dot_matches_new_line")?;
+                parsed.ignore_whitespace = flag("ignore_whitespace")?;
+                parsed.unicode = opt(opts, "unicode")?.unwrap_or(true);
+                parsed.size_limit = opt(opts, "size_limit")?;
+            }
+            Some(other) => {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "invalid regex flags: expected a string or a table, got {}",
+                    other.type_name()
+                )));
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn build(&self, pattern: &str) -> mlua::Result<Regex> {
+        configure!(RegexBuilder::new(pattern), self)
+    }
+}
+
+/// Recently compiled patterns, kept in the Lua state's app data so scripts
+/// can call `regex(...)` in a loop without recompiling every time.
+pub struct RegexCache {
+    entries: LruCache<(String, Flags), Rc<Regex>>,
+    capacity: usize,
+    hits: u64,
+    misses: u64,
+}
+
+impl RegexCache {
+    pub fn new(capacity: usize) -> RegexCache {
+        RegexCache {
+            entries: LruCache::unbounded(),
+            capacity,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn get(&mut self, pattern: &str, flags: Flags) -> mlua::Result<Rc<Regex>> {
+        let key = (pattern.to_string(), flags);
+        if let Some(regex) = self.entries.get(&key) {
+            self.hits += 1;
+            return Ok(Rc::clone(regex));
+        }
+
+        self.misses += 1;
+        let regex = Rc::new(key.1.build(pattern)?);
+        if self.capacity > 0 {
+            if self.entries.len() >= self.capacity {
+                self.entries.pop_lru();
+            }
+            self.entries.put(key, Rc::clone(&regex));
+        }
+        Ok(regex)
+    }
+
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.entries.len() > capacity {
+            self.entries.pop_lru();
+        }
+    }
+}
+
+/// Compiles a pattern, reusing the cached regex when the same pattern was
+/// compiled with the same flags before. The regex is shared rather than
+/// cloned, since a clone has to rebuild its internal search caches.
+pub fn new(lua: &Lua, pattern: &str, flags: Option<Value>) -> mlua::Result<RegexWrapper> {
+    let flags = Flags::parse(flags)?;
+    match lua.app_data_mut::<RegexCache>() {
+        Some(mut cache) => cache.get(pattern, flags),
+        None => flags.build(pattern).map(Rc::new),
+    }
+    .map(RegexWrapper)
+}
+
+/// Several patterns matched together in a single pass over the text.
+pub struct RegexSetWrapper(RegexSet);
+
+fn new_set(patterns: &[String]) -> mlua::Result<RegexSetWrapper> {
+    RegexSet::new(patterns).map(RegexSetWrapper).map_err(|e| {
+        // the set's error doesn't say which pattern is broken, so find it
+        match patterns
+            .iter()
+            .position(|pattern| Regex::new(pattern).is_err())
+        {
+            Some(i) => {
+                mlua::Error::RuntimeError(format!("invalid pattern at index {}: {e}", i + 1))
+            }
+            None => mlua::Error::RuntimeError(e.to_string()),
+        }
+    })
+}
+
+impl UserData for RegexSetWrapper {
+    fn add_methods<'lua, M: UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("is_match", |_, this, text: String| {
+            Ok(this.0.is_match(&text))
+        });
+
+        methods.add_method("matches", |_, this, text: String| {
+            Ok(this
+                .0
+                .matches(&text)
+                .iter()
+                .map(|i| i + 1)
+                .collect::<Vec<_>>())
+        });
+
+        methods.add_method("len", |_, this, _: ()| Ok(this.0.len()));
+    }
+}
+
+/// Creates the `regex` global: called as `regex(pattern, [flags])` to compile
+/// a pattern, with helper functions as fields.
+pub fn module(lua: &Lua) -> mlua::Result<Table> {
+    let module = lua.create_table()?;
+    module.set(
+        "escape",
+        lua.create_function(|_, s: String| Ok(::regex::escape(&s)))?,
+    )?;
+    module.set(
+        "is_valid",
+        lua.create_function(|_, pattern: String| {
+            Ok(match Regex::new(&pattern) {
+                Ok(_) => (true, None),
+                Err(e) => (false, Some(e.to_string())),
+            })
+        })?,
+    )?;
+
+    module.set(
+        "bytes",
+        lua.create_function(|_, (pattern, flags): (String, Option<Value>)| {
+            let flags = Flags::parse(flags)?;
+            configure!(::regex::bytes::RegexBuilder::new(&pattern), &flags).map(bytes::BytesRegex)
+        })?,
+    )?;
+    module.set(
+        "set",
+        lua.create_function(|_, patterns: Vec<String>| new_set(&patterns))?,
+    )?;
+
+    module.set(
+        "cache_stats",
+        lua.create_function(|lua, ()| {
+            let stats = lua.create_table()?;
+            if let Some(cache) = lua.app_data_ref::<RegexCache>() {
+                stats.set("hits", cache.hits)?;
+                stats.set("misses", cache.misses)?;
+                stats.set("size", cache.entries.len())?;
+                stats.set("capacity", cache.capacity)?;
+            }
+            Ok(stats)
+        })?,
+    )?;
+    module.set(
+        "set_cache_capacity",
+        lua.create_function(|lua, capacity: usize| {
+            if let Some(mut cache) = lua.app_data_mut::<RegexCache>() {
+                cache.set_capacity(capacity);
+            }
+            Ok(())
+        })?,
+    )?;
+
+    let meta = lua.create_table()?;
+    meta.set(
+        "__call",
+        lua.create_function(|lua, (_, pattern, flags): (Table, String, Option<Value>)| {
+            new(lua, &pattern, flags)
+        })?,
+    )?;
+    module.set_metatable(Some(meta));
+    Ok(module)
+}
+
+impl RegexWrapper {
+    /// Splits on matches into at most `max` pieces the same way `Regex::splitn`
+    /// does, except that empty pieces can be dropped without counting
+    /// towards `max`.
+    fn split<'a>(&self, text: &'a str, max: usize, remove_empty: bool) -> Vec<&'a str> {
+        let mut parts = Vec::new();
+        let mut start = 0;
+        for m in self.0.find_iter(text) {
+            if parts.len() + 1 >= max {
+                break;
+            }
+            let piece = &text[start..m.start()];
+            start = m.end();
+            if !(remove_empty && piece.is_empty()) {
+                parts.push(piece);
+            }
+        }
+
+        // searching the whole text rather than the rest of it keeps anchors
+        // like `^` and `\b` from matching at the start of the rest
+        if remove_empty {
+            while let Some(m) = self
+                .0
+                .find_at(text, start)
+                .filter(|m| m.start() == start && !m.is_empty())
+            {
+                start = m.end();
+            }
+        }
+        let rest = &text[start..];
+        if !(remove_empty && rest.is_empty()) {
+            parts.push(rest);
+        }
+        parts
+    }
+
+    /// Splits into at most `n` pieces like `Regex::splitn`, where the last
+    /// piece holds the rest of the text, so `n = 2` splits at the first match
+    /// only. Asking for no pieces gives none.
+    fn splitn<'a>(&self, text: &'a str, n: usize) -> Vec<&'a str> {
+        if n == 0 {
+            return Vec::new();
+        }
+        self.split(text, n, false)
+    }
+
+    /// Where the first match ends, as soon as the regex engine knows that
+    /// there is one, without finding its groups. `Regex::shortest_match`
+    /// gives the 0-based offset just after the last byte, which is the
+    /// 1-based index of that byte like the `stop` of `find`, but it can stop
+    /// before the end `find` would give for the same match: `a+` ends after
+    /// the first `a`.
+    fn shortest_match(&self, text: &str) -> Option<usize> {
+        self.0.shortest_match(text)
+    }
+
+    /// Replaces the first `limit` matches (all of them for 0), expanding `$1`
+    /// and `$name` references, and returns the result with the number of
+    /// replacements.
+    fn replace(&self, text: &str, replacement: &str, limit: usize) -> (String, usize) {
+        let count = match limit {
+            0 => self.0.find_iter(text).count(),
+            limit => self.0.find_iter(text).take(limit).count(),
+        };
+        let replaced = self.0.replacen(text, limit, replacement).into_owned();
+        (replaced, count)
+    }
+}
+
+/// Resolves a 1-based byte index like `string.find`'s init argument, where
+/// negative values count from the end, to a 0-based byte offset.
+fn start_offset(len: usize, from: Option<i64>) -> Option<usize> {
+    let len = len as i64;
+    let from = match from.unwrap_or(1) {
+        from if from < 0 => (len + from + 1).max(1),
+        0 => 1,
+        from => from,
+    };
+    (from <= len + 1).then(|| from as usize - 1)
+}
+
+/// Like `string.find`, positions are byte indices, 1-based and inclusive, so
+/// they can be passed straight to `string.sub`.
+fn match_table(lua: &Lua, m: ::regex::Match) -> mlua::Result<Table> {
+    let table = lua.create_table()?;
+    table.set("start", m.start() + 1)?;
+    table.set("stop", m.end())?;
+    table.set("text", m.as_str())?;
+    Ok(table)
+}
+
+/// Builds the capture table of one match for every method that returns them:
+/// the whole match at index 1, group `n` at index `n + 1` as `captures` has
+/// always done, and named groups also under their name. Groups that didn't
+/// take part in the match are left out.
+fn captures_table(lua: &Lua, re: &Regex, caps: &::regex::Captures) -> mlua::Result<Table> {
+    let table = lua.create_table()?;
+    for (i, name) in re.capture_names().enumerate() {
+        if let Some(cap) = caps.get(i) {
+            table.set(i + 1, cap.as_str())?;
+            if let Some(name) = name {
+                table.set(name, cap.as_str())?;
+            }
+        }
+    }
+    // set last, so that it's the whole match even with a group named `match`
+    let whole = caps.get(0).expect("group 0 always participates");
+    table.set("match", whole.as_str())?;
+    Ok(table)
+}
+
+/// Replaces up to `limit` matches with what `f` returns for their captures,
+/// keeping matches for which it returns `nil` or `false`.
+fn replace_with(
+    lua: &Lua,
+    re: &Regex,
+    text: &str,
+    f: Function,
+    limit: usize,
+) -> mlua::Result<(String, usize)> {
+    let mut out = String::with_capacity(text.len());
+    let mut last = 0;
+    let mut count = 0;
+    for caps in re.captures_iter(text).take(limit) {
+        let m = caps.get(0).expect("group 0 always participates");
+        let replacement: Value = f.call(captures_table(lua, re, &caps)?).map_err(|e| {
+            mlua::Error::RuntimeError(format!(
+                "replacement function failed for the match at byte {}: {e}",
+                m.start() + 1
+            ))
+        })?;
+
+        out.push_str(&text[last..m.start()]);
+        last = m.end();
+        match replacement {
+            Value::Nil | Value::Boolean(false) => out.push_str(m.as_str()),
+            other => {
+                let type_name = other.type_name();
+                let replacement = lua.coerce_string(other)?.ok_or_else(|| {
+                    mlua::Error::RuntimeError(format!(
+                        "replacement function returned a {type_name} for the match at byte {}, expected a string",
+                        m.start() + 1
+                    ))
+                })?;
+                out.push_str(&replacement.to_str()?);
+                count += 1;
+            }
+        }
+    }
+    out.push_str(&text[last..]);
+    Ok((out, count))
+}
+
+/// How much of the start of a file is checked for NUL bytes to decide that
+/// it is binary, like grep does.
+const BINARY_CHECK_SIZE: usize = 8 * 1024;
+
+fn line_table(lua: &Lua, line_no: usize, line: &str) -> mlua::Result<Table> {
+    let table = lua.create_table()?;
+    table.set("line_no", line_no)?;
+    table.set("line", line)?;
+    Ok(table)
+}
+
+/// Streams the lines of a file, collecting the ones that match (or don't,
+/// with `invert`) along with their captures and surrounding lines.
+fn grep_file(lua: &Lua, re: &Regex, path: &str, opts: Option<&Table>) -> mlua::Result<Vec<Table>> {
+    let max_matches: Option<usize> = opt(opts, "max_matches")?;
+    let invert = opt(opts, "invert")?.unwrap_or(false);
+    let context: usize = opt(opts, "context")?.unwrap_or(0);
+    let binary = opt(opts, "binary")?.unwrap_or(false);
+
+    let file = File::open(path).map_err(|e| io_error("open", path, e))?;
+    let mut reader = BufReader::with_capacity(BINARY_CHECK_SIZE, file);
+    let start = reader.fill_buf().map_err(|e| io_error("read", path, e))?;
+    if !binary && start.contains(&0) {
+        return Err(mlua::Error::RuntimeError(format!(
+            "'{path}' looks like a binary file (set binary = true to search it anyway)"
+        )));
+    }
+
+    let mut matches = Vec::new();
+    // the lines before the current one, and matches still collecting lines after them
+    let mut before: VecDeque<(usize, String)> = VecDeque::with_capacity(context);
+    let mut pending_after: Vec<(Table, usize)> = Vec::new();
+    let mut buf = Vec::new();
+    let mut line_no = 0;
+    loop {
+        buf.clear();
+        if reader
+            .read_until(b'\n', &mut buf)
+            .map_err(|e| io_error("read", path, e))?
+            == 0
+        {
+            break;
+        }
+        line_no += 1;
+        let line = String::from_utf8_lossy(&buf);
+        let line = line.trim_end_matches('\n').trim_end_matches('\r');
+
+        for (table, remaining) in &mut pending_after {
+            let after: Table = table.get("after")?;
+            after.push(line_table(lua, line_no, line)?)?;
+            *remaining -= 1;
+        }
+        pending_after.retain(|(_, remaining)| *remaining > 0);
+
+        let done = max_matches.is_some_and(|max| matches.len() >= max);
+        if done && pending_after.is_empty() {
+            break;
+        }
+
+        let caps = re.captures(line);
+        if !done && caps.is_some() != invert {
+            let table = line_table(lua, line_no, line)?;
+            if let Some(caps) = &caps {
+                table.set("captures", captures_table(lua, re, caps)?)?;
+            }
+            if context > 0 {
+                let before_lines = lua.create_table()?;
+                for (no, text) in &before {
+                    before_lines.push(line_table(lua, *no, text)?)?;
+                }
+                table.set("before", before_lines)?;
+                table.set("after", lua.create_table()?)?;
+                pending_after.push((table.clone(), context));
+            }
+            matches.push(table);
+        }
+
+        if context > 0 {
+            if before.len() == context {
+                before.pop_front();
+            }
+            before.push_back((line_no, line.to_string()));
+        }
+    }
+    Ok(matches)
+}
+
+/// Returns an iterator over the captures of every match, searching for the
+/// next one only when asked. Like `Regex::captures_iter`, an empty match
+/// right where the previous match ended is skipped, so the search always
+/// makes progress.
+fn iter_captures(lua: &Lua, re: Rc<Regex>, text: String) -> mlua::Result<Function> {
+    let mut pos = 0;
+    let mut last_end = None;
+    lua.create_function_mut(move |lua, ()| {
+        while pos <= text.len() {
+            let Some(caps) = re.captures_at(&text, pos) else {
+                break;
+            };
+            let m = caps.get(0).expect("group 0 always participates");
+            if m.is_empty() && last_end == Some(m.end()) {
+                pos = text[m.end()..]
+                    .chars()
+                    .next()
+                    .map_or(text.len() + 1, |c| m.end() + c.len_utf8());
+                continue;
+            }
+            pos = m.end();
+            last_end = Some(m.end());
+            return captures_table(lua, &re, &caps).map(Some);
+        }
+        pos = text.len() + 1;
+        Ok(None)
+    })
+}
+
+impl UserData for RegexWrapper {
+    fn add_methods<'lua, M: UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("is_match", |_, this, text: String| {
+            Ok(this.0.is_match(&text))
+        });
+
+        methods.add_method("captures", |lua, this, text: String| {
+            match this.0.captures(&text) {
+                Some(caps) => captures_table(lua, &this.0, &caps),
+                None => lua.create_table(),
+            }
+        });
+
+        methods.add_method(
+            "replace",
+            |_, this, (text, replacement): (String, String)| {
+                Ok(this.replace(&text, &replacement, 1))
+            },
+        );
+
+        methods.add_method(
+            "replace_all",
+            |_, this, (text, replacement): (String, String)| {
+                Ok(this.replace(&text, &replacement, 0))
+            },
+        );
+
+        methods.add_method(
+            "replace_n",
+            |_, this, (text, replacement, n): (String, String, usize)| {
+                if n == 0 {
+                    return Ok((text, 0));
+                }
+                Ok(this.replace(&text, &replacement, n))
+            },
+        );
+
+        methods.add_method("find", |lua, this, (text, from): (String, Option<i64>)| {
+            let found =
+                start_offset(text.len(), from).and_then(|start| this.0.find_at(&text, start));
+            match found {
+                Some(m) => (m.start() + 1, m.end(), m.as_str()).into_lua_multi(lua),
+                None => Value::Nil.into_lua_multi(lua),
+            }
+        });
+
+        methods.add_method("find_all", |lua, this, text: String| {
+            this.0
+                .find_iter(&text)
+                .map(|m| match_table(lua, m))
+                .collect::<mlua::Result<Vec<_>>>()
+        });
+
+        methods.add_method("captures_all", |lua, this, text: String| {
+            this.0
+                .captures_iter(&text)
+                .map(|caps| captures_table(lua, &this.0, &caps))
+                .collect::<mlua::Result<Vec<_>>>()
+        });
+
+        methods.add_method("iter", |lua, this, text: String| {
+            iter_captures(lua, Rc::clone(&this.0), text)
+        });
+
+        methods.add_method("split", |_, this, (text, opts): (String, Option<Table>)| {
+            let max: Option<usize> = opt(opts.as_ref(), "max")?;
+            if max == Some(0) {
+                return Err(mlua::Error::RuntimeError("max must be at least 1".into()));
+            }
+            let remove_empty = opt(opts.as_ref(), "remove_empty")?.unwrap_or(false);
+            Ok(this
+                .split(&text, max.unwrap_or(usize::MAX), remove_empty)
+                .into_iter()
+                .map(str::to_string)
+                .collect::<Vec<_>>())
+        });
+
+        methods.add_method("capture_names", |_, this, _: ()| {
+            Ok(this
+                .0
+                .capture_names()
+                .flatten()
+                .map(str::to_string)
+                .collect::<Vec<_>>())
+        });
+
+        methods.add_method(
+            "replace_with",
+            |lua, this, (text, f, max): (String, Function, Option<usize>)| {
+                replace_with(lua, &this.0, &text, f, max.unwrap_or(usize::MAX))
+            },
+        );
+
+        methods.add_method(
+            "grep_file",
+            |lua, this, (path, opts): (String, Option<Table>)| {
+                grep_file(lua, &this.0, &path, opts.as_ref())
+            },
+        );
+
+        methods.add_method("splitn", |_, this, (text, n): (String, usize)| {
+            Ok(this
+                .splitn(&text, n)
+                .into_iter()
+                .map(str::to_string)
+                .collect::<Vec<_>>())
+        });
+
+        methods.add_method("shortest_match", |_, this, text: String| {
+            Ok(this.shortest_match(&text))
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{error, eval, lua};
+
+    #[test]
+    fn replace_with_named_groups_round_trips() {
+        let lua = lua();
+        let (swapped, count, back): (String, usize, String) = eval(
+            &lua,
+            r#"local iso = regex([[(?P<year>\d{4})-(?P<month>\d{2})-(?P<day>\d{2})]])
+            local european = regex([[(?P<day>\d{2})/(?P<month>\d{2})/(?P<year>\d{4})]])
+            local text = "from 2024-01-31 to 2024-12-01"
+            local swapped, count = iso:replace_all(text, "$day/$month/$year")
+            return swapped, count, (european:replace_all(swapped, "${year}-${month}-${day}"))"#,
+        );
+        assert_eq!(swapped, "from 31/01/2024 to 01/12/2024");
+        assert_eq!(count, 2);
+        assert_eq!(back, "from 2024-01-31 to 2024-12-01");
+    }
+
+    #[test]
+    fn replace_counts_and_literal_dollars() {
+        let lua = lua();
+        let results: (String, usize, String, usize, String, usize, String) = eval(
+            &lua,
+            r#"local re = regex([[(\d+)]])
+            local first, n1 = re:replace("1 2 3", "<$1>")
+            local two, n2 = re:replace_n("1 2 3", "<$1>", 2)
+            local none, n0 = re:replace_n("1 2 3", "x", 0)
+            return first, n1, two, n2, none, n0, (re:replace_all("5 and 10", "$$$1.00"))"#,
+        );
+        assert_eq!(
+            results,
+            (
+                "<1> 2 3".into(),
+                1,
+                "<1> <2> 3".into(),
+                2,
+                "1 2 3".into(),
+                0,
+                "$5.00 and $10.00".into()
+            )
+        );
+
+        // `$1a` refers to a group called `1a`, which doesn't exist
+        let braces: (String, String) = eval(
+            &lua,
+            r#"local re = regex([[(\d)]])
+            return (re:replace("7", "$1a")), (re:replace("7", "${1}a"))"#,
+        );
+        assert_eq!(braces, (String::new(), "7a".into()));
+    }
+
+    #[test]
+    fn invalid_patterns_are_errors() {
+        let lua = lua();
+        let message = error(&lua, "regex('(unclosed')");
+        assert!(message.contains("unclosed"), "{message}");
+    }
+
+    #[test]
+    fn find_positions_are_byte_indices_for_string_sub() {
+        let lua = lua();
+        let (start, stop, text, sliced): (usize, usize, String, String) = eval(
+            &lua,
+            r#"local text = "naïve café"
+            local start, stop, matched = regex([[caf.]]):find(text)
+            return start, stop, matched, text:sub(start, stop)"#,
+        );
+        // "ï" and "é" take two bytes each
+        assert_eq!((start, stop), (8, 12));
+        assert_eq!(text, "café");
+        assert_eq!(sliced, "café");
+    }
+
+    #[test]
+    fn find_from_an_offset_and_find_all() {
+        let lua = lua();
+        let found: (usize, usize, usize, Option<usize>, usize) = eval(
+            &lua,
+            r#"local re = regex("é")
+            local text = "éaé"
+            local all = re:find_all(text)
+            return (re:find(text, 2)), (re:find(text, -2)), all[2].stop, (re:find(text, 7)), #all"#,
+        );
+        assert_eq!(found, (4, 4, 5, None, 2));
+
+        let slices: String = eval(
+            &lua,
+            r#"local text = "日本 and 中国"
+            local out = {}
+            for i, m in ipairs(regex([[\p{Han}+]]):find_all(text)) do
+                out[i] = m.start .. "-" .. m.stop .. "=" .. text:sub(m.start, m.stop)
+            end
+            return table.concat(out, ",")"#,
+        );
+        assert_eq!(slices, "1-6=日本,12-17=中国");
+    }
+
+    #[test]
+    fn every_capture_table_has_the_same_layout() {
+        let lua = lua();
+        let layouts: Vec<String> = eval(
+            &lua,
+            r#"local re = regex([[(?P<key>\w+)=(\d+)?]])
+            local text = "a=1 b="
+            local function layout(caps)
+                return table.concat({tostring(caps[0]), caps[1], caps[2], tostring(caps[3]), caps.key,
+                    caps.match}, ",")
+            end
+            local layouts = {layout(re:captures(text)), layout(re:captures_all(text)[1])}
+            for caps in re:iter(text) do layouts[#layouts + 1] = layout(caps) end
+            re:replace_with(text, function(caps) layouts[#layouts + 1] = layout(caps) end, 1)
+            return layouts"#,
+        );
+        let first = "nil,a=1,a,1,a,a=1";
+        assert_eq!(layouts, [first, first, first, "nil,b=,b,nil,b,b=", first]);
+
+        let (named, bytes): (String, String) = eval(
+            &lua,
+            r#"local caps = regex([[(?P<match>\d+)!]]):captures("x 42!")
+            local bytes = regex.bytes([[(?-u)(\xff)(?P<rest>\w+)]]):captures("-\xffabc")
+            return caps.match .. "," .. caps[2],
+                tostring(bytes.match == "\xffabc" and bytes[1] == bytes.match) .. "," .. bytes.rest"#,
+        );
+        assert_eq!(named, "42!,42");
+        assert_eq!(bytes, "true,abc");
+    }
+
+    #[test]
+    fn grep_file_captures_have_the_same_layout() {
+        let lua = lua();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        std::fs::write(&path, "skip\nlevel=warn\n").unwrap();
+        lua.globals().set("path", path.to_str().unwrap()).unwrap();
+        let (whole, level, line_no): (String, String, usize) = eval(
+            &lua,
+            r#"local found = regex([[level=(?P<level>\w+)]]):grep_file(path)[1]
+            assert(found.captures.match == found.captures[1])
+            return found.captures[1], found.captures[2], found.line_no"#,
+        );
+        assert_eq!(
+            (whole.as_str(), level.as_str(), line_no),
+            ("level=warn", "warn", 2)
+        );
+    }
+
+    #[test]
+    fn groups_that_do_not_take_part_are_nil() {
+        let lua = lua();
+        let (missing, named, empty): (bool, bool, String) = eval(
+            &lua,
+            r#"local caps = regex([[(a)|(?P<b>b)|(c*)]]):captures("a")
+            local empty = regex([[x(y*)]]):captures("x")
+            return caps[3] == nil, caps.b == nil, empty[2]"#,
+        );
+        // a group that matched nothing is still there, as an empty string
+        assert!(missing && named);
+        assert_eq!(empty, "");
+    }
+
+    /// Runs `re:split` and joins the pieces with `|`.
+    fn split(lua: &mlua::Lua, pattern: &str, text: &str, opts: &str) -> String {
+        lua.load(format!(
+            "local re, text = ...
+            return table.concat(re:split(text, {opts}), '|')"
+        ))
+        .call((super::new(lua, pattern, None).unwrap(), text))
+        .unwrap()
+    }
+
+    #[test]
+    fn split_on_alternation_and_at_the_edges() {
+        let lua = lua();
+        for (pattern, text, opts, expected) in [
+            ("[,;]+|\\s*\\|\\s*", "a,b;;c | d", "nil", "a|b|c|d"),
+            (",", ",a,b,", "nil", "|a|b|"),
+            (",", ",a,,b,", "{remove_empty = true}", "a|b"),
+            (",|;", ";a,b;c,", "{max = 3}", "|a|b;c,"),
+            (",|;", ";,a,,b;c", "{max = 2, remove_empty = true}", "a|b;c"),
+            // the rest starts at "xcd", but `^x` only matches at the start
+            // of the text
+            (
+                ",|^x",
+                "ab,,xcd",
+                "{max = 2, remove_empty = true}",
+                "ab|xcd",
+            ),
+            (
+                ",|\\bx",
+                "ab,,xcd",
+                "{max = 2, remove_empty = true}",
+                "ab|cd",
+            ),
+            (",", "", "nil", ""),
+        ] {
+            assert_eq!(
+                split(&lua, pattern, text, opts),
+                expected,
+                "/{pattern}/ on {text:?} with {opts}"
+            );
+        }
+    }
+
+    #[test]
+    fn split_on_empty_matches_like_rust() {
+        let lua = lua();
+        for (pattern, text) in [
+            ("", "rust"),
+            ("x*", "abc"),
+            ("\\b", "a bc"),
+            ("a*", "baaac"),
+        ] {
+            let expected: Vec<&str> = ::regex::Regex::new(pattern).unwrap().split(text).collect();
+            assert_eq!(
+                split(&lua, pattern, text, "nil"),
+                expected.join("|"),
+                "/{pattern}/ on {text:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn captures_keeps_its_numeric_indices() {
+        let lua = lua();
+        let (whole, first, second, count, empty): (String, String, String, usize, usize) = eval(
+            &lua,
+            r#"local caps = regex([[(\w+)@(\w+)]]):captures("mail bob@example now")
+            local count = 0
+            for _ in pairs(caps) do count = count + 1 end
+            return caps[1], caps[2], caps[3], count, #regex("z"):captures("abc")"#,
+        );
+        // without named groups, only the numeric indices and `match` are set
+        assert_eq!(
+            (
+                whole.as_str(),
+                first.as_str(),
+                second.as_str(),
+                count,
+                empty
+            ),
+            ("bob@example", "bob", "example", 4, 0)
+        );
+    }
+
+    #[test]
+    fn named_groups_are_also_under_their_names() {
+        let lua = lua();
+        let (year, by_index, day, names): (String, String, Option<String>, Vec<String>) = eval(
+            &lua,
+            r#"local re = regex([[(?P<year>\d{4})-(\d{2})(?:-(?P<day>\d{2}))?]])
+            local caps = re:captures("2024-06")
+            return caps.year, caps[2], caps.day, re:capture_names()"#,
+        );
+        assert_eq!(year, "2024");
+        assert_eq!(by_index, "2024");
+        assert_eq!(day, None);
+        assert_eq!(names, ["year", "day"]);
+    }
+
+    #[test]
+    fn set_matches_every_pattern_in_one_pass() {
+        let lua = lua();
+        // 40 patterns, checked against each line with one call to the set
+        // and with one call per pattern, which have to agree
+        let (agree, matched, len): (bool, Vec<usize>, usize) = eval(
+            &lua,
+            r#"local patterns = {}
+            for i = 1, 40 do patterns[i] = "code=" .. i .. "\\b" end
+            patterns[41] = "^ERROR"
+            local set = regex.set(patterns)
+            local compiled = {}
+            for i, p in ipairs(patterns) do compiled[i] = regex(p) end
+
+            local agree = true
+            for n = 1, 200 do
+                local line = (n % 3 == 0 and "ERROR " or "info ") .. "code=" .. n % 50
+                local one_pass = set:matches(line)
+                local each = {}
+                for i, re in ipairs(compiled) do
+                    if re:is_match(line) then each[#each + 1] = i end
+                end
+                agree = agree and table.concat(one_pass, ",") == table.concat(each, ",")
+                    and set:is_match(line) == (#each > 0)
+            end
+            return agree, set:matches("ERROR code=7 code=12"), set:len()"#,
+        );
+        assert!(agree);
+        assert_eq!(matched, [7, 12, 41]);
+        assert_eq!(len, 41);
+    }
+
+    #[test]
+    fn set_names_the_invalid_pattern() {
+        let lua = lua();
+        let message = error(&lua, r#"regex.set({"ok", "fine", "(broken"})"#);
+        assert!(message.contains("invalid pattern at index 3"), "{message}");
+        let (matched, empty): (bool, usize) = eval(
+            &lua,
+            r#"local set = regex.set({})
+            return set:is_match("anything"), #set:matches("anything")"#,
+        );
+        assert_eq!((matched, empty), (false, 0));
+    }
+
+    #[test]
+    fn constructing_the_same_pattern_in_a_loop_hits_the_cache() {
+        let lua = lua();
+        let start = std::time::Instant::now();
+        let (hits, misses, size, matched): (u64, u64, usize, usize) = eval(
+            &lua,
+            r#"local matched = 0
+            for i = 1, 10000 do
+                if regex([[^(\w+)@(\w+)\.com$]], "i"):is_match("user" .. i .. "@EXAMPLE.com") then
+                    matched = matched + 1
+                end
+            end
+            local stats = regex.cache_stats()
+            return stats.hits, stats.misses, stats.size, matched"#,
+        );
+        assert_eq!((hits, misses, size, matched), (9999, 1, 1, 10000));
+        // only the first iteration compiles the pattern
+        assert!(
+            start.elapsed() < std::time::Duration::from_secs(1),
+            "{:?}",
+            start.elapsed()
+        );
+    }
+
+    #[test]
+    fn cache_keys_include_the_flags_and_evict_the_oldest() {
+        let lua = lua();
+        let stats: (u64, u64, usize, usize, bool) = eval(
+            &lua,
+            r#"regex.set_cache_capacity(2)
+            regex("a"); regex("a", "i"); regex("a", {case_insensitive = true})
+            regex("b") -- evicts "a" without flags
+            regex("a")
+            local stats = regex.cache_stats()
+            regex.set_cache_capacity(0)
+            return stats.hits, stats.misses, stats.size, regex.cache_stats().size,
+                regex("A", "i"):is_match("a")"#,
+        );
+        assert_eq!(stats, (1, 4, 2, 0, true));
+    }
+
+    #[test]
+    fn bytes_regex_round_trips_invalid_utf8_and_nuls() {
+        let lua = lua();
+        let data = lua
+            .create_string(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR\xff\xfe\0end")
+            .unwrap();
+        lua.globals().set("data", data).unwrap();
+        let (is_png, start, stop, found, header, replaced, count): (
+            bool,
+            usize,
+            usize,
+            mlua::String,
+            mlua::String,
+            mlua::String,
+            usize,
+        ) = eval(
+            &lua,
+            r#"local png = regex.bytes([[^\x89PNG]], "a")
+            local start, stop, found = regex.bytes([[\xff\xfe\x00]], "a"):find(data)
+            local caps = regex.bytes([[(?-u)\x00{3}(?P<len>.)(IHDR)]]):captures(data)
+            local replaced, count = regex.bytes([[\x00]], "a"):replace_all(data, "<NUL>")
+            return png:is_match(data), start, stop, found, caps.len .. caps[3], replaced, count"#,
+        );
+        assert!(is_png);
+        assert_eq!((start, stop), (17, 19));
+        assert_eq!(&*found.as_bytes(), b"\xff\xfe\0");
+        assert_eq!(&*header.as_bytes(), b"\rIHDR");
+        assert_eq!(
+            &*replaced.as_bytes(),
+            b"\x89PNG\r\n\x1a\n<NUL><NUL><NUL>\rIHDR\xff\xfe<NUL>end"
+        );
+        assert_eq!(count, 4);
+    }
+
+    #[test]
+    fn bytes_regex_unicode_flag() {
+        let lua = lua();
+        let latin1 = lua.create_string(b"caf\xe9 ok").unwrap();
+        lua.globals().set("latin1", latin1).unwrap();
+        let (unicode_word, ascii_word, byte_class): (bool, bool, bool) = eval(
+            &lua,
+            r#"return regex.bytes([[\w]]):is_match("é"), regex.bytes([[\w]], "a"):is_match("é"),
+                regex.bytes([[^caf[\xe0-\xff] ok$]], {unicode = false}):is_match(latin1)"#,
+        );
+        assert_eq!((unicode_word, ascii_word, byte_class), (true, false, true));
+    }
+
+    #[test]
+    fn splitn_with_the_delimiter_inside_capture_groups() {
+        let lua = lua();
+        for (pattern, text, n, expected) in [
+            (r"\s*=\s*", "key = value = more", 2, "key|value = more"),
+            // the groups hold the delimiter, but only the whole match splits
+            (
+                r"(\s*)(=)(\s*)",
+                "key = value = more",
+                2,
+                "key|value = more",
+            ),
+            (r"(=)+", "a==b=c", 3, "a|b|c"),
+            (r"(?P<sep>[,;])", "a,b;c", 2, "a|b;c"),
+            (r",", "a,b,c", 1, "a,b,c"),
+            (r",", "a,b,c", 10, "a|b|c"),
+            (r",", "a,b,c", 0, ""),
+        ] {
+            let pieces: String = lua
+                .load("local re, text, n = ... return table.concat(re:splitn(text, n), '|')")
+                .call((super::new(&lua, pattern, None).unwrap(), text, n))
+                .unwrap();
+            assert_eq!(pieces, expected, "/{pattern}/ on {text:?} with n = {n}");
+        }
+    }
+
+    #[test]
+    fn shortest_match_ends_no_later_than_find() {
+        let lua = lua();
+        let ends: (usize, usize, usize, usize, Option<usize>) = eval(
+            &lua,
+            r#"local re = regex("a+")
+            local _, stop = re:find("xxaaa")
+            local grouped = regex([[(k)=(v+)]])
+            local _, grouped_stop = grouped:find("k=vvv")
+            return re:shortest_match("xxaaa"), stop, grouped:shortest_match("k=vvv"), grouped_stop,
+                re:shortest_match("xyz")"#,
+        );
+        // the 1-based index of the match's last byte, like find's stop, but
+        // as soon as a match is certain
+        assert_eq!(ends, (3, 5, 3, 5, None));
+
+        let same: (usize, usize) = eval(
+            &lua,
+            r#"local re = regex("é")
+            local _, stop = re:find("aé")
+            return re:shortest_match("aé"), stop"#,
+        );
+        assert_eq!(same, (3, 3));
+    }
+}
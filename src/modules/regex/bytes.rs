@@ -0,0 +1,68 @@
+use ::regex::bytes::{Captures, Regex};
+use mlua::{IntoLuaMulti, Lua, Table, UserData, UserDataMethods, Value};
+
+use super::start_offset;
+
+/// A regex from `regex.bytes` that matches Lua strings as raw bytes, so they
+/// don't have to be valid UTF-8.
+pub struct BytesRegex(pub Regex);
+
+impl BytesRegex {
+    /// Builds a table laid out like the one from the string regex's
+    /// `captures`, with the whole match at index 1.
+    fn captures_table(&self, lua: &Lua, caps: &Captures) -> mlua::Result<Table> {
+        let table = lua.create_table()?;
+        for (i, name) in self.0.capture_names().enumerate() {
+            if let Some(cap) = caps.get(i) {
+                let text = lua.create_string(cap.as_bytes())?;
+                table.set(i + 1, &text)?;
+                if let Some(name) = name {
+                    table.set(name, text)?;
+                }
+            }
+        }
+        // set last, so that it's the whole match even with a group named `match`
+        let whole = caps.get(0).expect("group 0 always participates");
+        table.set("match", lua.create_string(whole.as_bytes())?)?;
+        Ok(table)
+    }
+}
+
+impl UserData for BytesRegex {
+    fn add_methods<'lua, M: UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("is_match", |_, this, text: mlua::String| {
+            Ok(this.0.is_match(&text.as_bytes()))
+        });
+
+        methods.add_method(
+            "find",
+            |lua, this, (text, from): (mlua::String, Option<i64>)| {
+                let text = text.as_bytes();
+                let found =
+                    start_offset(text.len(), from).and_then(|start| this.0.find_at(&text, start));
+                match found {
+                    Some(m) => (m.start() + 1, m.end(), lua.create_string(m.as_bytes())?)
+                        .into_lua_multi(lua),
+                    None => Value::Nil.into_lua_multi(lua),
+                }
+            },
+        );
+
+        methods.add_method("captures", |lua, this, text: mlua::String| {
+            match this.0.captures(&text.as_bytes()) {
+                Some(caps) => this.captures_table(lua, &caps),
+                None => lua.create_table(),
+            }
+        });
+
+        methods.add_method(
+            "replace_all",
+            |lua, this, (text, replacement): (mlua::String, mlua::String)| {
+                let text = text.as_bytes();
+                let count = this.0.find_iter(&text).count();
+                let replaced = this.0.replace_all(&text, &*replacement.as_bytes());
+                Ok((lua.create_string(&replaced)?, count))
+            },
+        );
+    }
+}
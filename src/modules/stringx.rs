@@ -0,0 +1,2514 @@
+use std::{borrow::Cow, cmp::Ordering};
+
+use caseless::default_case_fold_str;
+use deunicode::deunicode;
+use fuzzy_matcher::{FuzzyMatcher, skim::SkimMatcherV2};
+use heck::{ToKebabCase, ToLowerCamelCase, ToSnakeCase, ToUpperCamelCase};
+use mlua::{Function, Lua, Table, UserData, UserDataMethods, Value};
+use rand::{Rng, TryRngCore, rngs::OsRng};
+use similar::{ChangeTag, TextDiff};
+use textwrap::{WordSeparator, WrapAlgorithm, core::Word};
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+use super::opt;
+
+pub struct StringUtils;
+
+/// Converts strings, numbers and booleans to a string like `tostring` does,
+/// returning `None` for anything else.
+fn to_text(lua: &Lua, value: Value) -> mlua::Result<Option<String>> {
+    match value {
+        Value::String(s) => Ok(Some(s.to_str()?.to_string())),
+        Value::Integer(_) | Value::Number(_) => {
+            Ok(lua.coerce_string(value)?.map(|s| s.to_string_lossy()))
+        }
+        Value::Boolean(b) => Ok(Some(b.to_string())),
+        _ => Ok(None),
+    }
+}
+
+/// Converts an element of a table being joined, naming its index on error.
+fn join_part(lua: &Lua, index: usize, value: Value) -> mlua::Result<String> {
+    let type_name = value.type_name();
+    to_text(lua, value)?.ok_or_else(|| {
+        mlua::Error::RuntimeError(format!("cannot join a {type_name} value at index {index}"))
+    })
+}
+
+/// Looks up a dotted name like `user.name` through nested tables.
+fn lookup_path(values: &Table, name: &str) -> mlua::Result<Value> {
+    let mut current = Value::Table(values.clone());
+    for key in name.split('.') {
+        current = match current {
+            Value::Table(table) => table.get(key)?,
+            _ => return Ok(Value::Nil),
+        };
+    }
+    Ok(current)
+}
+
+/// Expands `${name}` and `${name:default}` placeholders in a single pass, so
+/// placeholders inside substituted values are left alone. `$$` is a literal
+/// dollar sign.
+fn interp(lua: &Lua, template: &str, values: &Table) -> mlua::Result<String> {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(pos) = rest.find('$') {
+        out.push_str(&rest[..pos]);
+        let offset = template.len() - rest.len() + pos + 1;
+        let after = &rest[pos + 1..];
+
+        if let Some(after) = after.strip_prefix('$') {
+            out.push('$');
+            rest = after;
+        } else if let Some(after) = after.strip_prefix('{') {
+            let end = after.find('}').ok_or_else(|| {
+                mlua::Error::RuntimeError(format!("unterminated placeholder at byte {offset}"))
+            })?;
+            let placeholder = &after[..end];
+            let (name, default) = match placeholder.split_once(':') {
+                Some((name, default)) => (name, Some(default)),
+                None => (placeholder, None),
+            };
+
+            let value = lookup_path(values, name)?;
+            if value.is_nil() {
+                let default = default.ok_or_else(|| {
+                    mlua::Error::RuntimeError(format!(
+                        "no value for placeholder '${{{placeholder}}}' at byte {offset}"
+                    ))
+                })?;
+                out.push_str(default);
+            } else {
+                let type_name = value.type_name();
+                let text = to_text(lua, value)?.ok_or_else(|| {
+                    mlua::Error::RuntimeError(format!(
+                        "cannot substitute a {type_name} value for placeholder '${{{placeholder}}}' at byte {offset}"
+                    ))
+                })?;
+                out.push_str(&text);
+            }
+            rest = &after[end + 1..];
+        } else {
+            out.push('$');
+            rest = after;
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Applies full Unicode case folding when comparing case-insensitively, so
+/// that e.g. "STRASSE" and "straße" compare equal.
+fn fold(s: &str, case_insensitive: bool) -> Cow<'_, str> {
+    if case_insensitive {
+        Cow::Owned(default_case_fold_str(s))
+    } else {
+        Cow::Borrowed(s)
+    }
+}
+
+fn join(lua: &Lua, parts: Table, sep: &str) -> mlua::Result<String> {
+    // `#parts` is unreliable with holes, so find the highest index instead
+    let mut len = 0;
+    for pair in parts.pairs::<Value, Value>() {
+        if let (Value::Integer(i), _) = pair? {
+            len = len.max(i.max(0) as usize);
+        }
+    }
+
+    let mut joined = Vec::with_capacity(len);
+    for i in 1..=len {
+        joined.push(join_part(lua, i, parts.raw_get(i)?)?);
+    }
+    Ok(joined.join(sep))
+}
+
+/// Removes ANSI escape sequences: CSI sequences like the `ESC [ 31 m` color
+/// codes, OSC sequences like hyperlinks, and two-character escapes.
+fn strip_ansi(s: &str) -> Cow<'_, str> {
+    if !s.contains('\x1b') {
+        return Cow::Borrowed(s);
+    }
+
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('[') => {
+                // parameters and intermediates run up to a final byte in @..~
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            Some(']') => {
+                // terminated by BEL or by ST (ESC \)
+                while let Some(c) = chars.next() {
+                    if c == '\x07' || (c == '\x1b' && chars.next() == Some('\\')) {
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Cow::Owned(out)
+}
+
+/// The display width of a single character, where control characters take
+/// no space.
+fn char_width(c: char) -> usize {
+    c.width().unwrap_or(0)
+}
+
+/// The number of terminal columns `s` takes up, ignoring ANSI escape
+/// sequences and control characters. This is what every width-based helper
+/// measures with.
+fn display_width(s: &str) -> usize {
+    let visible = strip_ansi(s);
+    if visible.chars().any(char::is_control) {
+        visible
+            .chars()
+            .filter(|c| !c.is_control())
+            .collect::<String>()
+            .width()
+    } else {
+        visible.width()
+    }
+}
+
+/// Repeats `fill` to cover exactly `width` display columns, topping up with
+/// spaces when a wide fill character wouldn't fit in the last column.
+fn fill_columns(fill: &str, width: usize) -> String {
+    let mut out = String::new();
+    let mut used = 0;
+    // zero-width characters would make no progress on their own
+    for c in fill.chars().filter(|&c| char_width(c) > 0).cycle() {
+        if used + char_width(c) > width {
+            break;
+        }
+        out.push(c);
+        used += char_width(c);
+    }
+    out.extend(std::iter::repeat_n(' ', width - used));
+    out
+}
+
+/// Returns how many columns to add on the left and right of `s` to reach
+/// `width`, or `None` if it's already at least that wide.
+fn padding(s: &str, width: usize, fill: &str) -> mlua::Result<Option<usize>> {
+    if fill.chars().all(|c| char_width(c) == 0) {
+        return Err(mlua::Error::RuntimeError(
+            "fill string must be at least one column wide".into(),
+        ));
+    }
+    Ok(width.checked_sub(display_width(s)).filter(|&n| n > 0))
+}
+
+fn pad(
+    s: String,
+    width: usize,
+    fill: Option<String>,
+    left_share: fn(usize) -> usize,
+) -> mlua::Result<String> {
+    let fill = fill.as_deref().unwrap_or(" ");
+    let Some(total) = padding(&s, width, fill)? else {
+        return Ok(s);
+    };
+    let left = left_share(total);
+    Ok(format!(
+        "{}{s}{}",
+        fill_columns(fill, left),
+        fill_columns(fill, total - left)
+    ))
+}
+
+/// Replaces up to `count` literal occurrences of `from`, returning the new
+/// string and how many were replaced.
+fn replace(s: &str, from: &str, to: &str, count: Option<usize>) -> mlua::Result<(String, usize)> {
+    if from.is_empty() {
+        return Err(mlua::Error::RuntimeError(
+            "cannot replace an empty string".into(),
+        ));
+    }
+
+    let limit = count.unwrap_or(usize::MAX);
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    let mut replaced = 0;
+    while replaced < limit {
+        let Some(pos) = rest.find(from) else { break };
+        out.push_str(&rest[..pos]);
+        out.push_str(to);
+        rest = &rest[pos + from.len()..];
+        replaced += 1;
+    }
+    out.push_str(rest);
+    Ok((out, replaced))
+}
+
+/// Uppercases the first grapheme that starts with a letter, leaving leading
+/// punctuation and the rest of the string as they are.
+fn capitalize(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut graphemes = s.grapheme_indices(true);
+    for (i, grapheme) in graphemes.by_ref() {
+        if grapheme.starts_with(char::is_alphabetic) {
+            out.push_str(&grapheme.to_uppercase());
+            out.push_str(&s[i + grapheme.len()..]);
+            return out;
+        }
+        out.push_str(grapheme);
+    }
+    out
+}
+
+fn title(s: &str) -> String {
+    s.split_word_bounds().map(capitalize).collect()
+}
+
+/// Splits on a literal delimiter into at most `max` pieces, the last of
+/// which holds the rest of the string.
+fn split<'a>(s: &'a str, pat: &str, max: usize, remove_empty: bool) -> Vec<&'a str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    for (pos, _) in s.match_indices(pat) {
+        if parts.len() + 1 >= max {
+            break;
+        }
+        let piece = &s[start..pos];
+        start = pos + pat.len();
+        if !(remove_empty && piece.is_empty()) {
+            parts.push(piece);
+        }
+    }
+
+    let mut rest = &s[start..];
+    if remove_empty && !pat.is_empty() {
+        while let Some(stripped) = rest.strip_prefix(pat) {
+            rest = stripped;
+        }
+    }
+    if !(remove_empty && rest.is_empty()) {
+        parts.push(rest);
+    }
+    parts
+}
+
+/// Splits on runs of whitespace into at most `max` pieces, ignoring leading
+/// and trailing whitespace, so an empty or blank string gives no pieces.
+fn split_whitespace(s: &str, max: usize) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut rest = s.trim_start();
+    while !rest.is_empty() {
+        if parts.len() + 1 >= max {
+            parts.push(rest.trim_end());
+            break;
+        }
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        parts.push(&rest[..end]);
+        rest = rest[end..].trim_start();
+    }
+    parts
+}
+
+/// Edit distance over Unicode scalar values, keeping only one row of the
+/// table for the shorter string.
+fn levenshtein(a: &str, b: &str) -> usize {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    let (long, short) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+
+    let mut row: Vec<usize> = (0..=short.len()).collect();
+    for (i, lc) in long.iter().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, sc) in short.iter().enumerate() {
+            let substitution = diagonal + usize::from(lc != sc);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[short.len()]
+}
+
+fn similarity(a: &str, b: &str) -> f64 {
+    let len = a.chars().count().max(b.chars().count());
+    if len == 0 {
+        return 1.0;
+    }
+    1.0 - levenshtein(a, b) as f64 / len as f64
+}
+
+/// Returns the byte length of the longest common prefix of `items`, which
+/// always falls on a character boundary.
+fn common_prefix_len(items: &[String]) -> usize {
+    let Some((first, rest)) = items.split_first() else {
+        return 0;
+    };
+    rest.iter().fold(first.len(), |len, item| {
+        first[..len]
+            .char_indices()
+            .zip(item.chars())
+            .find(|((_, a), b)| a != b)
+            .map_or(len.min(item.len()), |((i, _), _)| i)
+    })
+}
+
+fn common_suffix_len(items: &[String]) -> usize {
+    let Some((first, rest)) = items.split_first() else {
+        return 0;
+    };
+    rest.iter().fold(first.len(), |len, item| {
+        let tail = &first[first.len() - len..];
+        tail.char_indices()
+            .rev()
+            .zip(item.chars().rev())
+            .find(|((_, a), b)| a != b)
+            .map_or(len.min(item.len()), |((i, a), _)| {
+                tail.len() - i - a.len_utf8()
+            })
+    })
+}
+
+fn common_prefix(items: &[String], path_mode: bool) -> String {
+    let Some(first) = items.first() else {
+        return String::new();
+    };
+    let mut len = common_prefix_len(items);
+    let on_boundary = first[..len].ends_with('/')
+        || items
+            .iter()
+            .all(|item| item.len() == len || item[len..].starts_with('/'));
+    if path_mode && !on_boundary {
+        len = first[..len].rfind('/').map_or(0, |i| i + 1);
+    }
+    first[..len].to_string()
+}
+
+fn common_suffix(items: &[String], path_mode: bool) -> String {
+    let Some(first) = items.first() else {
+        return String::new();
+    };
+    let mut len = common_suffix_len(items);
+    let on_boundary = first[first.len() - len..].starts_with('/')
+        || items
+            .iter()
+            .all(|item| item.len() == len || item[..item.len() - len].ends_with('/'));
+    if path_mode && !on_boundary {
+        let tail = &first[first.len() - len..];
+        len = tail.find('/').map_or(0, |i| tail.len() - i);
+    }
+    first[first.len() - len..].to_string()
+}
+
+fn text_diff<'a>(a: &'a str, b: &'a str, mode: &str) -> mlua::Result<TextDiff<'a, 'a, str>> {
+    let config = TextDiff::configure();
+    Ok(match mode {
+        "chars" => config.diff_chars(a, b),
+        "words" => config.diff_words(a, b),
+        "lines" => config.diff_lines(a, b),
+        _ => {
+            return Err(mlua::Error::RuntimeError(format!(
+                "invalid diff mode '{mode}' (expected chars, words or lines)"
+            )));
+        }
+    })
+}
+
+/// Diffs with Myers' algorithm, merging consecutive changes of the same kind
+/// into one hunk.
+fn diff(lua: &Lua, a: &str, b: &str, mode: &str) -> mlua::Result<Vec<Table>> {
+    let mut hunks: Vec<(ChangeTag, String)> = Vec::new();
+    for change in text_diff(a, b, mode)?.iter_all_changes() {
+        match hunks.last_mut() {
+            Some((tag, text)) if *tag == change.tag() => text.push_str(change.value()),
+            _ => hunks.push((change.tag(), change.value().to_string())),
+        }
+    }
+
+    hunks
+        .into_iter()
+        .map(|(tag, text)| {
+            let hunk = lua.create_table()?;
+            let op = match tag {
+                ChangeTag::Equal => "equal",
+                ChangeTag::Insert => "insert",
+                ChangeTag::Delete => "delete",
+            };
+            hunk.set("op", op)?;
+            hunk.set("text", text)?;
+            Ok(hunk)
+        })
+        .collect()
+}
+
+/// Escapes a string as the body of a JSON string literal. U+2028 and U+2029
+/// are escaped too, since JavaScript doesn't allow them in string literals,
+/// and with `ascii` everything else outside ASCII is, as surrogate pairs
+/// where needed.
+fn escape_json(s: &str, ascii: bool) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\u{8}' => out.push_str("\\b"),
+            '\u{c}' => out.push_str("\\f"),
+            c if c.is_control()
+                || c == '\u{2028}'
+                || c == '\u{2029}'
+                || (ascii && !c.is_ascii()) =>
+            {
+                let mut units = [0; 2];
+                for unit in c.encode_utf16(&mut units) {
+                    out.push_str(&format!("\\u{unit:04x}"));
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Reverses `escape_json`, decoding every escape JSON allows.
+fn unescape_json(s: &str) -> mlua::Result<String> {
+    /// Reads exactly four hex digits, which `from_str_radix` wouldn't
+    /// enforce, as it also takes a sign.
+    fn hex(chars: &mut std::str::CharIndices) -> Option<u16> {
+        let mut unit = 0;
+        for _ in 0..4 {
+            let digit = chars.next()?.1.to_digit(16)?;
+            unit = unit * 16 + digit as u16;
+        }
+        Some(unit)
+    }
+    let invalid = |offset: usize| {
+        mlua::Error::RuntimeError(format!("invalid JSON escape at byte {}", offset + 1))
+    };
+
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.char_indices();
+    while let Some((offset, c)) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next().map(|(_, c)| c) {
+            Some('"') => out.push('"'),
+            Some('\\') => out.push('\\'),
+            Some('/') => out.push('/'),
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some('t') => out.push('\t'),
+            Some('b') => out.push('\u{8}'),
+            Some('f') => out.push('\u{c}'),
+            Some('u') => {
+                let mut units = vec![hex(&mut chars).ok_or_else(|| invalid(offset))?];
+                // a high surrogate has to be followed by an escaped low one
+                if (0xd800..0xdc00).contains(&units[0]) {
+                    if chars.next().map(|(_, c)| c) != Some('\\')
+                        || chars.next().map(|(_, c)| c) != Some('u')
+                    {
+                        return Err(invalid(offset));
+                    }
+                    units.push(hex(&mut chars).ok_or_else(|| invalid(offset))?);
+                }
+                let c = char::decode_utf16(units)
+                    .next()
+                    .and_then(Result::ok)
+                    .ok_or_else(|| invalid(offset))?;
+                out.push(c);
+            }
+            _ => return Err(invalid(offset)),
+        }
+    }
+    Ok(out)
+}
+
+fn slugify(s: &str, opts: Option<&Table>) -> mlua::Result<String> {
+    let separator: String = opt(opts, "separator")?.unwrap_or_else(|| "-".to_string());
+    let max_length: Option<usize> = opt(opts, "max_length")?;
+    let keep_unicode = opt(opts, "keep_unicode")?.unwrap_or(false);
+    let transliterate = opt(opts, "transliterate")?.unwrap_or(true);
+
+    let text = if !keep_unicode && transliterate {
+        deunicode(s).to_lowercase()
+    } else {
+        s.to_lowercase()
+    };
+
+    let mut words: Vec<String> = Vec::new();
+    let mut word = String::new();
+    // anything else separates words, except that without transliteration,
+    // non-ASCII characters are dropped
+    for c in text.chars() {
+        if c.is_ascii_alphanumeric() || (keep_unicode && c.is_alphanumeric()) {
+            word.push(c);
+        } else if (c.is_ascii() || keep_unicode) && !word.is_empty() {
+            words.push(std::mem::take(&mut word));
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+
+    let Some(max_length) = max_length else {
+        return Ok(words.join(&separator));
+    };
+    // keep whole words while they fit, cutting the first one if it's too long
+    let mut slug = String::new();
+    for word in words {
+        let len = slug.chars().count();
+        let joined_len =
+            len + separator.chars().count() * usize::from(len > 0) + word.chars().count();
+        if joined_len > max_length {
+            if slug.is_empty() {
+                slug = word.chars().take(max_length).collect();
+            }
+            break;
+        }
+        if !slug.is_empty() {
+            slug.push_str(&separator);
+        }
+        slug.push_str(&word);
+    }
+    Ok(slug)
+}
+
+/// Splits off the leading run of digits or of non-digits.
+fn next_chunk(s: &str) -> (&str, &str) {
+    let is_digit = s.starts_with(|c: char| c.is_ascii_digit());
+    let end = s
+        .find(|c: char| c.is_ascii_digit() != is_digit)
+        .unwrap_or(s.len());
+    s.split_at(end)
+}
+
+/// Compares strings so that runs of digits are ordered by their numeric
+/// value, e.g. "file2" < "file10". Numbers that only differ in leading zeros
+/// are ordered by the rest of the string first and then with fewer zeros
+/// first, and strings only compare equal if they are identical.
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut rest_a, mut rest_b) = (a, b);
+    let mut zeros = Ordering::Equal;
+    while !rest_a.is_empty() && !rest_b.is_empty() {
+        let (chunk_a, next_a) = next_chunk(rest_a);
+        let (chunk_b, next_b) = next_chunk(rest_b);
+        let is_number = |chunk: &str| chunk.starts_with(|c: char| c.is_ascii_digit());
+
+        let ordering = if is_number(chunk_a) && is_number(chunk_b) {
+            let (digits_a, digits_b) = (
+                chunk_a.trim_start_matches('0'),
+                chunk_b.trim_start_matches('0'),
+            );
+            zeros = zeros.then(chunk_a.len().cmp(&chunk_b.len()));
+            // without leading zeros, a longer number is a bigger one
+            digits_a
+                .len()
+                .cmp(&digits_b.len())
+                .then_with(|| digits_a.cmp(digits_b))
+        } else {
+            chunk_a.cmp(chunk_b)
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+        (rest_a, rest_b) = (next_a, next_b);
+    }
+    rest_a
+        .len()
+        .cmp(&rest_b.len())
+        .then(zeros)
+        .then_with(|| a.cmp(b))
+}
+
+/// A matcher with skim's scoring, which favours consecutive matches, matches
+/// at word boundaries and matches near the start.
+fn fuzzy_matcher(opts: Option<&Table>) -> mlua::Result<SkimMatcherV2> {
+    let matcher = SkimMatcherV2::default();
+    Ok(if opt(opts, "smart_case")?.unwrap_or(false) {
+        matcher.smart_case()
+    } else {
+        matcher.ignore_case()
+    })
+}
+
+fn normalize(s: &str, form: &str) -> mlua::Result<String> {
+    Ok(match form.to_ascii_uppercase().as_str() {
+        "NFC" => s.nfc().collect(),
+        "NFD" => s.nfd().collect(),
+        "NFKC" => s.nfkc().collect(),
+        "NFKD" => s.nfkd().collect(),
+        _ => {
+            return Err(mlua::Error::RuntimeError(format!(
+                "invalid normalization form '{form}' (expected NFC, NFD, NFKC or NFKD)"
+            )));
+        }
+    })
+}
+
+/// The characters of a `stringx.random` preset, or `None` if there's no
+/// preset with that name.
+fn preset_charset(name: &str) -> Option<Vec<char>> {
+    Some(match name {
+        "alnum" => ('0'..='9').chain('A'..='Z').chain('a'..='z').collect(),
+        "hex" => "0123456789abcdef".chars().collect(),
+        "base58" => "123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz"
+            .chars()
+            .collect(),
+        "ascii_printable" => (' '..='~').collect(),
+        _ => return None,
+    })
+}
+
+/// Reads the second argument of `stringx.random`: a preset name or else a
+/// string of the characters to use, or a table whose `preset` and `charset`
+/// options keep the two apart, so that `{charset = "hex"}` means those three
+/// letters.
+fn random_charset(charset: Option<Value>) -> mlua::Result<Vec<char>> {
+    let charset = match charset {
+        None | Some(Value::Nil) => preset_charset("alnum").expect("alnum is a preset"),
+        Some(Value::String(charset)) => {
+            let charset = charset.to_str()?;
+            preset_charset(&charset).unwrap_or_else(|| charset.chars().collect())
+        }
+        Some(Value::Table(opts)) => {
+            let opts = Some(&opts);
+            match (
+                opt::<String>(opts, "charset")?,
+                opt::<String>(opts, "preset")?,
+            ) {
+                (Some(_), Some(_)) => {
+                    return Err(mlua::Error::RuntimeError(
+                        "preset and charset can't both be given".into(),
+                    ));
+                }
+                (Some(charset), None) => charset.chars().collect(),
+                (None, preset) => {
+                    let preset = preset.as_deref().unwrap_or("alnum");
+                    preset_charset(preset).ok_or_else(|| {
+                        mlua::Error::RuntimeError(format!(
+                            "unknown preset '{preset}' (expected alnum, hex, base58 or ascii_printable)"
+                        ))
+                    })?
+                }
+            }
+        }
+        Some(other) => {
+            return Err(mlua::Error::RuntimeError(format!(
+                "invalid charset: expected a string or a table, got {}",
+                other.type_name()
+            )));
+        }
+    };
+    if charset.is_empty() {
+        return Err(mlua::Error::RuntimeError(
+            "charset must not be empty".into(),
+        ));
+    }
+    Ok(charset)
+}
+
+/// Generates a random string from a set of characters using the operating
+/// system's CSPRNG. `random_range` rejects out-of-range samples, so every
+/// character is equally likely.
+fn random(len: usize, charset: &[char]) -> String {
+    let mut rng = OsRng.unwrap_err();
+    (0..len)
+        .map(|_| charset[rng.random_range(0..charset.len())])
+        .collect()
+}
+
+/// Removes the longest common leading whitespace from every line, like
+/// Python's `textwrap.dedent`. Tabs and spaces are different characters, so
+/// "\t" and "  " have no common prefix. Blank lines don't count towards the
+/// margin and are emptied, and the first line is treated like the others.
+fn dedent(s: &str) -> String {
+    let indentation = |line: &str| {
+        let end = line
+            .find(|c: char| !c.is_whitespace())
+            .unwrap_or(line.len());
+        line[..end].to_string()
+    };
+
+    let mut margin: Option<String> = None;
+    for line in s.lines().filter(|line| !line.trim().is_empty()) {
+        let indent = indentation(line);
+        margin = Some(match margin {
+            None => indent,
+            Some(margin) => {
+                let common = margin
+                    .char_indices()
+                    .zip(indent.chars())
+                    .find(|((_, a), b)| a != b)
+                    .map_or(margin.len().min(indent.len()), |((i, _), _)| i);
+                margin[..common].to_string()
+            }
+        });
+    }
+    let margin = margin.unwrap_or_default();
+
+    s.split_inclusive('\n')
+        .map(|line| {
+            if line.trim().is_empty() {
+                // keep only the line ending of blank lines
+                &line[line.trim_end_matches(['\r', '\n']).len()..]
+            } else {
+                line.strip_prefix(&*margin).unwrap_or(line)
+            }
+        })
+        .collect()
+}
+
+fn indent(s: &str, prefix: &str, skip_blank: bool) -> String {
+    s.split_inclusive('\n')
+        .map(|line| {
+            if skip_blank && line.trim().is_empty() {
+                line.to_string()
+            } else {
+                format!("{prefix}{line}")
+            }
+        })
+        .collect()
+}
+
+/// Quotes a string as a single argument for a POSIX shell, a Windows command
+/// line (using the quoting rules of `CommandLineToArgvW`) or PowerShell.
+fn shell_quote(s: &str, style: &str) -> mlua::Result<String> {
+    match style {
+        "posix" => {
+            let is_safe = |c: char| c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c);
+            if !s.is_empty() && s.chars().all(is_safe) {
+                Ok(s.to_string())
+            } else {
+                Ok(format!("'{}'", s.replace('\'', r"'\''")))
+            }
+        }
+        "windows" => {
+            let mut quoted = String::from('"');
+            let mut backslashes = 0;
+            for c in s.chars() {
+                match c {
+                    '\\' => {
+                        backslashes += 1;
+                        continue;
+                    }
+                    // backslashes before a quote are escapes, so double them
+                    '"' => quoted.extend(std::iter::repeat_n('\\', backslashes * 2 + 1)),
+                    _ => quoted.extend(std::iter::repeat_n('\\', backslashes)),
+                }
+                backslashes = 0;
+                quoted.push(c);
+            }
+            quoted.extend(std::iter::repeat_n('\\', backslashes * 2));
+            quoted.push('"');
+            Ok(quoted)
+        }
+        "powershell" => Ok(format!("'{}'", s.replace('\'', "''"))),
+        _ => Err(mlua::Error::RuntimeError(format!(
+            "invalid quoting style '{style}' (expected posix, windows or powershell)"
+        ))),
+    }
+}
+
+/// Splits a command line into words like a POSIX shell, handling single and
+/// double quotes and backslash escapes but not expansions.
+fn shell_split(cmdline: &str) -> mlua::Result<Vec<String>> {
+    let unterminated = |what: &str, offset: usize| {
+        mlua::Error::RuntimeError(format!("unterminated {what} at byte {}", offset + 1))
+    };
+
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = cmdline.char_indices().peekable();
+    while let Some((offset, c)) = chars.next() {
+        match c {
+            ' ' | '\t' | '\n' => {
+                if let Some(word) = word.take() {
+                    words.push(word);
+                }
+            }
+            '\\' => match chars.next() {
+                // a backslash before a newline continues the line
+                Some((_, '\n')) => {}
+                Some((_, c)) => word.get_or_insert_default().push(c),
+                None => return Err(unterminated("escape", offset)),
+            },
+            '\'' => {
+                let word = word.get_or_insert_default();
+                loop {
+                    match chars.next() {
+                        Some((_, '\'')) => break,
+                        Some((_, c)) => word.push(c),
+                        None => return Err(unterminated("single quote", offset)),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_default();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, '\n')) => {}
+                            Some((_, c @ ('$' | '`' | '"' | '\\'))) => word.push(c),
+                            Some((_, c)) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => return Err(unterminated("double quote", offset)),
+                        },
+                        Some((_, c)) => word.push(c),
+                        None => return Err(unterminated("double quote", offset)),
+                    }
+                }
+            }
+            c => word.get_or_insert_default().push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// Checks `s` against `[sign] digits [. digits] [e [sign] digits]`, where
+/// each optional part must be enabled and a decimal point needs a digit on
+/// at least one side.
+fn is_numeric(s: &str, sign: bool, decimal: bool, exponent: bool) -> bool {
+    fn digits(s: &str) -> (usize, &str) {
+        let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        (end, &s[end..])
+    }
+    fn strip_sign(s: &str, sign: bool) -> &str {
+        match s.strip_prefix(['+', '-']) {
+            Some(rest) if sign => rest,
+            _ => s,
+        }
+    }
+
+    let (int_digits, mut rest) = digits(strip_sign(s, sign));
+    let mut frac_digits = 0;
+    if decimal && let Some(after) = rest.strip_prefix('.') {
+        (frac_digits, rest) = digits(after);
+    }
+    if int_digits + frac_digits == 0 {
+        return false;
+    }
+    if exponent && let Some(after) = rest.strip_prefix(['e', 'E']) {
+        let exp_digits;
+        (exp_digits, rest) = digits(strip_sign(after, sign));
+        if exp_digits == 0 {
+            return false;
+        }
+    }
+    rest.is_empty()
+}
+
+/// Parses an integer in the given base, with an optional sign.
+fn to_number(s: &str, base: u32) -> mlua::Result<Result<i64, String>> {
+    if !(2..=36).contains(&base) {
+        return Err(mlua::Error::RuntimeError(format!(
+            "invalid base {base} (expected 2 to 36)"
+        )));
+    }
+    Ok(i64::from_str_radix(s, base).map_err(|e| format!("invalid base {base} number '{s}': {e}")))
+}
+
+/// Slices by character index with the same rules as `string.sub`: indices
+/// are 1-based and inclusive, negative ones count from the end, and
+/// out-of-range ones are clamped.
+fn sub(s: &str, i: i64, j: Option<i64>) -> &str {
+    let len = s.chars().count() as i64;
+    let resolve = |index: i64| if index < 0 { len + index + 1 } else { index };
+    let start = resolve(i).max(1);
+    let end = resolve(j.unwrap_or(-1)).min(len);
+    if start > end {
+        return "";
+    }
+
+    let byte_offset = |index: i64| {
+        s.char_indices()
+            .nth(index as usize)
+            .map_or(s.len(), |(offset, _)| offset)
+    };
+    &s[byte_offset(start - 1)..byte_offset(end)]
+}
+
+/// Counts literal occurrences of `needle`, letting them overlap if asked.
+fn count(s: &str, needle: &str, overlapping: bool) -> mlua::Result<usize> {
+    if needle.is_empty() {
+        return Err(mlua::Error::RuntimeError(
+            "cannot count an empty string".into(),
+        ));
+    }
+    if !overlapping {
+        return Ok(s.matches(needle).count());
+    }
+
+    let mut count = 0;
+    let mut rest = s;
+    while let Some(pos) = rest.find(needle) {
+        count += 1;
+        // restart just after the first character of the match
+        let step = rest[pos..].chars().next().map_or(1, char::len_utf8);
+        rest = &rest[pos + step..];
+    }
+    Ok(count)
+}
+
+/// The longest string `rep` builds, which is about the most a LuaJIT string
+/// can hold anyway.
+const MAX_REP_LEN: usize = i32::MAX as usize;
+
+/// Repeats `s` `n` times with `sep` between the copies, erroring like
+/// `string.rep` rather than failing to allocate when the result would be
+/// too large.
+fn rep(s: &str, n: usize, sep: &str) -> mlua::Result<String> {
+    let len = s
+        .len()
+        .checked_mul(n)
+        .and_then(|len| len.checked_add(sep.len().checked_mul(n.saturating_sub(1))?))
+        .filter(|&len| len <= MAX_REP_LEN)
+        .ok_or_else(|| mlua::Error::RuntimeError("resulting string too large".into()))?;
+    // which also saves looping over copies of an empty string
+    if len == 0 {
+        return Ok(String::new());
+    }
+    let mut result = String::with_capacity(len);
+    result.push_str(s);
+    for _ in 1..n {
+        result.push_str(sep);
+        result.push_str(s);
+    }
+    Ok(result)
+}
+
+/// Reads the `repeat` option of `strip_prefix` and `strip_suffix`, which is
+/// also accepted as `repeated` since `{repeat = true}` doesn't parse, `repeat`
+/// being a Lua keyword.
+fn repeated_option(opts: Option<&Table>) -> mlua::Result<bool> {
+    match (opt(opts, "repeat")?, opt(opts, "repeated")?) {
+        (Some(_), Some(_)) => Err(mlua::Error::RuntimeError(
+            "repeat and repeated can't both be given".into(),
+        )),
+        (repeat, repeated) => Ok(repeat.or(repeated).unwrap_or(false)),
+    }
+}
+
+/// Strips `affix` with `strip` once, or as many times as it matches with
+/// `repeat`, returning the rest and whether it matched at all. Like Rust's
+/// `strip_prefix`, an empty affix always matches.
+fn strip_affix<'a>(
+    s: &'a str,
+    affix: &str,
+    repeat: bool,
+    strip: impl Fn(&'a str) -> Option<&'a str>,
+) -> (&'a str, bool) {
+    let Some(mut rest) = strip(s) else {
+        return (s, false);
+    };
+    // an empty affix would match forever
+    while repeat && !affix.is_empty() {
+        match strip(rest) {
+            Some(stripped) => rest = stripped,
+            None => break,
+        }
+    }
+    (rest, true)
+}
+
+/// Splits a string into the units `truncate` counts, given as their byte
+/// offset and size.
+fn measure_units(s: &str, by: &str) -> mlua::Result<Vec<(usize, usize)>> {
+    Ok(match by {
+        "chars" => s.char_indices().map(|(i, _)| (i, 1)).collect(),
+        "graphemes" => s.grapheme_indices(true).map(|(i, _)| (i, 1)).collect(),
+        "width" => s
+            .grapheme_indices(true)
+            .map(|(i, g)| (i, display_width(g)))
+            .collect(),
+        _ => {
+            return Err(mlua::Error::RuntimeError(format!(
+                "invalid truncation unit '{by}' (expected chars, graphemes or width)"
+            )));
+        }
+    })
+}
+
+/// Cuts `s` to at most `max` units, keeping a prefix that fits within `max`.
+fn take_units<'a>(s: &'a str, units: &[(usize, usize)], max: usize) -> &'a str {
+    let mut used = 0;
+    for &(i, size) in units {
+        if used + size > max {
+            return &s[..i];
+        }
+        used += size;
+    }
+    s
+}
+
+fn truncate(s: &str, max: usize, opts: Option<&Table>) -> mlua::Result<String> {
+    let by: String = opt(opts, "by")?.unwrap_or_else(|| "chars".to_string());
+    let ellipsis: String = opt(opts, "ellipsis")?.unwrap_or_else(|| "…".to_string());
+
+    let units = measure_units(s, &by)?;
+    if units.iter().map(|&(_, size)| size).sum::<usize>() <= max {
+        return Ok(s.to_string());
+    }
+
+    let ellipsis_size: usize = measure_units(&ellipsis, &by)?
+        .iter()
+        .map(|&(_, size)| size)
+        .sum();
+    match max.checked_sub(ellipsis_size) {
+        Some(budget) => Ok(format!("{}{ellipsis}", take_units(s, &units, budget))),
+        // there's no room for the ellipsis, so just cut the string
+        None => Ok(take_units(s, &units, max).to_string()),
+    }
+}
+
+/// Splits a paragraph into words at spaces for textwrap, measuring them with
+/// `display_width` instead of textwrap's own width so that wrapping agrees
+/// with padding and truncation about wide characters and escape sequences.
+fn measured_words(paragraph: &str) -> Vec<Word<'_>> {
+    WordSeparator::AsciiSpace
+        .find_words(paragraph)
+        .map(|word| Word {
+            width: display_width(word.word),
+            ..word
+        })
+        .collect()
+}
+
+/// Breaks a word wider than `width` into pieces that fit, between graphemes.
+/// Each piece is measured as a whole, so escape sequences take no space and
+/// are never cut.
+fn break_word(word: Word<'_>, width: usize) -> Vec<Word<'_>> {
+    let mut pieces = Vec::new();
+    let (mut start, mut used) = (0, 0);
+    for (i, grapheme) in word.word.grapheme_indices(true) {
+        let with_grapheme = display_width(&word.word[start..i + grapheme.len()]);
+        if with_grapheme > width && used > 0 {
+            pieces.push(Word {
+                word: &word.word[start..i],
+                whitespace: "",
+                penalty: "",
+                width: used,
+            });
+            start = i;
+            used = display_width(grapheme);
+        } else {
+            used = with_grapheme;
+        }
+    }
+    pieces.push(Word {
+        word: &word.word[start..],
+        width: used,
+        ..word
+    });
+    pieces
+}
+
+/// Word-wraps each paragraph (line of the input) separately, so existing
+/// newlines are kept and every paragraph starts with the initial indent.
+fn wrap(s: &str, width: usize, opts: Option<&Table>) -> mlua::Result<Vec<String>> {
+    let initial_indent: String = opt(opts, "initial_indent")?.unwrap_or_default();
+    let subsequent_indent: String = opt(opts, "subsequent_indent")?.unwrap_or_default();
+    let break_long_words = opt(opts, "break_long_words")?.unwrap_or(false);
+    let line_widths = [
+        width.saturating_sub(display_width(&initial_indent)),
+        width.saturating_sub(display_width(&subsequent_indent)),
+    ];
+
+    let mut lines = Vec::new();
+    for paragraph in s.lines() {
+        if paragraph.trim().is_empty() {
+            lines.push(String::new());
+            continue;
+        }
+
+        let mut words = measured_words(paragraph);
+        if break_long_words {
+            words = words
+                .into_iter()
+                .flat_map(|word| {
+                    if word.width > line_widths[1] {
+                        break_word(word, line_widths[1])
+                    } else {
+                        vec![word]
+                    }
+                })
+                .collect();
+            // pieces are cut to fit the following lines, so like textwrap,
+            // an empty first word lets the first piece skip the first line
+            if !initial_indent.is_empty() {
+                words.insert(0, Word::from(""));
+            }
+        }
+
+        let wrapped = WrapAlgorithm::new_optimal_fit().wrap(&words, &line_widths);
+        for (i, line) in wrapped.into_iter().enumerate() {
+            let Some((last, rest)) = line.split_last() else {
+                lines.push(String::new());
+                continue;
+            };
+            let mut text = if i == 0 {
+                initial_indent.clone()
+            } else {
+                subsequent_indent.clone()
+            };
+            for word in rest {
+                text.push_str(word.word);
+                text.push_str(word.whitespace);
+            }
+            text.push_str(last.word);
+            lines.push(text);
+        }
+    }
+    Ok(lines)
+}
+
+/// Splits on characters for which `is_separator` returns true, dropping
+/// empty tokens.
+fn fields(s: &str, is_separator: Function) -> mlua::Result<Vec<&str>> {
+    let mut fields = Vec::new();
+    let mut start = None;
+    for (i, c) in s.char_indices() {
+        let mut buf = [0; 4];
+        if is_separator.call::<bool>(&*c.encode_utf8(&mut buf))? {
+            if let Some(start) = start.take() {
+                fields.push(&s[start..i]);
+            }
+        } else if start.is_none() {
+            start = Some(i);
+        }
+    }
+    if let Some(start) = start {
+        fields.push(&s[start..]);
+    }
+    Ok(fields)
+}
+
+/// Strips a trailing `\n` or `\r\n` from a line.
+fn strip_line_ending(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// Returns an iterator over the lines of `s` that slices the Lua string as it
+/// goes instead of building a table of every line.
+fn iter_lines(lua: &Lua, s: mlua::String) -> mlua::Result<Function> {
+    let mut pos = 0;
+    lua.create_function_mut(move |lua, ()| {
+        let bytes = s.as_bytes();
+        if pos >= bytes.len() {
+            return Ok(None);
+        }
+        let rest = &bytes[pos..];
+        let end = rest
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(rest.len(), |i| i + 1);
+        pos += end;
+        lua.create_string(strip_line_ending(&rest[..end])).map(Some)
+    })
+}
+
+impl UserData for StringUtils {
+    fn add_methods<'lua, M: UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_function(
+            "split",
+            |_, (s, pat, opts): (String, Option<String>, Option<Table>)| {
+                let max: Option<usize> = opt(opts.as_ref(), "max")?;
+                if max == Some(0) {
+                    return Err(mlua::Error::RuntimeError("max must be at least 1".into()));
+                }
+                let max = max.unwrap_or(usize::MAX);
+
+                let parts = match pat {
+                    Some(pat) => {
+                        let remove_empty = opt(opts.as_ref(), "remove_empty")?.unwrap_or(false);
+                        split(&s, &pat, max, remove_empty)
+                    }
+                    None => split_whitespace(&s, max),
+                };
+                Ok(parts.into_iter().map(str::to_string).collect::<Vec<_>>())
+            },
+        );
+
+        methods.add_function("trim", |_, s: String| Ok(s.trim().to_string()));
+
+        methods.add_function("join", |lua, (parts, sep): (Table, Option<String>)| {
+            join(lua, parts, sep.as_deref().unwrap_or(""))
+        });
+
+        methods.add_function(
+            "starts_with",
+            |_, (s, prefix, case_insensitive): (String, String, Option<bool>)| {
+                let ci = case_insensitive.unwrap_or(false);
+                Ok(fold(&s, ci).starts_with(&*fold(&prefix, ci)))
+            },
+        );
+
+        methods.add_function(
+            "ends_with",
+            |_, (s, suffix, case_insensitive): (String, String, Option<bool>)| {
+                let ci = case_insensitive.unwrap_or(false);
+                Ok(fold(&s, ci).ends_with(&*fold(&suffix, ci)))
+            },
+        );
+
+        methods.add_function(
+            "contains",
+            |_, (s, needle, case_insensitive): (String, String, Option<bool>)| {
+                let ci = case_insensitive.unwrap_or(false);
+                Ok(fold(&s, ci).contains(&*fold(&needle, ci)))
+            },
+        );
+
+        methods.add_function(
+            "pad_left",
+            |_, (s, width, fill): (String, usize, Option<String>)| pad(s, width, fill, |n| n),
+        );
+
+        methods.add_function(
+            "pad_right",
+            |_, (s, width, fill): (String, usize, Option<String>)| pad(s, width, fill, |_| 0),
+        );
+
+        methods.add_function(
+            "center",
+            |_, (s, width, fill): (String, usize, Option<String>)| pad(s, width, fill, |n| n / 2),
+        );
+
+        methods.add_function(
+            "replace",
+            |_, (s, from, to, count): (String, String, String, Option<usize>)| {
+                replace(&s, &from, &to, count)
+            },
+        );
+
+        methods.add_function("upper", |_, s: String| Ok(s.to_uppercase()));
+
+        methods.add_function("lower", |_, s: String| Ok(s.to_lowercase()));
+
+        methods.add_function("capitalize", |_, s: String| Ok(capitalize(&s)));
+
+        methods.add_function("title", |_, s: String| Ok(title(&s)));
+
+        methods.add_function("to_snake", |_, s: String| Ok(s.to_snake_case()));
+
+        methods.add_function("to_camel", |_, s: String| Ok(s.to_lower_camel_case()));
+
+        methods.add_function("to_kebab", |_, s: String| Ok(s.to_kebab_case()));
+
+        methods.add_function("to_pascal", |_, s: String| Ok(s.to_upper_camel_case()));
+
+        methods.add_function("lines", |lua, s: mlua::String| {
+            let bytes = s.as_bytes();
+            bytes
+                .split_inclusive(|&b| b == b'\n')
+                .map(|line| lua.create_string(strip_line_ending(line)))
+                .collect::<mlua::Result<Vec<_>>>()
+        });
+
+        methods.add_function("iter_lines", |lua, s: mlua::String| iter_lines(lua, s));
+
+        methods.add_function(
+            "fields",
+            |_, (s, is_separator): (String, Option<Function>)| {
+                let fields = match is_separator {
+                    Some(is_separator) => fields(&s, is_separator)?,
+                    None => s.split_whitespace().collect(),
+                };
+                Ok(fields.into_iter().map(str::to_string).collect::<Vec<_>>())
+            },
+        );
+
+        methods.add_function("levenshtein", |_, (a, b): (String, String)| {
+            Ok(levenshtein(&a, &b))
+        });
+
+        methods.add_function("similarity", |_, (a, b): (String, String)| {
+            Ok(similarity(&a, &b))
+        });
+
+        methods.add_function(
+            "closest",
+            |_, (needle, candidates, max_distance): (String, Vec<String>, Option<usize>)| {
+                let best = candidates
+                    .into_iter()
+                    .map(|candidate| {
+                        let distance = levenshtein(&needle, &candidate);
+                        (candidate, distance)
+                    })
+                    .filter(|(_, distance)| max_distance.is_none_or(|max| *distance <= max))
+                    // min_by_key keeps the first of equally close candidates
+                    .min_by_key(|(_, distance)| *distance);
+                Ok(best.unzip())
+            },
+        );
+
+        methods.add_function(
+            "wrap",
+            |_, (s, width, opts): (String, usize, Option<Table>)| wrap(&s, width, opts.as_ref()),
+        );
+
+        methods.add_function(
+            "fill",
+            |_, (s, width, opts): (String, usize, Option<Table>)| {
+                Ok(wrap(&s, width, opts.as_ref())?.join("\n"))
+            },
+        );
+
+        methods.add_function(
+            "truncate",
+            |_, (s, max, opts): (String, usize, Option<Table>)| truncate(&s, max, opts.as_ref()),
+        );
+
+        methods.add_function(
+            "strip_prefix",
+            |_, (s, prefix, opts): (String, String, Option<Table>)| {
+                let repeat = repeated_option(opts.as_ref())?;
+                let (rest, matched) =
+                    strip_affix(&s, &prefix, repeat, |s| s.strip_prefix(&*prefix));
+                Ok((rest.to_string(), matched))
+            },
+        );
+
+        methods.add_function(
+            "strip_suffix",
+            |_, (s, suffix, opts): (String, String, Option<Table>)| {
+                let repeat = repeated_option(opts.as_ref())?;
+                let (rest, matched) =
+                    strip_affix(&s, &suffix, repeat, |s| s.strip_suffix(&*suffix));
+                Ok((rest.to_string(), matched))
+            },
+        );
+
+        methods.add_function("interp", |lua, (template, values): (String, Table)| {
+            interp(lua, &template, &values)
+        });
+
+        methods.add_function("rep", |_, (s, n, sep): (String, i64, Option<String>)| {
+            rep(
+                &s,
+                usize::try_from(n).unwrap_or(0),
+                sep.as_deref().unwrap_or(""),
+            )
+        });
+
+        methods.add_function("reverse", |_, s: String| {
+            Ok(s.graphemes(true).rev().collect::<String>())
+        });
+
+        methods.add_function(
+            "count",
+            |_, (s, needle, opts): (String, String, Option<Table>)| {
+                let overlapping = opt(opts.as_ref(), "overlapping")?.unwrap_or(false);
+                count(&s, &needle, overlapping)
+            },
+        );
+
+        methods.add_function("sub", |_, (s, i, j): (String, i64, Option<i64>)| {
+            Ok(sub(&s, i, j).to_string())
+        });
+
+        methods.add_function("chars", |_, s: String| {
+            Ok(s.chars().map(String::from).collect::<Vec<_>>())
+        });
+
+        methods.add_function("len", |_, s: String| Ok(s.chars().count()));
+
+        methods.add_function("byte_len", |_, s: mlua::String| Ok(s.as_bytes().len()));
+
+        methods.add_function("is_blank", |_, s: String| {
+            Ok(s.chars().all(char::is_whitespace))
+        });
+
+        methods.add_function("is_numeric", |_, (s, opts): (String, Option<Table>)| {
+            let opts = opts.as_ref();
+            Ok(is_numeric(
+                &s,
+                opt(opts, "sign")?.unwrap_or(false),
+                opt(opts, "decimal")?.unwrap_or(false),
+                opt(opts, "exponent")?.unwrap_or(false),
+            ))
+        });
+
+        methods.add_function("is_alpha", |_, s: String| {
+            Ok(!s.is_empty() && s.chars().all(char::is_alphabetic))
+        });
+
+        methods.add_function("is_alnum", |_, s: String| {
+            Ok(!s.is_empty() && s.chars().all(char::is_alphanumeric))
+        });
+
+        methods.add_function("is_ascii", |_, s: mlua::String| Ok(s.as_bytes().is_ascii()));
+
+        methods.add_function("to_number", |_, (s, base): (String, Option<u32>)| {
+            Ok(match to_number(&s, base.unwrap_or(10))? {
+                Ok(n) => (Some(n), None),
+                Err(e) => (None, Some(e)),
+            })
+        });
+
+        methods.add_function("shell_quote", |_, (s, opts): (String, Option<Table>)| {
+            let style: Option<String> = opt(opts.as_ref(), "style")?;
+            shell_quote(&s, style.as_deref().unwrap_or("posix"))
+        });
+
+        methods.add_function("shell_split", |_, cmdline: String| shell_split(&cmdline));
+
+        methods.add_function("dedent", |_, s: String| Ok(dedent(&s)));
+
+        methods.add_function(
+            "indent",
+            |_, (s, prefix, opts): (String, String, Option<Table>)| {
+                let skip_blank = opt(opts.as_ref(), "skip_blank")?.unwrap_or(false);
+                Ok(indent(&s, &prefix, skip_blank))
+            },
+        );
+
+        methods.add_function("random", |_, (len, charset): (usize, Option<Value>)| {
+            Ok(random(len, &random_charset(charset)?))
+        });
+
+        methods.add_function("normalize", |_, (s, form): (String, Option<String>)| {
+            normalize(&s, form.as_deref().unwrap_or("NFC"))
+        });
+
+        methods.add_function("eq_normalized", |_, (a, b): (String, String)| {
+            Ok(a.nfc().eq(b.nfc()))
+        });
+
+        methods.add_function("width", |_, s: String| {
+            Ok((display_width(&s), s.contains('\x1b')))
+        });
+
+        methods.add_function(
+            "fuzzy_match",
+            |lua, (needle, haystack, opts): (String, String, Option<Table>)| {
+                let matcher = fuzzy_matcher(opts.as_ref())?;
+                let Some((score, positions)) = matcher.fuzzy_indices(&haystack, &needle) else {
+                    return Ok(None);
+                };
+                let result = lua.create_table()?;
+                result.set("score", score)?;
+                result.set(
+                    "positions",
+                    positions.into_iter().map(|i| i + 1).collect::<Vec<_>>(),
+                )?;
+                Ok(Some(result))
+            },
+        );
+
+        methods.add_function(
+            "fuzzy_filter",
+            |_, (needle, candidates, opts): (String, Vec<String>, Option<Table>)| {
+                let matcher = fuzzy_matcher(opts.as_ref())?;
+                let mut matches: Vec<_> = candidates
+                    .into_iter()
+                    .filter_map(|candidate| {
+                        let score = matcher.fuzzy_match(&candidate, &needle)?;
+                        Some((score, candidate))
+                    })
+                    .collect();
+                // the sort is stable, so equally good matches keep their order
+                matches.sort_by_key(|&(score, _)| std::cmp::Reverse(score));
+                Ok(matches
+                    .into_iter()
+                    .map(|(_, candidate)| candidate)
+                    .collect::<Vec<_>>())
+            },
+        );
+
+        methods.add_function("natural_cmp", |_, (a, b): (String, String)| {
+            Ok(natural_cmp(&a, &b) as i8)
+        });
+
+        methods.add_function("natural_lt", |_, (a, b): (String, String)| {
+            Ok(natural_cmp(&a, &b).is_lt())
+        });
+
+        methods.add_function("natural_sort", |_, t: Table| {
+            let mut items: Vec<String> = t.sequence_values().collect::<mlua::Result<_>>()?;
+            items.sort_by(|a, b| natural_cmp(a, b));
+            for (i, item) in items.into_iter().enumerate() {
+                t.raw_set(i + 1, item)?;
+            }
+            Ok(())
+        });
+
+        methods.add_function("slugify", |_, (s, opts): (String, Option<Table>)| {
+            slugify(&s, opts.as_ref())
+        });
+
+        methods.add_function("escape_html", |_, s: String| {
+            Ok(html_escape::encode_quoted_attribute(&s).into_owned())
+        });
+
+        methods.add_function("unescape_html", |_, s: String| {
+            Ok(html_escape::decode_html_entities(&s).into_owned())
+        });
+
+        methods.add_function("escape_json", |_, (s, opts): (String, Option<Table>)| {
+            Ok(escape_json(
+                &s,
+                opt(opts.as_ref(), "ascii")?.unwrap_or(false),
+            ))
+        });
+
+        methods.add_function("unescape_json", |_, s: String| unescape_json(&s));
+
+        methods.add_function("escape_regex", |_, s: String| Ok(regex::escape(&s)));
+
+        methods.add_function(
+            "common_prefix",
+            |_, (items, opts): (Vec<String>, Option<Table>)| {
+                let path_mode = opt(opts.as_ref(), "path_mode")?.unwrap_or(false);
+                Ok(common_prefix(&items, path_mode))
+            },
+        );
+
+        methods.add_function(
+            "common_suffix",
+            |_, (items, opts): (Vec<String>, Option<Table>)| {
+                let path_mode = opt(opts.as_ref(), "path_mode")?.unwrap_or(false);
+                Ok(common_suffix(&items, path_mode))
+            },
+        );
+
+        methods.add_function(
+            "diff",
+            |lua, (a, b, opts): (String, String, Option<Table>)| {
+                let mode: Option<String> = opt(opts.as_ref(), "mode")?;
+                diff(lua, &a, &b, mode.as_deref().unwrap_or("chars"))
+            },
+        );
+
+        methods.add_function(
+            "diff_pretty",
+            |_, (a, b, opts): (String, String, Option<Table>)| {
+                let context: Option<usize> = opt(opts.as_ref(), "context")?;
+                let diff = text_diff(&a, &b, "lines")?;
+                let mut unified = diff.unified_diff();
+                unified
+                    .context_radius(context.unwrap_or(3))
+                    .header("a", "b");
+                Ok(unified.to_string())
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{error, eval, lua};
+
+    #[test]
+    fn join_mixed_strings_numbers_and_booleans() {
+        let lua = lua();
+        let joined: (String, String, String, String) = eval(
+            &lua,
+            r#"return stringx.join({"a", 1, 2.5, true}, ", "), stringx.join({}, ","),
+                stringx.join({"only"}, ","), stringx.join({"x", "y"})"#,
+        );
+        assert_eq!(
+            joined,
+            (
+                "a, 1, 2.5, true".to_string(),
+                String::new(),
+                "only".to_string(),
+                "xy".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn join_names_the_index_of_a_nil_hole() {
+        let lua = lua();
+        let message = error(&lua, r#"stringx.join({"a", nil, "c"}, ",")"#);
+        assert!(
+            message.contains("cannot join a nil value at index 2"),
+            "{message}"
+        );
+        let message = error(&lua, r#"stringx.join({"a", {}}, ",")"#);
+        assert!(
+            message.contains("cannot join a table value at index 2"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn pad_measures_display_columns() {
+        let lua = lua();
+        for (code, expected) in [
+            // a combining acute takes no column of its own
+            (
+                r#"stringx.pad_right("cafe\u{301}", 6, ".")"#,
+                "cafe\u{301}..",
+            ),
+            (r#"stringx.pad_left("日本", 6)"#, "  日本"),
+            (r#"stringx.center("日本", 7, "*")"#, "*日本**"),
+            (r#"stringx.pad_right("ab", 7, "<>=")"#, "ab<>=<>"),
+            // a wide fill that doesn't fit in the last column is topped up
+            (r#"stringx.pad_left("x", 4, "日")"#, "日 x"),
+            (r#"stringx.pad_left("too long", 3)"#, "too long"),
+            (r#"stringx.center("日本", 3)"#, "日本"),
+        ] {
+            let padded: String = eval(&lua, &format!("return {code}"));
+            assert_eq!(padded, expected, "{code}");
+        }
+    }
+
+    #[test]
+    fn pad_rejects_a_zero_width_fill() {
+        let lua = lua();
+        for fill in [r#""""#, r#""\u{301}""#] {
+            let message = error(&lua, &format!(r#"stringx.pad_left("x", 3, {fill})"#));
+            assert!(message.contains("at least one column wide"), "{message}");
+        }
+    }
+
+    #[test]
+    fn case_conversion_is_unicode_aware() {
+        let lua = lua();
+        for (code, expected) in [
+            (r#"stringx.upper("straße")"#, "STRASSE"),
+            (r#"stringx.upper("ström")"#, "STRÖM"),
+            (r#"stringx.lower("STRÖM")"#, "ström"),
+            (r#"stringx.capitalize("ßtraße")"#, "SStraße"),
+            // no locale applies, so Turkish letters don't get their own rules
+            (r#"stringx.upper("i")"#, "I"),
+            (r#"stringx.lower("I")"#, "i"),
+            (r#"stringx.upper("ı")"#, "I"),
+            (r#"stringx.lower("İ")"#, "i\u{307}"),
+        ] {
+            let converted: String = eval(&lua, &format!("return {code}"));
+            assert_eq!(converted, expected, "{code}");
+        }
+    }
+
+    #[test]
+    fn capitalize_and_title_skip_leading_punctuation() {
+        let lua = lua();
+        for (code, expected) in [
+            (r#"stringx.capitalize("¡hola amigo!")"#, "¡Hola amigo!"),
+            (r#"stringx.capitalize("«élan» vital")"#, "«Élan» vital"),
+            (r#"stringx.capitalize("...and mIxEd")"#, "...And mIxEd"),
+            (r#"stringx.capitalize("123")"#, "123"),
+            (r#"stringx.capitalize("")"#, ""),
+            (r#"stringx.title("hello, wörld")"#, "Hello, Wörld"),
+            (
+                r#"stringx.title("(über) straße-ende")"#,
+                "(Über) Straße-Ende",
+            ),
+            (r#"stringx.title("don't stop")"#, "Don't Stop"),
+        ] {
+            let converted: String = eval(&lua, &format!("return {code}"));
+            assert_eq!(converted, expected, "{code}");
+        }
+    }
+
+    #[test]
+    fn identifier_case_conversions() {
+        let lua = lua();
+        let convert = lua
+            .load(
+                "return function(s)
+                    return stringx.to_snake(s), stringx.to_camel(s), stringx.to_kebab(s), stringx.to_pascal(s)
+                end",
+            )
+            .eval::<mlua::Function>()
+            .unwrap();
+        // input, snake_case, camelCase, kebab-case, PascalCase
+        for (input, snake, camel, kebab, pascal) in [
+            (
+                "hello world",
+                "hello_world",
+                "helloWorld",
+                "hello-world",
+                "HelloWorld",
+            ),
+            (
+                "HTTPServerV2",
+                "http_server_v2",
+                "httpServerV2",
+                "http-server-v2",
+                "HttpServerV2",
+            ),
+            (
+                "XMLHttpRequest",
+                "xml_http_request",
+                "xmlHttpRequest",
+                "xml-http-request",
+                "XmlHttpRequest",
+            ),
+            (
+                "already_snake_case",
+                "already_snake_case",
+                "alreadySnakeCase",
+                "already-snake-case",
+                "AlreadySnakeCase",
+            ),
+            (
+                "kebab-case-input",
+                "kebab_case_input",
+                "kebabCaseInput",
+                "kebab-case-input",
+                "KebabCaseInput",
+            ),
+            (
+                "camelCaseInput",
+                "camel_case_input",
+                "camelCaseInput",
+                "camel-case-input",
+                "CamelCaseInput",
+            ),
+            (
+                "version2Update",
+                "version2_update",
+                "version2Update",
+                "version2-update",
+                "Version2Update",
+            ),
+            (
+                "utf8 to utf16",
+                "utf8_to_utf16",
+                "utf8ToUtf16",
+                "utf8-to-utf16",
+                "Utf8ToUtf16",
+            ),
+            (
+                "  mixed_Separators-and spaces ",
+                "mixed_separators_and_spaces",
+                "mixedSeparatorsAndSpaces",
+                "mixed-separators-and-spaces",
+                "MixedSeparatorsAndSpaces",
+            ),
+            ("", "", "", "", ""),
+        ] {
+            let converted: (String, String, String, String) = convert.call(input).unwrap();
+            assert_eq!(
+                converted,
+                (snake.into(), camel.into(), kebab.into(), pascal.into()),
+                "{input:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn split_an_empty_string() {
+        let lua = lua();
+        let counts: (usize, usize, String, usize, usize) = eval(
+            &lua,
+            r#"local delimited = stringx.split("", ",")
+            return #stringx.split(""), #stringx.split("  \t\n "), delimited[1], #delimited,
+                #stringx.split("", ",", {remove_empty = true})"#,
+        );
+        // whitespace mode has nothing to split, a delimiter gives one empty piece
+        assert_eq!(counts, (0, 0, String::new(), 1, 0));
+    }
+
+    #[test]
+    fn split_with_max_and_remove_empty() {
+        let lua = lua();
+        let join =
+            |code: &str| -> String { eval(&lua, &format!(r#"return table.concat({code}, "|")"#)) };
+        assert_eq!(join(r#"stringx.split("a,,b", ",")"#), "a||b");
+        assert_eq!(
+            join(r#"stringx.split(",a,,b,", ",", {remove_empty = true})"#),
+            "a|b"
+        );
+        assert_eq!(
+            join(r#"stringx.split("a,b,c,d", ",", {max = 2})"#),
+            "a|b,c,d"
+        );
+        assert_eq!(
+            join(r#"stringx.split("a,,,b,c", ",", {max = 2, remove_empty = true})"#),
+            "a|b,c"
+        );
+        assert_eq!(
+            join(r#"stringx.split("  one  two\tthree  ")"#),
+            "one|two|three"
+        );
+        assert_eq!(
+            join(r#"stringx.split("  one  two   three  ", nil, {max = 2})"#),
+            "one|two   three"
+        );
+        assert_eq!(join(r#"stringx.split("a::b::c", "::")"#), "a|b|c");
+        let message = error(&lua, r#"stringx.split("a,b", ",", {max = 0})"#);
+        assert!(message.contains("max must be at least 1"), "{message}");
+    }
+
+    #[test]
+    fn lines_with_mixed_line_endings() {
+        let lua = lua();
+        let text = r#""one\r\ntwo\nthree\r\n\nlast""#;
+        let (lines, iterated): (String, String) = eval(
+            &lua,
+            &format!(
+                r#"local iterated = {{}}
+                for line in stringx.iter_lines({text}) do iterated[#iterated + 1] = line end
+                return table.concat(stringx.lines({text}), "|"), table.concat(iterated, "|")"#
+            ),
+        );
+        assert_eq!(lines, "one|two|three||last");
+        assert_eq!(iterated, lines);
+    }
+
+    #[test]
+    fn lines_without_a_phantom_last_line() {
+        let lua = lua();
+        let counts: (usize, usize, usize, usize) = eval(
+            &lua,
+            r#"return #stringx.lines("a\nb\n"), #stringx.lines("a\r\n"), #stringx.lines(""),
+                #stringx.lines("\n")"#,
+        );
+        assert_eq!(counts, (2, 1, 0, 1));
+    }
+
+    #[test]
+    fn truncate_keeps_graphemes_whole() {
+        let lua = lua();
+        let family = "\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}";
+        lua.globals().set("family", family).unwrap();
+        for (code, expected) in [
+            (
+                r#"stringx.truncate("ab" .. family .. "cd", 4, {by = "graphemes"})"#,
+                format!("ab{family}…"),
+            ),
+            (
+                r#"stringx.truncate("ab" .. family .. "cd", 3, {by = "graphemes"})"#,
+                "ab…".to_string(),
+            ),
+            (
+                r#"stringx.truncate(family, 1, {by = "graphemes"})"#,
+                family.to_string(),
+            ),
+            (
+                r#"stringx.truncate("e\u{301}e\u{301}e\u{301}", 2, {by = "graphemes"})"#,
+                "e\u{301}…".to_string(),
+            ),
+            // characters count code points, so a combining mark counts on its own
+            (
+                r#"stringx.truncate("cafe\u{301}s", 5)"#,
+                "cafe…".to_string(),
+            ),
+            (
+                r#"stringx.truncate("cafe\u{301}s", 5, {by = "graphemes"})"#,
+                "cafe\u{301}s".to_string(),
+            ),
+            (
+                r#"stringx.truncate("日本語", 5, {by = "width"})"#,
+                "日本…".to_string(),
+            ),
+            (
+                r#"stringx.truncate("日本語", 4, {by = "width"})"#,
+                "日…".to_string(),
+            ),
+        ] {
+            let truncated: String = eval(&lua, &format!("return {code}"));
+            assert_eq!(truncated, expected, "{code}");
+        }
+    }
+
+    #[test]
+    fn truncate_only_adds_the_ellipsis_when_it_cuts() {
+        let lua = lua();
+        let truncated: (String, String, String, String) = eval(
+            &lua,
+            r#"return stringx.truncate("short", 5), stringx.truncate("abcdefg", 5, {ellipsis = "..."}),
+                stringx.truncate("abcdef", 2, {ellipsis = "..."}), stringx.truncate("abcdef", 3, {ellipsis = ""})"#,
+        );
+        assert_eq!(
+            truncated,
+            ("short".into(), "ab...".into(), "ab".into(), "abc".into())
+        );
+        let message = error(&lua, r#"stringx.truncate("abc", 1, {by = "bytes"})"#);
+        assert!(
+            message.contains("invalid truncation unit 'bytes'"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn strip_prefix_and_suffix() {
+        let lua = lua();
+        let stripped: (String, bool, String, bool, String, bool) = eval(
+            &lua,
+            r#"local a, am = stringx.strip_prefix("refs/heads/main", "refs/heads/")
+            local b, bm = stringx.strip_suffix("main", ".git")
+            local c, cm = stringx.strip_prefix("abc", "")
+            return a, am, b, bm, c, cm"#,
+        );
+        assert_eq!(
+            stripped,
+            (
+                "main".into(),
+                true,
+                "main".into(),
+                false,
+                "abc".into(),
+                true
+            )
+        );
+    }
+
+    #[test]
+    fn strip_repeatedly() {
+        let lua = lua();
+        let stripped: (String, String, String, String) = eval(
+            &lua,
+            r#"return stringx.strip_prefix("////path", "/", {repeated = true}),
+                stringx.strip_prefix("////path", "/"),
+                stringx.strip_suffix("a.txt.txt.txt", ".txt", {repeated = true}),
+                stringx.strip_prefix("abab", "", {repeated = true})"#,
+        );
+        assert_eq!(
+            stripped,
+            ("path".into(), "///path".into(), "a".into(), "abab".into())
+        );
+
+        let aliased: (String, String) = eval(
+            &lua,
+            r#"return stringx.strip_prefix("//a", "/", {["repeat"] = true}),
+                stringx.strip_suffix("a!!", "!", {["repeat"] = false})"#,
+        );
+        assert_eq!(aliased, ("a".into(), "a!".into()));
+        let message = error(
+            &lua,
+            r#"stringx.strip_prefix("//a", "/", {["repeat"] = true, repeated = true})"#,
+        );
+        assert!(
+            message.contains("repeat and repeated can't both be given"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn reverse_by_grapheme() {
+        let lua = lua();
+        for (input, expected) in [
+            ("abc", "cba"),
+            ("e\u{301}a", "ae\u{301}"),
+            // flags are pairs of regional indicators, here FR then JP
+            (
+                "\u{1f1eb}\u{1f1f7}\u{1f1ef}\u{1f1f5}",
+                "\u{1f1ef}\u{1f1f5}\u{1f1eb}\u{1f1f7}",
+            ),
+            (
+                "a\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}b",
+                "b\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}a",
+            ),
+            ("", ""),
+        ] {
+            let reversed: String = lua.load("return stringx.reverse(...)").call(input).unwrap();
+            assert_eq!(reversed, expected, "{input:?}");
+        }
+    }
+
+    #[test]
+    fn rep_and_count() {
+        let lua = lua();
+        let results: (String, String, String, usize, usize, usize) = eval(
+            &lua,
+            r#"return stringx.rep("ab", 3, ", "), stringx.rep("x", 3), stringx.rep("x", 0, ","),
+                stringx.count("aaa", "aa"), stringx.count("aaa", "aa", {overlapping = true}),
+                stringx.count("a.b.c", ".")"#,
+        );
+        assert_eq!(
+            results,
+            ("ab, ab, ab".into(), "xxx".into(), String::new(), 1, 2, 2)
+        );
+    }
+
+    #[test]
+    fn rep_errors_instead_of_aborting_on_huge_results() {
+        let lua = lua();
+        for code in [
+            r#"stringx.rep("ab", 1e12)"#,
+            r#"stringx.rep("x", 2^31)"#,
+            r#"stringx.rep("abcd", 2^62)"#,
+        ] {
+            let message: String = eval(
+                &lua,
+                &format!(
+                    "local ok, e = pcall(function() {code} end) assert(not ok) return tostring(e)"
+                ),
+            );
+            assert!(
+                message.contains("resulting string too large"),
+                "{code}: {message}"
+            );
+        }
+        let empty: String = eval(&lua, r#"return stringx.rep("", 1e12, "")"#);
+        assert_eq!(empty, "");
+    }
+
+    const NASTY_ARGUMENTS: [&str; 12] = [
+        "plain",
+        "",
+        "with space",
+        "it's",
+        "'''",
+        "line\nbreak",
+        "$(rm -rf /)",
+        "`id` $HOME",
+        "\"double\" \\ back\\slash",
+        "*.txt ~user !1 #comment",
+        "tab\there;&|<>",
+        "ünïcode",
+    ];
+
+    #[test]
+    fn shell_quote_then_split_round_trips() {
+        let lua = lua();
+        let round_trip = lua
+            .load(
+                "return function(args)
+                    local quoted = {}
+                    for i, arg in ipairs(args) do quoted[i] = stringx.shell_quote(arg) end
+                    local cmdline = table.concat(quoted, ' ')
+                    return stringx.shell_split(cmdline), cmdline
+                end",
+            )
+            .eval::<mlua::Function>()
+            .unwrap();
+        let (split, cmdline): (Vec<String>, String) = round_trip.call(NASTY_ARGUMENTS).unwrap();
+        assert_eq!(split, NASTY_ARGUMENTS);
+
+        // and a real shell agrees
+        #[cfg(unix)]
+        {
+            let output = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(format!(
+                    r#"for arg in {cmdline}; do printf '%s\0' "$arg"; done"#
+                ))
+                .output()
+                .unwrap();
+            let args: Vec<&[u8]> = output.stdout.split(|&b| b == 0).collect();
+            let expected: Vec<&[u8]> = NASTY_ARGUMENTS.iter().map(|s| s.as_bytes()).collect();
+            assert_eq!(args[..args.len() - 1], expected);
+        }
+    }
+
+    #[test]
+    fn shell_quote_styles() {
+        let lua = lua();
+        let quoted: (String, String, String, String) = eval(
+            &lua,
+            r#"return stringx.shell_quote("it's"), stringx.shell_quote("safe/path.txt"),
+                stringx.shell_quote([[a "b" c\]], {style = "windows"}),
+                stringx.shell_quote("it's", {style = "powershell"})"#,
+        );
+        assert_eq!(
+            quoted,
+            (
+                r"'it'\''s'".into(),
+                "safe/path.txt".into(),
+                r#""a \"b\" c\\""#.into(),
+                "'it''s'".into()
+            )
+        );
+    }
+
+    #[test]
+    fn shell_split_reports_where_a_quote_is_unterminated() {
+        let lua = lua();
+        for (cmdline, expected) in [
+            (r#"echo 'abc"#, "unterminated single quote at byte 6"),
+            (r#"a "b\"c"#, "unterminated double quote at byte 3"),
+            (r"trailing\", "unterminated escape at byte 9"),
+        ] {
+            let message = error(&lua, &format!("stringx.shell_split({cmdline:?})"));
+            assert!(message.contains(expected), "{cmdline}: {message}");
+        }
+        let words: Vec<String> = eval(
+            &lua,
+            r#"return stringx.shell_split([[  a\ b  "c\$d\x" 'e\f'  line\
+continued ""]])"#,
+        );
+        assert_eq!(words, ["a b", r"c$d\x", r"e\f", "linecontinued", ""]);
+    }
+
+    #[test]
+    fn dedent_like_python() {
+        let lua = lua();
+        for (input, expected) in [
+            ("    a\n      b\n    c\n", "a\n  b\nc\n"),
+            // blank lines don't count towards the margin and are emptied
+            ("  a\n\n   \n  b", "a\n\n\nb"),
+            // the first line counts like any other, so text right after `[[`
+            // leaves the rest indented
+            ("first\n    second\n", "first\n    second\n"),
+            ("\n    first\n    second\n", "\nfirst\nsecond\n"),
+            // tabs and spaces are different characters
+            ("\ta\n    b\n", "\ta\n    b\n"),
+            ("\t  a\n\t b\n", " a\nb\n"),
+            ("  \ta\n  \tb\n", "a\nb\n"),
+            ("  a\r\n  b\r\n", "a\r\nb\r\n"),
+        ] {
+            let dedented: String = lua.load("return stringx.dedent(...)").call(input).unwrap();
+            assert_eq!(dedented, expected, "{input:?}");
+        }
+    }
+
+    #[test]
+    fn indent_every_line_or_skip_blank_ones() {
+        let lua = lua();
+        let indented: (String, String) = eval(
+            &lua,
+            r#"return stringx.indent("a\n\nb\n", "> "), stringx.indent("a\n\nb\n", "> ", {skip_blank = true})"#,
+        );
+        assert_eq!(indented, ("> a\n> \n> b\n".into(), "> a\n\n> b\n".into()));
+    }
+
+    #[test]
+    fn random_uses_every_character_of_the_set() {
+        let lua = lua();
+        let printable: String = (' '..='~').collect();
+        for (opts, charset) in [
+            (
+                "nil",
+                "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz",
+            ),
+            (r#"{preset = "hex"}"#, "0123456789abcdef"),
+            (
+                r#"{preset = "base58"}"#,
+                "123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz",
+            ),
+            (r#"{charset = "hex"}"#, "hex"),
+            (r#"{charset = "aé日"}"#, "aé日"),
+            (r#""hex""#, "0123456789abcdef"),
+            (r#""ascii_printable""#, &printable),
+            (r#""01""#, "01"),
+            (r#""aé日""#, "aé日"),
+        ] {
+            let sample: String = eval(&lua, &format!("return stringx.random(20000, {opts})"));
+            assert_eq!(sample.chars().count(), 20000, "{opts}");
+            // 20000 draws from at most 95 characters make a missing one about
+            // 95 * (94/95)^20000 likely, which never happens
+            let mut counts = std::collections::HashMap::new();
+            for c in sample.chars() {
+                *counts.entry(c).or_insert(0) += 1;
+            }
+            let mut seen: Vec<char> = counts.keys().copied().collect();
+            seen.sort();
+            let mut expected: Vec<char> = charset.chars().collect();
+            expected.sort();
+            assert_eq!(seen, expected, "{opts}");
+        }
+    }
+
+    #[test]
+    fn random_lengths_and_invalid_sets() {
+        let lua = lua();
+        let empty: String = eval(&lua, "return stringx.random(0)");
+        assert_eq!(empty, "");
+        for (opts, expected) in [
+            (r#"{charset = ""}"#, "charset must not be empty"),
+            (r#""""#, "charset must not be empty"),
+            (
+                "42",
+                "invalid charset: expected a string or a table, got integer",
+            ),
+            (r#"{preset = "octal"}"#, "unknown preset 'octal'"),
+            (
+                r#"{preset = "hex", charset = "abc"}"#,
+                "can't both be given",
+            ),
+        ] {
+            let message = error(&lua, &format!("stringx.random(8, {opts})"));
+            assert!(message.contains(expected), "{opts}: {message}");
+        }
+    }
+
+    #[test]
+    fn composed_and_decomposed_strings_are_equal_once_normalized() {
+        let lua = lua();
+        let results: (bool, bool, bool, usize, usize) = eval(
+            &lua,
+            r#"local composed, decomposed = "caf\u{e9}", "cafe\u{301}"
+            return composed == decomposed, stringx.eq_normalized(composed, decomposed),
+                stringx.normalize(decomposed) == composed, #stringx.normalize(composed, "NFD"),
+                #stringx.normalize("\u{fb01}", "nfkc")"#,
+        );
+        // the fi ligature only becomes "fi" under compatibility normalization
+        assert_eq!(results, (false, true, true, 6, 2));
+    }
+
+    #[test]
+    fn normalize_lists_the_forms_it_knows() {
+        let lua = lua();
+        let message = error(&lua, r#"stringx.normalize("a", "NFX")"#);
+        assert!(
+            message.contains("invalid normalization form 'NFX' (expected NFC, NFD, NFKC or NFKD)"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn width_ignores_escape_sequences_and_control_characters() {
+        let lua = lua();
+        for (code, expected) in [
+            (r#""plain""#, (5, false)),
+            (r#""日本語""#, (6, false)),
+            (r#""\27[1;31mred\27[0m""#, (3, true)),
+            (
+                r#""\27]8;;https://example.com\7link\27]8;;\27\\""#,
+                (4, true),
+            ),
+            (r#""tab\there\r""#, (7, false)),
+            (r#""e\u{301}""#, (1, false)),
+        ] {
+            let width: (usize, bool) = eval(&lua, &format!("return stringx.width({code})"));
+            assert_eq!(width, expected, "{code}");
+        }
+    }
+
+    #[test]
+    fn wrap_measures_like_width() {
+        let lua = lua();
+        for (code, expected) in [
+            (
+                r#"stringx.wrap("\27[31mred\27[0m \27[32mgreen\27[0m \27[34mblue\27[0m", 9)"#,
+                vec![
+                    "\x1b[31mred\x1b[0m \x1b[32mgreen\x1b[0m",
+                    "\x1b[34mblue\x1b[0m",
+                ],
+            ),
+            (
+                r#"stringx.wrap("\27]8;;https://example.com\7link\27]8;;\7 text", 9)"#,
+                vec!["\x1b]8;;https://example.com\x07link\x1b]8;;\x07 text"],
+            ),
+            (
+                r#"stringx.wrap("日本 語の 文章", 5)"#,
+                vec!["日本", "語の", "文章"],
+            ),
+            (
+                r#"stringx.wrap("\27[1m日本語の文章\27[0m", 4, {break_long_words = true})"#,
+                vec!["\x1b[1m日本", "語の", "文章\x1b[0m"],
+            ),
+            (
+                r#"stringx.wrap("one two three", 8, {initial_indent = "* ", subsequent_indent = "  "})"#,
+                vec!["* one", "  two", "  three"],
+            ),
+            (r#"stringx.wrap("a\n\nb", 10)"#, vec!["a", "", "b"]),
+        ] {
+            let lines: Vec<String> = eval(&lua, &format!("return {code}"));
+            assert_eq!(lines, expected, "{code}");
+        }
+    }
+
+    #[test]
+    fn wrapped_lines_fit_the_width_pad_measures() {
+        let lua = lua();
+        let widest: usize = eval(
+            &lua,
+            r#"local text = "\27[33mwarning:\27[0m 日本語 café e\u{301}clair ☕☕☕ " .. string.rep("\27[4mword\27[24m ", 10)
+            local widest = 0
+            for _, line in ipairs(stringx.wrap(text, 12, {break_long_words = true})) do
+                assert(stringx.width(stringx.pad_right(line, 12)) == 12, line)
+                widest = math.max(widest, (stringx.width(line)))
+            end
+            return widest"#,
+        );
+        assert!(widest <= 12, "{widest}");
+    }
+
+    const NATURALLY_SORTED: [&str; 12] = [
+        "README",
+        "a.txt",
+        "file1.txt",
+        "file2.txt",
+        "file02.txt",
+        "file002.txt",
+        "file10.txt",
+        "file10a.txt",
+        "file100.txt",
+        "v1.2.9.tar.gz",
+        "v1.2.10.tar.gz",
+        "v1.10.0.tar.gz",
+    ];
+
+    #[test]
+    fn natural_sort_of_shuffled_file_names() {
+        let lua = lua();
+        let shuffled = [
+            "file10a.txt",
+            "v1.10.0.tar.gz",
+            "file02.txt",
+            "a.txt",
+            "file100.txt",
+            "v1.2.9.tar.gz",
+            "file2.txt",
+            "README",
+            "file002.txt",
+            "v1.2.10.tar.gz",
+            "file1.txt",
+            "file10.txt",
+        ];
+        let sort = lua
+            .load(
+                "return function(t)
+                    local copy = {unpack(t)}
+                    stringx.natural_sort(t)
+                    table.sort(copy, stringx.natural_lt)
+                    return t, copy
+                end",
+            )
+            .eval::<mlua::Function>()
+            .unwrap();
+        let (sorted, with_table_sort): (Vec<String>, Vec<String>) = sort.call(shuffled).unwrap();
+        assert_eq!(sorted, NATURALLY_SORTED);
+        assert_eq!(with_table_sort, NATURALLY_SORTED);
+    }
+
+    #[test]
+    fn natural_cmp_returns_the_sign() {
+        let lua = lua();
+        let results: (i32, i32, i32, i32, i32) = eval(
+            &lua,
+            r#"return stringx.natural_cmp("file2", "file10"), stringx.natural_cmp("file10", "file2"),
+                stringx.natural_cmp("x9", "x9"), stringx.natural_cmp("file002", "file2"),
+                stringx.natural_cmp("a01b2", "a1b02")"#,
+        );
+        // leading zeros only break ties, and the first difference in them wins
+        assert_eq!(results, (-1, 1, 0, 1, 1));
+    }
+
+    #[test]
+    fn json_escapes_of_astral_characters_use_surrogate_pairs() {
+        let lua = lua();
+        let results: (String, String, String, String) = eval(
+            &lua,
+            r#"local s = "smile \u{1f600} caf\u{e9}"
+            local ascii = stringx.escape_json(s, {ascii = true})
+            return ascii, stringx.unescape_json(ascii), stringx.escape_json(s),
+                stringx.unescape_json([[😀 é \"\/\\\b\f\n\r\t]])"#,
+        );
+        assert_eq!(
+            results,
+            (
+                r"smile \ud83d\ude00 caf\u00e9".into(),
+                "smile \u{1f600} café".into(),
+                "smile \u{1f600} café".into(),
+                "\u{1f600} é \"/\\\u{8}\u{c}\n\r\t".into()
+            )
+        );
+    }
+
+    #[test]
+    fn json_escapes_need_four_hex_digits_and_paired_surrogates() {
+        let lua = lua();
+        for (escaped, offset) in [
+            (r"\u+041", 1),
+            (r"\u-041", 1),
+            (r"\u 041", 1),
+            (r"ab\u00g1", 3),
+            (r"\u12", 1),
+            // a lone high surrogate, one followed by something else, and a
+            // lone low surrogate
+            (r"\ud83d", 1),
+            (r"x\ud83dx", 2),
+            (r"\ud83dA", 1),
+            (r"\ud83d\u0041", 1),
+            (r"\ude00", 1),
+            (r"\q", 1),
+            ("\\", 1),
+        ] {
+            let message = error(&lua, &format!("stringx.unescape_json([[{escaped}]])"));
+            assert!(
+                message.contains(&format!("invalid JSON escape at byte {offset}")),
+                "{escaped}: {message}"
+            );
+        }
+    }
+
+    /// Renders diff hunks as `=text`, `-text` and `+text` joined with `|`.
+    fn hunks(lua: &mlua::Lua, a: &str, b: &str, mode: &str) -> String {
+        lua.load(
+            r#"local a, b, mode = ...
+            local out = {}
+            local ops = {equal = "=", delete = "-", insert = "+"}
+            for i, hunk in ipairs(stringx.diff(a, b, {mode = mode})) do
+                out[i] = ops[hunk.op] .. hunk.text
+            end
+            return table.concat(out, "|")"#,
+        )
+        .call((a, b, mode))
+        .unwrap()
+    }
+
+    #[test]
+    fn diff_in_each_mode() {
+        let lua = lua();
+        assert_eq!(
+            hunks(&lua, "kitten", "sitting", "chars"),
+            "-k|+s|=itt|-e|+i|=n|+g"
+        );
+        assert_eq!(
+            hunks(&lua, "the quick fox", "the slow fox", "words"),
+            "=the |-quick|+slow|= fox"
+        );
+        assert_eq!(
+            hunks(&lua, "a\nb\nc\n", "a\nc\nd\n", "lines"),
+            "=a\n|-b\n|=c\n|+d\n"
+        );
+        assert_eq!(hunks(&lua, "same", "same", "chars"), "=same");
+        assert_eq!(hunks(&lua, "", "new", "chars"), "+new");
+        let message = error(&lua, r#"stringx.diff("a", "b", {mode = "bytes"})"#);
+        assert!(message.contains("invalid diff mode 'bytes'"), "{message}");
+    }
+
+    #[test]
+    fn diff_pretty_is_a_unified_diff() {
+        let lua = lua();
+        let (pretty, equal): (String, String) = eval(
+            &lua,
+            r#"return stringx.diff_pretty("a\nb\nc\nd\ne\n", "a\nb\nC\nd\ne\n", {context = 1}),
+                stringx.diff_pretty("x\n", "x\n")"#,
+        );
+        assert_eq!(pretty, "--- a\n+++ b\n@@ -2,3 +2,3 @@\n b\n-c\n+C\n d\n");
+        assert_eq!(equal, "");
+    }
+
+    #[test]
+    fn diff_of_ten_thousand_lines_is_fast() {
+        let lua = lua();
+        let start = std::time::Instant::now();
+        let changes: usize = eval(
+            &lua,
+            r#"local a, b = {}, {}
+            for i = 1, 10000 do
+                a[i] = "line " .. i
+                b[i] = i % 100 == 0 and "changed " .. i or a[i]
+            end
+            local changes = 0
+            for _, hunk in ipairs(stringx.diff(table.concat(a, "\n"), table.concat(b, "\n"), {mode = "lines"})) do
+                if hunk.op ~= "equal" then changes = changes + 1 end
+            end
+            return changes"#,
+        );
+        // each changed line is a deletion and an insertion
+        assert_eq!(changes, 200);
+        assert!(
+            start.elapsed() < std::time::Duration::from_secs(1),
+            "{:?}",
+            start.elapsed()
+        );
+    }
+}